@@ -0,0 +1,129 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use pprof::{Collector, ProfilerGuard, ProfilerGuardBuilder, UnresolvedFrame, UnresolvedFrames};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Counts the allocations, to compare how many the borrowing and consuming builds make.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// The number of allocations made by `f`.
+fn allocations<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    let output = f();
+
+    (output, ALLOCATIONS.load(Ordering::Relaxed) - start)
+}
+
+#[inline(never)]
+fn busy_for(duration: Duration) -> u64 {
+    let start = Instant::now();
+    let mut v: u64 = 0;
+    while start.elapsed() < duration {
+        for i in 0..10000 {
+            v = v.wrapping_mul(31).wrapping_add(i);
+        }
+    }
+
+    v
+}
+
+const STACKS: usize = 20_000;
+const DEPTH: usize = 32;
+
+// A large profile: many distinct deep stacks of a few functions, collected up front rather than
+// sampled, so that every build starts from the same stacks.
+fn profiled() -> ProfilerGuard<'static> {
+    let functions = [
+        busy_for as *const () as usize,
+        profiled as *const () as usize,
+        bench_build_report as *const () as usize,
+    ];
+    let mut collector = Collector::in_memory();
+    for index in 0..STACKS {
+        let frames: Vec<UnresolvedFrame> = (0..DEPTH)
+            .map(|depth| {
+                // the digits of `index` in base 3 tell the stacks apart
+                let digit = index / 3_usize.pow(depth.min(12) as u32);
+                let function = functions[digit % functions.len()];
+                UnresolvedFrame {
+                    ip: function + 1,
+                    symbol_address: function,
+                }
+            })
+            .collect();
+        let thread = index as u64 / 1000;
+        collector
+            .add(UnresolvedFrames::new(&frames, b"main", thread), 1)
+            .unwrap();
+    }
+
+    ProfilerGuardBuilder::default()
+        .frequency(1)
+        .collector(collector)
+        .build()
+        .unwrap()
+}
+
+fn bench_build_report(c: &mut Criterion) {
+    // the allocation counts are deterministic, so they are measured once, after a build which
+    // fills the caches of the symbolizer. A captured stack owns no heap memory, so moving rather
+    // than cloning it saves copies, not allocations: the counts are about the same, and the
+    // difference is in the times below.
+    drop(profiled().report().build().unwrap());
+    let guard = profiled();
+    let (borrowed, borrowing) = allocations(|| guard.report().build().unwrap());
+    drop(guard);
+    let guard = profiled();
+    let (consumed, consuming) = allocations(|| guard.into_report().build().unwrap());
+    assert_eq!(borrowed.data, consumed.data);
+    println!(
+        "build_report: {} stacks of {} frames, allocations borrowing: {}, consuming: {}",
+        borrowed.data.len(),
+        DEPTH,
+        borrowing,
+        consuming
+    );
+
+    let mut group = c.benchmark_group("build_report");
+    group.sample_size(10);
+
+    group.bench_function("borrowing", |b| {
+        b.iter_batched(
+            profiled,
+            |guard| guard.report().build().unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("consuming", |b| {
+        b.iter_batched(
+            profiled,
+            |guard| guard.into_report().build().unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_build_report);
+criterion_main!(benches);
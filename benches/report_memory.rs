@@ -0,0 +1,128 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pprof::{
+    Collector, Frames, ProfilerGuard, ProfilerGuardBuilder, UnresolvedFrame, UnresolvedFrames,
+};
+
+// Counts the bytes allocated and not freed yet, to measure what a report keeps alive.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// The bytes allocated by `f` which are still allocated once it returned, i.e. the memory kept by
+// its output.
+fn retained_bytes<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let start = ALLOCATED.load(Ordering::Relaxed);
+    let output = f();
+
+    (
+        output,
+        ALLOCATED.load(Ordering::Relaxed).saturating_sub(start),
+    )
+}
+
+macro_rules! leaves {
+    ($($name:ident = $value:expr),*) => {
+        $(
+            #[inline(never)]
+            fn $name() -> u32 {
+                $value
+            }
+        )*
+
+        fn functions() -> Vec<usize> {
+            vec![$($name as *const () as usize),*]
+        }
+    };
+}
+
+leaves!(
+    f0 = 0,
+    f1 = 1,
+    f2 = 2,
+    f3 = 3,
+    f4 = 4,
+    f5 = 5,
+    f6 = 6,
+    f7 = 7
+);
+
+const STACKS: usize = 2000;
+const DEPTH: usize = 16;
+
+// Many distinct stacks of a few functions, so that the report repeats the same names a lot.
+fn stacks() -> Vec<UnresolvedFrames> {
+    let functions = functions();
+    (0..STACKS)
+        .map(|index| {
+            let frames: Vec<UnresolvedFrame> = (0..DEPTH)
+                .map(|depth| {
+                    let function = functions[(index >> (depth * 3 % 12)) % functions.len()];
+                    UnresolvedFrame {
+                        ip: function + 1,
+                        symbol_address: function,
+                    }
+                })
+                .collect();
+            UnresolvedFrames::new(&frames, b"main", 1)
+        })
+        .collect()
+}
+
+fn profiled() -> ProfilerGuard<'static> {
+    let mut collector = Collector::in_memory();
+    for stack in stacks() {
+        collector.add(stack, 1).unwrap();
+    }
+
+    ProfilerGuardBuilder::default()
+        .frequency(1)
+        .collector(collector)
+        .build()
+        .unwrap()
+}
+
+// The memory of a report is deterministic, so it is measured once instead of with criterion.
+fn main() {
+    // every stack resolved on its own, each symbol owning its name
+    let mut owned = stacks();
+    let (report, owned_bytes) = retained_bytes(|| {
+        owned
+            .drain(..)
+            .map(|stack| (Frames::from(stack), 1))
+            .collect::<HashMap<_, isize>>()
+    });
+    let stacks = report.len();
+    drop(report);
+
+    let guard = profiled();
+    let (report, interned_bytes) = retained_bytes(|| guard.report().build().unwrap());
+    assert_eq!(report.data.len(), stacks);
+
+    println!(
+        "report_memory: {} stacks of {} frames, owned: {} KiB, interned: {} KiB",
+        stacks,
+        DEPTH,
+        owned_bytes / 1024,
+        interned_bytes / 1024
+    );
+}
@@ -0,0 +1,47 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use pprof::ProfilerGuardBuilder;
+use std::time::{Duration, Instant};
+
+// spins at a single instruction, the workload the deduplicated samples are for
+#[inline(never)]
+fn spin_for(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        for _ in 0..10000 {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+// The CPU time spent by the signal handler per sample, over `iters` samples.
+fn per_sample_cost(iters: u64, dedup_samples: bool) -> Duration {
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(1000)
+        .dedup_samples(dedup_samples)
+        .build()
+        .unwrap();
+    spin_for(Duration::from_millis(200));
+    let samples = guard.handle().sample_count().max(1) as f64;
+
+    Duration::from_secs_f64(guard.overhead().as_secs_f64() / samples * iters as f64)
+}
+
+fn bench_sampling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sample_tight_loop");
+    group.sample_size(10);
+
+    group.bench_function("default", |b| {
+        b.iter_custom(|iters| per_sample_cost(iters, false))
+    });
+
+    group.bench_function("dedup_samples", |b| {
+        b.iter_custom(|iters| per_sample_cost(iters, true))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_sampling);
+criterion_main!(benches);
@@ -2,7 +2,6 @@
 
 extern crate libc;
 
-use pprof;
 use std::ffi::c_void;
 
 extern "C" {
@@ -72,8 +71,8 @@ fn prepare_prime_numbers() -> Vec<usize> {
         }
     }
     let mut prime_numbers = vec![];
-    for i in 2..10000 {
-        if prime_number_table[i] {
+    for (i, is_prime) in prime_number_table.iter().enumerate().skip(2) {
+        if *is_prime {
             prime_numbers.push(i);
         }
     }
@@ -1,6 +1,5 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use pprof;
 use std::sync::Arc;
 
 #[inline(never)]
@@ -35,8 +34,8 @@ fn prepare_prime_numbers() -> Vec<usize> {
         }
     }
     let mut prime_numbers = vec![];
-    for i in 2..10000 {
-        if prime_number_table[i] {
+    for (i, is_prime) in prime_number_table.iter().enumerate().skip(2) {
+        if *is_prime {
             prime_numbers.push(i);
         }
     }
@@ -88,12 +87,9 @@ fn main() {
     });
 
     loop {
-        match guard.report().build() {
-            Ok(report) => {
-                println!("{:?}", report);
-            }
-            Err(_) => {}
-        };
+        if let Ok(report) = guard.report().build() {
+            println!("{:?}", report);
+        }
         std::thread::sleep(std::time::Duration::from_secs(1))
     }
     //    pprof::PROFILER.lock().unwrap().stop();
@@ -1,10 +1,19 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::path::{Path, PathBuf};
 
-use crate::frames::UnresolvedFrames;
+use parking_lot::Mutex;
+
+use crate::frames::{Frames, Symbol, SymbolInterner, UnresolvedFrames};
+use crate::report::Report;
+use crate::timer::ReportTiming;
 
 use tempfile::NamedTempFile;
 
@@ -17,64 +26,164 @@ pub struct Entry<T> {
     pub count: isize,
 }
 
+/// Chooses the entry which a full bucket of a `StackHashCounter` or a `Collector` evicts to make
+/// room for a new one, e.g. the least counted one, which `MinCount` does, or the least recently
+/// used one, which `LeastRecentlyUsed` does. Both are called by the signal handler, so they have to
+/// be AS-safe.
+pub trait EvictionPolicy<T> {
+    /// The index of the entry of `entries` to evict. They are in insertion order, the oldest
+    /// first, as an evicted entry is removed and the new one appended, unless `hit` reorders them.
+    /// An index past the entries evicts the last one.
+    fn victim(&self, entries: &[Entry<T>]) -> usize;
+
+    /// Called when a key is added to `entries[index]`, already in the bucket, after its count was
+    /// increased. It may reorder the entries, e.g. to keep them in the order of their last use. It
+    /// does nothing by default.
+    fn hit(&self, _entries: &mut [Entry<T>], _index: usize) {}
+}
+
+impl<T, P: EvictionPolicy<T> + ?Sized> EvictionPolicy<T> for Box<P> {
+    fn victim(&self, entries: &[Entry<T>]) -> usize {
+        (**self).victim(entries)
+    }
+
+    fn hit(&self, entries: &mut [Entry<T>], index: usize) {
+        (**self).hit(entries, index)
+    }
+}
+
+/// The default `EvictionPolicy`: the entry with the minimum count, the oldest one on ties, so that
+/// the hottest stacks stay in the buckets.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinCount;
+
+impl<T> EvictionPolicy<T> for MinCount {
+    fn victim(&self, entries: &[Entry<T>]) -> usize {
+        let mut min_index = 0;
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.count < entries[min_index].count {
+                min_index = index;
+            }
+        }
+
+        min_index
+    }
+}
+
+/// An `EvictionPolicy` evicting the entry which was added to the longest ago, whatever its count,
+/// so that a stack hot a while ago doesn't stay in the buckets forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeastRecentlyUsed;
+
+impl<T> EvictionPolicy<T> for LeastRecentlyUsed {
+    fn victim(&self, _entries: &[Entry<T>]) -> usize {
+        0
+    }
+
+    fn hit(&self, entries: &mut [Entry<T>], index: usize) {
+        // the most recently used entry is the last one, like a new one
+        entries[index..].rotate_left(1);
+    }
+}
+
 pub struct Bucket<T: 'static> {
     pub length: usize,
-    entries: &'static mut [Entry<T>; BUCKETS_ASSOCIATIVITY],
+    // only the first `len()` entries are initialized, the others are never read nor dropped
+    entries: &'static mut [MaybeUninit<Entry<T>>],
 }
 
 impl<T: Eq> Default for Bucket<T> {
     fn default() -> Bucket<T> {
-        let entries = Box::new(unsafe { std::mem::MaybeUninit::uninit().assume_init() });
+        Self::with_associativity(BUCKETS_ASSOCIATIVITY)
+    }
+}
+
+impl<T> Bucket<T> {
+    // A bucket of `associativity` entries, at least one.
+    fn with_associativity(associativity: usize) -> Bucket<T> {
+        let entries = std::iter::repeat_with(MaybeUninit::uninit);
+        let entries: Box<[MaybeUninit<Entry<T>>]> = entries.take(associativity.max(1)).collect();
 
         Self {
             length: 0,
             entries: Box::leak(entries),
         }
     }
+
+    /// The number of entries, never beyond the array holding them, even if `length` was set
+    /// past it.
+    fn len(&self) -> usize {
+        self.length.min(self.entries.len())
+    }
+
+    /// The entries added and not drained or evicted yet.
+    fn occupied(&self) -> &[Entry<T>] {
+        let length = self.len();
+        unsafe { std::slice::from_raw_parts(self.entries.as_ptr() as *const Entry<T>, length) }
+    }
+
+    fn occupied_mut(&mut self) -> &mut [Entry<T>] {
+        let length = self.len();
+        unsafe {
+            std::slice::from_raw_parts_mut(self.entries.as_mut_ptr() as *mut Entry<T>, length)
+        }
+    }
 }
 
 impl<T: Eq> Bucket<T> {
-    pub fn add(&mut self, key: T, count: isize) -> Option<Entry<T>> {
-        let mut done = false;
-        self.entries[0..self.length].iter_mut().for_each(|ele| {
-            if ele.item == key {
-                ele.count += count;
-                done = true;
-            }
-        });
-
-        if done {
+    /// Adds `count` to `key`'s entry, inserting it if it's missing. If the bucket is full, the
+    /// entry chosen by `policy` is evicted and returned.
+    pub fn add<P: EvictionPolicy<T>>(
+        &mut self,
+        key: T,
+        count: isize,
+        policy: &P,
+    ) -> Option<Entry<T>> {
+        debug_assert!(self.length <= self.entries.len());
+        self.length = self.len();
+
+        let entries = self.occupied_mut();
+        if let Some(index) = entries.iter().position(|ele| ele.item == key) {
+            entries[index].count += count;
+            policy.hit(entries, index);
             None
-        } else if self.length < BUCKETS_ASSOCIATIVITY {
-            let ele = &mut self.entries[self.length];
-            ele.item = key;
-            ele.count = count;
+        } else if self.length < self.entries.len() {
+            // the slot is uninitialized, or holds an entry moved out by `drain`, so it's written
+            // without dropping it
+            self.entries[self.length] = MaybeUninit::new(Entry { item: key, count });
 
             self.length += 1;
             None
         } else {
-            let mut min_index = 0;
-            let mut min_count = self.entries[0].count;
-            for index in 0..self.length {
-                let count = self.entries[index].count;
-                if count < min_count {
-                    min_index = index;
-                    min_count = count;
-                }
-            }
-
-            let mut new_entry = Entry { item: key, count };
-            std::mem::swap(&mut self.entries[min_index], &mut new_entry);
-            Some(new_entry)
+            let victim = policy.victim(self.occupied()).min(self.length - 1);
+
+            // the entries are kept in insertion order, or the one of `hit`, so that the first
+            // minimum is the oldest
+            let entries = self.occupied_mut();
+            entries[victim..].rotate_left(1);
+            let last = entries.len() - 1;
+            Some(std::mem::replace(
+                &mut entries[last],
+                Entry { item: key, count },
+            ))
         }
     }
 
-    pub fn iter(&self) -> BucketIterator<T> {
+    pub fn iter(&self) -> BucketIterator<'_, T> {
         BucketIterator::<T> {
-            related_bucket: &self,
+            related_bucket: self,
             index: 0,
         }
     }
+
+    /// Moves every entry out of the bucket, leaving it empty.
+    pub fn drain(&mut self) -> impl Iterator<Item = Entry<T>> + '_ {
+        let length = self.len();
+        self.length = 0;
+        self.entries[0..length]
+            .iter()
+            .map(|entry| unsafe { entry.assume_init_read() })
+    }
 }
 
 pub struct BucketIterator<'a, T: 'static> {
@@ -86,117 +195,409 @@ impl<'a, T> Iterator for BucketIterator<'a, T> {
     type Item = &'a Entry<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index < self.related_bucket.length {
+        if self.index < self.related_bucket.len() {
             self.index += 1;
-            Some(&self.related_bucket.entries[self.index - 1])
+            self.related_bucket.occupied().get(self.index - 1)
         } else {
             None
         }
     }
 }
 
-pub struct StackHashCounter<T: Hash + Eq + 'static> {
-    buckets: &'static mut [Bucket<T>; BUCKETS],
+pub struct StackHashCounter<
+    T: Hash + Eq + 'static,
+    S = BuildHasherDefault<DefaultHasher>,
+    P = MinCount,
+> {
+    buckets: &'static mut [Bucket<T>],
+    hash_builder: S,
+    policy: P,
 }
 
 impl<T: Hash + Eq> Default for StackHashCounter<T> {
     fn default() -> Self {
-        let buckets = Box::new(unsafe { std::mem::MaybeUninit::uninit().assume_init() });
-        let counter = Self {
-            buckets: Box::leak(buckets),
-        };
-        counter.buckets.iter_mut().for_each(|item| {
-            *item = Bucket::<T>::default();
-        });
+        Self::with_hasher(BuildHasherDefault::default())
+    }
+}
 
-        counter
+impl<T: Hash + Eq, S: BuildHasher> StackHashCounter<T, S> {
+    /// Creates a counter which places keys into buckets with hashers built by `hash_builder`.
+    /// A fixed-seed `hash_builder` makes bucket placement, and thus eviction, reproducible.
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_policy(hash_builder, MinCount)
     }
 }
 
-impl<T: Hash + Eq> StackHashCounter<T> {
-    fn hash(key: &T) -> u64 {
-        let mut s = DefaultHasher::new();
+impl<T: Hash + Eq, S: BuildHasher, P: EvictionPolicy<T>> StackHashCounter<T, S, P> {
+    /// Creates a counter like `with_hasher`, whose full buckets evict the entries chosen by
+    /// `policy` instead of the least counted ones.
+    pub fn with_policy(hash_builder: S, policy: P) -> Self {
+        Self::with_sizes(hash_builder, policy, BUCKETS, BUCKETS_ASSOCIATIVITY)
+    }
+
+    // A counter of `buckets` buckets of `associativity` entries, at least one of each.
+    fn with_sizes(hash_builder: S, policy: P, buckets: usize, associativity: usize) -> Self {
+        let bucket = || Bucket::with_associativity(associativity);
+        let buckets: Box<[Bucket<T>]> = std::iter::repeat_with(bucket)
+            .take(buckets.max(1))
+            .collect();
+
+        Self {
+            buckets: Box::leak(buckets),
+            hash_builder,
+            policy,
+        }
+    }
+
+    fn hash(&self, key: &T) -> u64 {
+        let mut s = self.hash_builder.build_hasher();
         key.hash(&mut s);
         s.finish()
     }
 
     pub fn add(&mut self, key: T, count: isize) -> Option<Entry<T>> {
-        let hash_value = Self::hash(&key);
-        let bucket = &mut self.buckets[(hash_value % BUCKETS as u64) as usize];
+        let hash_value = self.hash(&key);
+        let bucket = &mut self.buckets[(hash_value % self.buckets.len() as u64) as usize];
+
+        bucket.add(key, count, &self.policy)
+    }
 
-        bucket.add(key, count)
+    /// Moves every entry out of the counter, leaving it empty.
+    pub fn drain(&mut self) -> impl Iterator<Item = Entry<T>> + '_ {
+        self.buckets.iter_mut().flat_map(|bucket| bucket.drain())
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &Entry<T>> {
-        let mut iter: Box<dyn Iterator<Item = &Entry<T>>> =
-            Box::new(self.buckets[0].iter().chain(std::iter::empty()));
-        for bucket in self.buckets[1..].iter() {
-            iter = Box::new(iter.chain(bucket.iter()));
-        }
+        self.buckets.iter().flat_map(Bucket::iter)
+    }
+}
+
+// The spill file starts with a header of `SPILL_HEADER_LEN` bytes: the `SPILL_MAGIC` bytes, then
+// the format version and the size of the entries, as little-endian `u32`s. The entries follow as
+// the raw bytes of `T`, whose layout depends on the compiler and the target, so the header tells
+// apart a file written by another build, or which isn't a spill at all, instead of reading its
+// bytes as garbage entries. The version changes with the format.
+const SPILL_MAGIC: [u8; 8] = *b"PPROFSPL";
+const SPILL_VERSION: u32 = 2;
+const SPILL_HEADER_LEN: u64 = 16;
+
+fn spill_header(size: usize) -> [u8; SPILL_HEADER_LEN as usize] {
+    let mut header = [0; SPILL_HEADER_LEN as usize];
+    header[0..8].copy_from_slice(&SPILL_MAGIC);
+    header[8..12].copy_from_slice(&SPILL_VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(size as u32).to_le_bytes());
+
+    header
+}
 
-        iter
+/// Checks that `header` is the one of a spill file of entries of `size` bytes, written by this
+/// version.
+fn check_spill_header(
+    header: &[u8; SPILL_HEADER_LEN as usize],
+    size: usize,
+) -> std::io::Result<()> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let field = |range: std::ops::Range<usize>| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&header[range]);
+        u32::from_le_bytes(bytes)
+    };
+
+    if header[0..8] != SPILL_MAGIC {
+        return Err(invalid("temp file is not a spill file".to_owned()));
     }
+    let version = field(8..12);
+    if version != SPILL_VERSION {
+        return Err(invalid(format!(
+            "spill format version {} mismatches the supported version {}",
+            version, SPILL_VERSION
+        )));
+    }
+    let entry_size = field(12..16);
+    if entry_size as usize != size {
+        return Err(invalid(format!(
+            "spill entries of {} bytes mismatch the {} bytes entries of this build",
+            entry_size, size
+        )));
+    }
+
+    Ok(())
+}
+
+// The error of creating the temp file in `dir`, or in the default temp directory, of the same
+// kind as `err`: the bare error of the OS, e.g. "No such file or directory", doesn't tell that a
+// temp file was needed, nor how to do without the default temp directory.
+fn temp_file_error(err: std::io::Error, dir: Option<&Path>) -> std::io::Error {
+    let dir = dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+    let message = format!(
+        "cannot create the temp file of the collector in {}, which may not exist or be \
+         writable: {}; set `CollectorConfig::spill_dir` to a writable directory, or use an \
+         in-memory collector",
+        dir.display(),
+        err
+    );
+
+    std::io::Error::new(err.kind(), message)
 }
 
 pub struct TempFdArray<T: 'static> {
     file: NamedTempFile,
-    buffer: &'static mut [T; BUFFER_LENGTH],
+    // only the first `buffer_index` items are owned by the buffer, the others were never pushed,
+    // or were moved to the file or out by `take_all`, and are never dropped
+    buffer: &'static mut [MaybeUninit<T>],
     buffer_index: usize,
+    // the number of items written to the file
+    flushed: usize,
+    // the number of writes to the file, to tell whether `cache` is stale. It only changes through
+    // `&mut self`, which `cached_file` relies on
+    writes: usize,
+    // the file contents at a number of writes, read by the first `try_iter` after a write. It is
+    // only stale after a write through `&mut self`, so the items it holds outlive any borrow of
+    // `self`. A write leaves it in place rather than freeing it, as it may be done by the signal
+    // handler.
+    cache: Mutex<Option<(usize, Vec<ManuallyDrop<T>>)>>,
 }
 
 impl<T> TempFdArray<T> {
     fn new() -> std::io::Result<TempFdArray<T>> {
-        let file = NamedTempFile::new()?;
-        let buffer = Box::new(unsafe { std::mem::MaybeUninit::uninit().assume_init() });
+        Self::with_buffer(BUFFER_LENGTH, None)
+    }
+
+    // An array buffering `buffer_len` items, at least one, in a temp file in `dir`, or in the
+    // default temp directory.
+    fn with_buffer(buffer_len: usize, dir: Option<&Path>) -> std::io::Result<TempFdArray<T>> {
+        let file = match dir {
+            Some(dir) => NamedTempFile::new_in(dir),
+            None => NamedTempFile::new(),
+        };
+        let mut file = file.map_err(|err| temp_file_error(err, dir))?;
+        file.write_all(&spill_header(std::mem::size_of::<T>()))?;
+        // zeroed rather than uninitialized, as the buffer is written to the file as plain bytes
+        let buffer = std::iter::repeat_with(MaybeUninit::zeroed);
+        let buffer: Box<[MaybeUninit<T>]> = buffer.take(buffer_len.max(1)).collect();
         Ok(Self {
             file,
             buffer: Box::leak(buffer),
             buffer_index: 0,
+            flushed: 0,
+            writes: 0,
+            cache: Mutex::new(None),
         })
     }
 
+    /// Reserves `size` bytes of disk for the file, without changing its length, so that later
+    /// writes land contiguously. It is only a hint, ignored where it isn't supported.
+    #[cfg(target_os = "linux")]
+    fn preallocate(&self, size: u64) {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.file.as_file().as_raw_fd();
+        let ret = unsafe { libc::fallocate(fd, libc::FALLOC_FL_KEEP_SIZE, 0, size as libc::off_t) };
+        if ret != 0 {
+            log::debug!(
+                "fail to preallocate the temp file: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn preallocate(&self, _size: u64) {}
+
     fn flush_buffer(&mut self) -> std::io::Result<()> {
         self.buffer_index = 0;
+        self.writes += 1;
         let buf = unsafe {
             std::slice::from_raw_parts(
                 self.buffer.as_ptr() as *const u8,
-                BUFFER_LENGTH * std::mem::size_of::<T>(),
+                std::mem::size_of_val(self.buffer),
             )
         };
         self.file.write_all(buf)?;
+        self.flushed += self.buffer.len();
 
         Ok(())
     }
 
+    /// Replaces the `index`th item pushed, in the file or the buffer, with `item`, and returns
+    /// the replaced one.
+    fn replace(&mut self, index: usize, item: T) -> std::io::Result<ManuallyDrop<T>> {
+        use std::os::unix::fs::FileExt;
+
+        if index >= self.flushed {
+            let replaced = std::mem::replace(
+                &mut self.buffer[index - self.flushed],
+                MaybeUninit::new(item),
+            );
+            return Ok(ManuallyDrop::new(unsafe { replaced.assume_init() }));
+        }
+
+        self.writes += 1;
+        let size = std::mem::size_of::<T>();
+        let offset = SPILL_HEADER_LEN + (index * size) as u64;
+        let file = self.file.as_file();
+        let mut replaced = MaybeUninit::<T>::uninit();
+        // the item is written back as plain bytes, it is never dropped through the file
+        let item = ManuallyDrop::new(item);
+        unsafe {
+            let bytes = std::slice::from_raw_parts_mut(replaced.as_mut_ptr() as *mut u8, size);
+            file.read_exact_at(bytes, offset)?;
+            let bytes = std::slice::from_raw_parts(&*item as *const T as *const u8, size);
+            file.write_all_at(bytes, offset)?;
+
+            Ok(ManuallyDrop::new(replaced.assume_init()))
+        }
+    }
+
     fn push(&mut self, entry: T) -> std::io::Result<()> {
-        if self.buffer_index >= BUFFER_LENGTH {
+        if self.buffer_index >= self.buffer.len() {
             self.flush_buffer()?;
         }
 
-        self.buffer[self.buffer_index] = entry;
+        // the slot is never dropped, see `buffer`
+        self.buffer[self.buffer_index] = MaybeUninit::new(entry);
         self.buffer_index += 1;
 
         Ok(())
     }
 
-    fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &T>> {
-        let mut file_vec = Vec::new();
-        let mut file = self.file.reopen()?;
+    /// Reads the whole file back into a buffer aligned for `T`. It fails rather than building a
+    /// buffer larger than the address space allows, which a multi-GB file would on 32-bit.
+    ///
+    /// The items are bitwise copies of ones pushed earlier, so they are never dropped here.
+    fn read_file(&self) -> std::io::Result<Vec<ManuallyDrop<T>>> {
+        let mut file = self.file.as_file();
+        let file_len = file.metadata()?.len().saturating_sub(SPILL_HEADER_LEN);
+        let length = spilled_length(file_len, std::mem::size_of::<T>())?;
+
+        let mut header = [0; SPILL_HEADER_LEN as usize];
         file.seek(SeekFrom::Start(0))?;
-        file.read_to_end(&mut file_vec)?;
+        file.read_exact(&mut header)?;
+        check_spill_header(&header, std::mem::size_of::<T>())?;
+
+        let mut file_vec = Vec::with_capacity(length);
+        unsafe {
+            let ptr = file_vec.as_mut_ptr();
+            std::ptr::write_bytes(ptr, 0, length);
+            let bytes =
+                std::slice::from_raw_parts_mut(ptr as *mut u8, length * std::mem::size_of::<T>());
+            file.read_exact(bytes)?;
+            file_vec.set_len(length);
+        }
+        // later pushes append to the file
         file.seek(SeekFrom::End(0))?;
 
+        Ok(file_vec)
+    }
+
+    /// Moves every item out of the file and the buffer, leaving both empty.
+    fn take_all(&mut self) -> std::io::Result<Vec<T>> {
+        let file_vec = match self.cache.get_mut().take() {
+            Some((writes, file_vec)) if writes == self.writes => file_vec,
+            _ => self.read_file()?,
+        };
+
+        let mut items = Vec::with_capacity(file_vec.len() + self.buffer_index);
+        items.extend(file_vec.into_iter().map(ManuallyDrop::into_inner));
+        for item in self.buffer[0..self.buffer_index].iter() {
+            items.push(unsafe { item.assume_init_read() });
+        }
+
+        self.buffer_index = 0;
+        self.flushed = 0;
+        self.writes += 1;
+        self.file.as_file().set_len(SPILL_HEADER_LEN)?;
+        self.file.seek(SeekFrom::Start(SPILL_HEADER_LEN))?;
+
+        Ok(items)
+    }
+
+    /// The items of the file, only read again after it has been written to, so iterating several
+    /// times in a row doesn't repeat the IO.
+    fn cached_file(&self) -> std::io::Result<&[ManuallyDrop<T>]> {
+        let mut cache = self.cache.lock();
+        if !matches!(&*cache, Some((writes, _)) if *writes == self.writes) {
+            *cache = None;
+            *cache = Some((self.writes, self.read_file()?));
+        }
+        let file_vec: &[ManuallyDrop<T>] = cache.as_ref().map_or(&[], |(_, file_vec)| file_vec);
+        // SAFETY: `writes` only changes through `&mut self`, so while `self` is borrowed, which
+        // the returned slice is, the cache matches `writes` and no call replaces it. Its items thus
+        // outlive the lock, for as long as this borrow of `self`.
+        Ok(unsafe { std::slice::from_raw_parts(file_vec.as_ptr(), file_vec.len()) })
+    }
+
+    /// The items pushed to the buffer and not written to the file yet.
+    fn buffered(&self) -> &[T] {
+        let ptr = self.buffer.as_ptr() as *const T;
+        unsafe { std::slice::from_raw_parts(ptr, self.buffer_index) }
+    }
+
+    /// The bytes of the buffer and of the items read back from the file and cached, of the file,
+    /// and of the items buffered and not written to it yet.
+    fn usage(&self) -> (u64, u64, u64) {
+        let size = std::mem::size_of::<T>() as u64;
+        let cached = self
+            .cache
+            .lock()
+            .as_ref()
+            .map_or(0, |(_, file_vec)| file_vec.capacity() as u64 * size);
+
+        (
+            self.buffer.len() as u64 * size + cached,
+            SPILL_HEADER_LEN + self.flushed as u64 * size,
+            self.buffer_index as u64 * size,
+        )
+    }
+
+    /// Iterates over the buffer, then the file. The items of the file are borrowed from `cache`,
+    /// so the iterator doesn't depend on any local buffer.
+    fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &T>> {
         Ok(TempFdArrayIterator {
-            buffer: &self.buffer[0..self.buffer_index],
-            file_vec,
+            buffer: self.buffered(),
+            file_vec: self.cached_file()?,
             index: 0,
         })
     }
+
+    /// Iterates over the items in the order they were pushed, i.e. the file, then the buffer.
+    fn try_iter_ordered(&self) -> std::io::Result<impl Iterator<Item = &T>> {
+        let file_vec = self.cached_file()?;
+        let buffer = self.buffered();
+
+        Ok(file_vec.iter().map(|item| &**item).chain(buffer.iter()))
+    }
+}
+
+/// Number of `T`s of `size` bytes in a file of `file_len` bytes. It is an error if they can't be
+/// held in memory, i.e. they would take more than `isize::MAX` bytes, or if the file ends with a
+/// partial `T`, which only a torn write can leave and which would be read as garbage.
+fn spilled_length(file_len: u64, size: usize) -> std::io::Result<usize> {
+    if file_len > isize::MAX as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "temp file of {} bytes exceeds the addressable memory",
+                file_len
+            ),
+        ));
+    }
+    if file_len % size as u64 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "temp file of {} bytes is not made of whole {} bytes entries",
+                file_len, size
+            ),
+        ));
+    }
+
+    Ok(file_len as usize / size)
 }
 
 pub struct TempFdArrayIterator<'a, T> {
     pub buffer: &'a [T],
-    pub file_vec: Vec<u8>,
+    pub file_vec: &'a [ManuallyDrop<T>],
     pub index: usize,
 }
 
@@ -208,128 +609,946 @@ impl<'a, T> Iterator for TempFdArrayIterator<'a, T> {
             self.index += 1;
             Some(&self.buffer[self.index - 1])
         } else {
-            let length = self.file_vec.len() / std::mem::size_of::<T>();
-            let ts =
-                unsafe { std::slice::from_raw_parts(self.file_vec.as_ptr() as *const T, length) };
-            if self.index - self.buffer.len() < ts.len() {
-                self.index += 1;
-                Some(&ts[self.index - self.buffer.len() - 1])
-            } else {
-                None
-            }
+            let item = self.file_vec.get(self.index - self.buffer.len())?;
+            self.index += 1;
+            Some(item)
+        }
+    }
+}
+
+/// The memory and disk taken by a collector, see `Collector::resource_usage`. The memory the keys
+/// own besides their own bytes, if any, isn't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The bytes of the entries of the bucket table, plus those of the evicted entries kept in
+    /// memory by an in-memory collector, or of the buffer of the temp file and of its entries
+    /// cached since it was last read.
+    pub in_memory_bytes: u64,
+    /// The length of the temp file, 0 for an in-memory collector.
+    pub spill_bytes: u64,
+    /// The bytes of the evicted entries buffered and not written to the temp file yet, out of the
+    /// `CollectorConfig::buffer_len` entries of the buffer, counted in `in_memory_bytes`.
+    pub buffer_bytes: u64,
+}
+
+/// The sizes of a `Collector`, see `CollectorBuilder::config`, tuned together in one place. The
+/// default ones are those of `Collector::new`. A size of 0 is taken as 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectorConfig {
+    /// The number of buckets of the table of the hottest entries, `BUCKETS` by default.
+    pub buckets: usize,
+    /// The number of entries of every bucket, `BUCKETS_ASSOCIATIVITY` by default. A new entry
+    /// whose bucket is full evicts one of them to the temp file, see `EvictionPolicy`.
+    pub associativity: usize,
+    /// The number of evicted entries buffered in memory before they are written to the temp file
+    /// at once, `BUFFER_LENGTH` by default. The temp file grows by chunks of as many entries.
+    pub buffer_len: usize,
+    /// The directory of the temp file, `std::env::temp_dir` if `None`, the default.
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        Self {
+            buckets: BUCKETS,
+            associativity: BUCKETS_ASSOCIATIVITY,
+            buffer_len: BUFFER_LENGTH,
+            spill_dir: None,
         }
     }
 }
 
-pub struct Collector<T: Hash + Eq + 'static> {
-    map: StackHashCounter<T>,
-    temp_array: TempFdArray<Entry<T>>,
+pub struct Collector<T: Hash + Eq + 'static, P = MinCount> {
+    map: StackHashCounter<T, BuildHasherDefault<DefaultHasher>, P>,
+    // `None` for an in-memory collector, which keeps evicted entries in `evicted` instead
+    temp_array: Option<TempFdArray<Entry<T>>>,
+    evicted: Vec<Entry<T>>,
+    // the number of evicted entries kept, in the temp file or in `evicted`
+    spilled: usize,
+    spill_limit: Option<SpillLimit>,
+    // the sizes the collector was built with, at least 1
+    config: CollectorConfig,
+}
+
+// The bound of the evicted entries of a collector, see `Collector::with_max_spill_bytes`. Once
+// it is reached, the kept entries are a uniform sample of all the evicted ones.
+struct SpillLimit {
+    // the number of evicted entries which can be kept
+    capacity: usize,
+    // the number of entries evicted since the collector was last drained
+    evictions: u64,
+    // the total count of the dropped entries
+    dropped: isize,
+    rng: u64,
+}
+
+impl SpillLimit {
+    // A random number in `0..bound`, from xorshift64*.
+    fn random(&mut self, bound: u64) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound
+    }
 }
 
 impl<T: Hash + Eq + 'static> Collector<T> {
+    /// Creates a collector spilling to a temp file in the default temp directory. If it can't be
+    /// created, the error, of the kind of the OS one, names the directory and the alternatives.
     pub fn new() -> std::io::Result<Self> {
-        Ok(Self {
-            map: StackHashCounter::<T>::default(),
-            temp_array: TempFdArray::<Entry<T>>::new()?,
-        })
+        Self::with_config(CollectorConfig::default(), false, MinCount)
+    }
+
+    /// Creates a collector which never creates a temp file, for environments where none can be
+    /// written. Evicted entries are kept in a `Vec` growing as needed, instead of being spilled,
+    /// so the signal handler may allocate when it evicts one, unlike with a temp file; a bound
+    /// set with `with_max_spill_bytes` is reserved up front to avoid it.
+    pub fn in_memory() -> Self {
+        Self::with_temp_array(CollectorConfig::default(), None, MinCount)
+    }
+}
+
+impl<T: Hash + Eq + 'static, P: EvictionPolicy<T>> Collector<T, P> {
+    // A collector of the sizes of `config` evicting with `policy`, in memory or with a temp file.
+    fn with_config(config: CollectorConfig, in_memory: bool, policy: P) -> std::io::Result<Self> {
+        let config = CollectorConfig {
+            buckets: config.buckets.max(1),
+            associativity: config.associativity.max(1),
+            buffer_len: config.buffer_len.max(1),
+            ..config
+        };
+        let temp_array = if in_memory {
+            None
+        } else {
+            let dir = config.spill_dir.as_deref();
+            Some(TempFdArray::with_buffer(config.buffer_len, dir)?)
+        };
+
+        Ok(Self::with_temp_array(config, temp_array, policy))
+    }
+
+    fn with_temp_array(
+        config: CollectorConfig,
+        temp_array: Option<TempFdArray<Entry<T>>>,
+        policy: P,
+    ) -> Self {
+        let map = StackHashCounter::with_sizes(
+            BuildHasherDefault::default(),
+            policy,
+            config.buckets,
+            config.associativity,
+        );
+
+        Self {
+            map,
+            temp_array,
+            evicted: Vec::new(),
+            spilled: 0,
+            spill_limit: None,
+            config,
+        }
+    }
+
+    // The collector with its policy boxed, so that collectors of any policy have the same type.
+    pub(crate) fn boxed_policy(self) -> Collector<T, Box<dyn EvictionPolicy<T> + Send + Sync>>
+    where
+        P: Send + Sync + 'static,
+    {
+        let map = StackHashCounter {
+            buckets: self.map.buckets,
+            hash_builder: self.map.hash_builder,
+            policy: Box::new(self.map.policy) as Box<dyn EvictionPolicy<T> + Send + Sync>,
+        };
+
+        Collector {
+            map,
+            temp_array: self.temp_array,
+            evicted: self.evicted,
+            spilled: self.spilled,
+            spill_limit: self.spill_limit,
+            config: self.config,
+        }
+    }
+
+    /// The sizes of the collector, see `CollectorConfig`, with any size of 0 it was configured
+    /// with taken as 1.
+    pub fn config(&self) -> &CollectorConfig {
+        &self.config
+    }
+
+    /// Bounds the evicted entries to `max_bytes`, on disk or in memory for an in-memory
+    /// collector, e.g. for a profiler running for days. The hottest entries are never evicted. Once
+    /// the bound is reached, further entries are sampled down: every new evicted entry either
+    /// replaces a random kept one or is dropped, so that the kept entries stay a uniform sample
+    /// of all the evicted ones, and their counts keep approximately the same proportions. The
+    /// counts of the dropped entries add up to `dropped_count`.
+    ///
+    /// The temp file grows by chunks of `CollectorConfig::buffer_len` entries, which are buffered
+    /// in memory first, so the file never exceeds `max_bytes` but the buffer still holds one
+    /// chunk.
+    pub fn with_max_spill_bytes(self, max_bytes: u64) -> Self {
+        let entry_size = std::mem::size_of::<Entry<T>>().max(1) as u64;
+        let capacity = match &self.temp_array {
+            Some(temp_array) => {
+                let chunk_length = temp_array.buffer.len() as u64;
+                let chunk_size = entry_size * chunk_length;
+                (max_bytes.saturating_sub(SPILL_HEADER_LEN) / chunk_size + 1) * chunk_length
+            }
+            None => max_bytes / entry_size,
+        };
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let capacity = usize::try_from(capacity).unwrap_or(usize::MAX);
+        let mut evicted = self.evicted;
+        if self.temp_array.is_none() {
+            // so that the signal handler never grows it, unless the bound is too large to reserve
+            let _ = evicted.try_reserve(capacity.saturating_sub(evicted.len()));
+        }
+
+        Self {
+            evicted,
+            spill_limit: Some(SpillLimit {
+                capacity,
+                evictions: self.spilled as u64,
+                dropped: 0,
+                rng: seed | 1,
+            }),
+            ..self
+        }
+    }
+
+    /// The total count of the evicted entries dropped since the spill bound was reached, see
+    /// `with_max_spill_bytes`.
+    pub fn dropped_count(&self) -> isize {
+        self.spill_limit.as_ref().map_or(0, |limit| limit.dropped)
+    }
+
+    /// The memory and disk the collector takes now, e.g. to monitor the profiler or choose a
+    /// bound for `with_max_spill_bytes`.
+    pub fn resource_usage(&self) -> ResourceUsage {
+        let entry_size = std::mem::size_of::<Entry<T>>() as u64;
+        let table_entries: usize = self
+            .map
+            .buckets
+            .iter()
+            .map(|bucket| bucket.entries.len())
+            .sum();
+        let table_bytes = table_entries as u64 * entry_size;
+        let (buffered, spill_bytes, buffer_bytes) = match &self.temp_array {
+            Some(temp_array) => temp_array.usage(),
+            None => (0, 0, 0),
+        };
+
+        ResourceUsage {
+            in_memory_bytes: table_bytes + self.evicted.capacity() as u64 * entry_size + buffered,
+            spill_bytes,
+            buffer_bytes,
+        }
     }
 
     pub fn add(&mut self, key: T, count: isize) -> std::io::Result<()> {
         if let Some(evict) = self.map.add(key, count) {
-            self.temp_array.push(evict)?;
+            self.evict(evict)?;
         }
 
         Ok(())
     }
 
-    pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &Entry<T>>> {
-        Ok(self.map.iter().chain(self.temp_array.try_iter()?))
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cell::RefCell;
-    use std::collections::BTreeMap;
-    use std::ffi::c_void;
+    /// Adds every `(item, count)` pair, e.g. to rebuild a collector from serialized entries.
+    /// Duplicate items are merged like with `add`. A count exceeding `isize::MAX` is an
+    /// `InvalidInput` error, the pairs before it are kept.
+    pub fn extend<I: IntoIterator<Item = (T, usize)>>(&mut self, iter: I) -> std::io::Result<()> {
+        for (item, count) in iter {
+            let count = isize::try_from(count).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("count {} exceeds isize::MAX", count),
+                )
+            })?;
+            self.add(item, count)?;
+        }
 
-    #[test]
-    fn stack_hash_counter() {
-        let mut stack_hash_counter = StackHashCounter::<usize>::default();
-        stack_hash_counter.add(0, 1);
-        stack_hash_counter.add(1, 1);
-        stack_hash_counter.add(1, 1);
+        Ok(())
+    }
 
-        stack_hash_counter.iter().for_each(|item| {
-            if item.item == 0 {
-                assert_eq!(item.count, 1);
-            } else if item.item == 1 {
-                assert_eq!(item.count, 2);
-            } else {
-                unreachable!();
+    fn evict(&mut self, entry: Entry<T>) -> std::io::Result<()> {
+        if let Some(limit) = &mut self.spill_limit {
+            limit.evictions += 1;
+            if self.spilled >= limit.capacity {
+                // a reservoir sample: the new entry replaces a kept one with the probability of
+                // being kept among all the evicted ones
+                let index = limit.random(limit.evictions);
+                if index >= self.spilled as u64 {
+                    limit.dropped += entry.count;
+                    return Ok(());
+                }
+                let index = index as usize;
+                let replaced = match &mut self.temp_array {
+                    Some(temp_array) => temp_array.replace(index, entry)?.count,
+                    None => std::mem::replace(&mut self.evicted[index], entry).count,
+                };
+                limit.dropped += replaced;
+                return Ok(());
             }
-        });
-    }
+        }
 
-    fn add_map(hashmap: &mut BTreeMap<usize, isize>, entry: &Entry<usize>) {
-        match hashmap.get_mut(&entry.item) {
+        self.spilled += 1;
+        match &mut self.temp_array {
+            Some(temp_array) => temp_array.push(entry),
             None => {
-                hashmap.insert(entry.item, entry.count);
+                self.evicted.push(entry);
+                Ok(())
             }
-            Some(count) => *count += entry.count,
         }
     }
 
-    #[test]
-    fn evict_test() {
-        let mut stack_hash_counter = StackHashCounter::<usize>::default();
-        let mut real_map = BTreeMap::new();
+    fn take_evicted(&mut self) -> std::io::Result<Vec<Entry<T>>> {
+        let mut evicted = match &mut self.temp_array {
+            Some(temp_array) => temp_array.take_all()?,
+            None => Vec::new(),
+        };
+        evicted.append(&mut self.evicted);
+        self.spilled = 0;
+        if let Some(limit) = &mut self.spill_limit {
+            limit.evictions = 0;
+        }
 
-        for item in 0..(1 << 10) * 4 {
-            for _ in 0..(item % 4) {
-                match stack_hash_counter.add(item, 1) {
-                    None => {}
-                    Some(evict) => {
-                        add_map(&mut real_map, &evict);
-                    }
-                }
-            }
+        Ok(evicted)
+    }
+
+    pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = &Entry<T>>> {
+        let spilled = match &self.temp_array {
+            Some(temp_array) => Some(temp_array.try_iter()?),
+            None => None,
+        };
+
+        Ok(self
+            .map
+            .iter()
+            .chain(spilled.into_iter().flatten())
+            .chain(self.evicted.iter()))
+    }
+
+    /// Copies every entry, merging those which share the same item, without emptying the
+    /// collector. As it only borrows the collector, sampling into a collector shared behind a lock
+    /// is only paused for the time of the copy, and the snapshot is consistent.
+    pub fn snapshot(&self) -> std::io::Result<Vec<Entry<T>>>
+    where
+        T: Clone,
+    {
+        let mut merged: HashMap<&T, isize> = HashMap::new();
+        for entry in self.try_iter()? {
+            *merged.entry(&entry.item).or_insert(0) += entry.count;
         }
 
-        stack_hash_counter.iter().for_each(|entry| {
-            add_map(&mut real_map, &entry);
-        });
+        Ok(merged
+            .into_iter()
+            .filter(|(_, count)| *count != 0)
+            .map(|(item, count)| Entry {
+                item: item.clone(),
+                count,
+            })
+            .collect())
+    }
 
-        for item in 0..(1 << 10) * 4 {
-            let count = (item % 4) as isize;
-            match real_map.get(&item) {
-                Some(item) => {
-                    assert_eq!(*item, count);
-                }
-                None => {
-                    assert_eq!(count, 0);
-                }
+    /// Drops the items whose count, merged over all their entries, fails `f`, e.g. to bound the
+    /// memory of a collector running for hours by dropping the stacks seen only a few times. The
+    /// kept entries are merged, so the temp file is compacted too.
+    pub fn retain<F: Fn(&T, usize) -> bool>(&mut self, f: F) -> std::io::Result<()> {
+        let mut merged: HashMap<T, isize> = HashMap::new();
+        for entry in self.drain()? {
+            *merged.entry(entry.item).or_insert(0) += entry.count;
+        }
+
+        for (item, count) in merged {
+            if f(&item, usize::try_from(count).unwrap_or(0)) {
+                self.add(item, count)?;
             }
         }
+
+        Ok(())
     }
 
-    #[test]
-    fn collector_test() {
-        let mut collector = Collector::new().unwrap();
-        let mut real_map = BTreeMap::new();
+    /// Like `snapshot`, but with the entries sorted by decreasing count, e.g. for top-N views.
+    /// Entries with the same count are sorted by the hash of their item, so the order doesn't
+    /// change from a call to the next. The evicted entries are compacted first.
+    pub fn iter_sorted(&mut self) -> std::io::Result<Vec<Entry<T>>>
+    where
+        T: Clone,
+    {
+        self.compact()?;
+        let mut entries = self.snapshot()?;
+        entries.sort_by_cached_key(|entry| (Reverse(entry.count), self.map.hash(&entry.item)));
+
+        Ok(entries)
+    }
 
-        for item in 0..(1 << 12) * 4 {
-            for _ in 0..(item % 4) {
-                collector.add(item, 1).unwrap();
+    /// Moves every entry out of the collector, leaving it empty. Unlike `try_iter`, the items are
+    /// not borrowed and don't need to be cloned.
+    pub fn drain(&mut self) -> std::io::Result<impl Iterator<Item = Entry<T>> + '_> {
+        let evicted = self.take_evicted()?;
+        Ok(self.map.drain().chain(evicted))
+    }
+
+    /// Merges the evicted entries which share the same item and rewrites the temp file with them,
+    /// truncating it to the compacted size. Counts are preserved.
+    pub fn compact(&mut self) -> std::io::Result<()> {
+        let mut merged: HashMap<T, isize> = HashMap::new();
+        for entry in self.take_evicted()? {
+            *merged.entry(entry.item).or_insert(0) += entry.count;
+        }
+
+        for (item, count) in merged {
+            if count != 0 {
+                self.evict(Entry { item, count })?;
             }
         }
 
-        collector.try_iter().unwrap().for_each(|entry| {
-            add_map(&mut real_map, &entry);
-        });
+        Ok(())
+    }
+}
 
-        for item in 0..(1 << 12) * 4 {
+impl<P: EvictionPolicy<UnresolvedFrames>> Collector<UnresolvedFrames, P> {
+    /// Streams the stacks of the collector resolved by `symbolizer`, without building a `Report`,
+    /// e.g. to forward them elsewhere as they are read. `symbolizer` gets every address once,
+    /// with whether it is a return address, i.e. of a caller, and returns its symbols from the
+    /// innermost inlined function, or `Symbol::unresolved`; the names are shared by the stacks.
+    ///
+    /// Like `try_iter`, an item evicted several times is yielded once per entry, so the stacks
+    /// may repeat, and the entries without a positive count are skipped. None of the processing
+    /// of `ReportBuilder`, e.g. `rebase`, is applied.
+    pub fn iter_resolved<'a, S>(
+        &'a self,
+        mut symbolizer: S,
+    ) -> std::io::Result<impl Iterator<Item = (Frames, usize)> + 'a>
+    where
+        S: FnMut(usize, bool) -> Vec<Symbol> + 'a,
+    {
+        let mut interner = SymbolInterner::default();
+
+        Ok(self
+            .try_iter()?
+            .filter(|entry| entry.count > 0)
+            .map(move |entry| {
+                let frames = interner.resolve_by(entry.item.clone(), &mut symbolizer);
+                (frames, entry.count as usize)
+            }))
+    }
+}
+
+/// A builder of `Collector`.
+#[derive(Debug, Clone)]
+pub struct CollectorBuilder<P = MinCount> {
+    preallocate: u64,
+    in_memory: bool,
+    config: CollectorConfig,
+    policy: P,
+}
+
+impl Default for CollectorBuilder {
+    fn default() -> Self {
+        Self {
+            preallocate: 0,
+            in_memory: false,
+            config: CollectorConfig::default(),
+            policy: MinCount,
+        }
+    }
+}
+
+impl<P> CollectorBuilder<P> {
+    /// Reserve `size` bytes of disk for the temp file up front, so that a large spill is written
+    /// contiguously. It doesn't limit how much can be spilled.
+    pub fn preallocate(self, size: u64) -> Self {
+        Self {
+            preallocate: size,
+            ..self
+        }
+    }
+
+    /// Never create a temp file, see `Collector::in_memory`.
+    pub fn in_memory(self, in_memory: bool) -> Self {
+        Self { in_memory, ..self }
+    }
+
+    /// Set the sizes of the collector and the directory of its temp file, see `CollectorConfig`.
+    pub fn config(self, config: CollectorConfig) -> Self {
+        Self { config, ..self }
+    }
+
+    /// Evict the entries chosen by `policy` from the full buckets, instead of the least counted
+    /// ones, see `EvictionPolicy`.
+    pub fn policy<Q>(self, policy: Q) -> CollectorBuilder<Q> {
+        CollectorBuilder {
+            preallocate: self.preallocate,
+            in_memory: self.in_memory,
+            config: self.config,
+            policy,
+        }
+    }
+
+    pub fn build<T: Hash + Eq + 'static>(self) -> std::io::Result<Collector<T, P>>
+    where
+        P: EvictionPolicy<T>,
+    {
+        let collector = Collector::with_config(self.config, self.in_memory, self.policy)?;
+        if let Some(temp_array) = &collector.temp_array {
+            if self.preallocate > 0 {
+                temp_array.preallocate(self.preallocate);
+            }
+        }
+
+        Ok(collector)
+    }
+}
+
+/// A collector which keeps every item with its timestamp, in the order they were pushed, instead
+/// of counting them, e.g. to draw the samples on a timeline. The items are spilled to a temp file
+/// like the entries evicted from a `Collector`, so memory doesn't grow with their number.
+pub struct TraceCollector<T: 'static> {
+    samples: TempFdArray<(u64, T)>,
+    len: usize,
+}
+
+impl<T: 'static> TraceCollector<T> {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            samples: TempFdArray::new()?,
+            len: 0,
+        })
+    }
+
+    /// Appends `item` taken at `timestamp`, in any unit, e.g. the nanoseconds of a monotonic
+    /// clock. This function has to be AS-safe.
+    pub fn push(&mut self, timestamp: u64, item: T) -> std::io::Result<()> {
+        self.samples.push((timestamp, item))?;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// The number of items pushed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over the items and their timestamps in the order they were pushed.
+    pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = (u64, &T)>> {
+        Ok(self
+            .samples
+            .try_iter_ordered()?
+            .map(|(timestamp, item)| (*timestamp, item)))
+    }
+
+    /// Moves the items and their timestamps out in the order they were pushed, leaving the
+    /// collector empty.
+    pub fn drain(&mut self) -> std::io::Result<Vec<(u64, T)>> {
+        self.len = 0;
+        self.samples.take_all()
+    }
+}
+
+impl TraceCollector<UnresolvedFrames> {
+    /// Aggregates the stacks pushed with a timestamp in `start..end` into a report, each of them
+    /// counted once, e.g. to see what was hot during a spike. The timestamps have no known unit,
+    /// so the `timing` of the report is left to its default. The empty stacks are counted in
+    /// `Report::dropped_samples`, and none of the processing of `ReportBuilder` is applied.
+    pub fn report_between(&self, start: u64, end: u64) -> std::io::Result<Report> {
+        let mut interner = SymbolInterner::default();
+        let mut data = HashMap::new();
+        let (mut dropped_samples, mut truncated_samples) = (0, 0);
+        for (timestamp, item) in self.try_iter()? {
+            if timestamp < start || timestamp >= end {
+                continue;
+            }
+            let frames = interner.resolve(item.clone());
+            if frames.is_empty() {
+                dropped_samples += 1;
+                continue;
+            }
+            if item.truncated {
+                truncated_samples += 1;
+            }
+            *data.entry(frames).or_insert(0) += 1;
+        }
+
+        Ok(Report {
+            data,
+            timing: ReportTiming::default(),
+            dropped_samples,
+            truncated_samples,
+            labels: HashMap::new(),
+            pprof_drop_frames: None,
+            pprof_keep_frames: None,
+            pprof_addresses: false,
+            metadata: BTreeMap::new(),
+            thread_states: Vec::new(),
+        })
+    }
+}
+
+/// The id of a stack in a `HashedCollector`: its hash, and its slot in the side table, which
+/// tells colliding stacks apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StackId {
+    pub hash: u64,
+    pub index: usize,
+}
+
+/// The number of distinct items a `HashedCollector` has room for by default, as many as the
+/// entries of a `Collector`.
+pub const HASHED_ITEMS: usize = BUCKETS * BUCKETS_ASSOCIATIVITY;
+
+/// The number of slots of the side table of a `HashedCollector` an item is looked for in, from
+/// the one of its hash, so that the signal handler never scans the whole table.
+pub const HASHED_PROBES: usize = 16;
+
+/// A collector which counts `StackId`s instead of the items themselves, storing every distinct
+/// item only once in a side table. Counting and spilling then move a small id instead of a large
+/// item, e.g. `UnresolvedFrames`. Items sharing a hash are compared in full, so a collision never
+/// merges distinct items. The profiler counts its samples this way with
+/// `ProfilerGuardBuilder::hashed_stacks`.
+///
+/// The side table is allocated up front with room for a fixed number of items, so that `add`
+/// doesn't allocate either and can be called by the signal handler. An item is only stored in
+/// one of the `HASHED_PROBES` slots following its hash, the counts of the items which don't fit
+/// in them, e.g. once the table is nearly full, add up to `dropped_count`.
+pub struct HashedCollector<T, S = BuildHasherDefault<DefaultHasher>> {
+    ids: Collector<StackId>,
+    // the distinct items with their hashes, in the first slot following their hash which was free,
+    // at most `HASHED_PROBES` slots away
+    items: Vec<Option<(u64, T)>>,
+    // the total count of the items which didn't fit in `items`
+    dropped: isize,
+    hash_builder: S,
+}
+
+impl<T: Hash + Eq> HashedCollector<T> {
+    pub fn new() -> std::io::Result<Self> {
+        Self::with_hasher(BuildHasherDefault::default())
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> HashedCollector<T, S> {
+    /// Creates a collector which hashes the items with hashers built by `hash_builder`.
+    pub fn with_hasher(hash_builder: S) -> std::io::Result<Self> {
+        Ok(Self::with_capacity(
+            HASHED_ITEMS,
+            Collector::new()?,
+            hash_builder,
+        ))
+    }
+
+    /// Creates a collector with room for `capacity` distinct items, at least one, counting their
+    /// ids in `ids`, e.g. an in-memory one.
+    pub fn with_capacity(capacity: usize, ids: Collector<StackId>, hash_builder: S) -> Self {
+        Self {
+            ids,
+            items: std::iter::repeat_with(|| None)
+                .take(capacity.max(1))
+                .collect(),
+            dropped: 0,
+            hash_builder,
+        }
+    }
+
+    /// Returns the id of `item`, adding it to the side table if it's new, or `None` if it's new
+    /// and none of the `HASHED_PROBES` slots following its hash is free. This function is AS-safe
+    /// if hashing and comparing `T` are.
+    pub fn id(&mut self, item: T) -> Option<StackId> {
+        let mut hasher = self.hash_builder.build_hasher();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let start = (hash % self.items.len() as u64) as usize;
+        for offset in 0..self.items.len().min(HASHED_PROBES) {
+            let index = (start + offset) % self.items.len();
+            match &mut self.items[index] {
+                Some((known_hash, known)) if *known_hash == hash && *known == item => {
+                    return Some(StackId { hash, index });
+                }
+                Some(_) => {}
+                free => {
+                    *free = Some((hash, item));
+                    return Some(StackId { hash, index });
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Counts `count` more for `item`. This function has to be AS-safe.
+    pub fn add(&mut self, item: T, count: isize) -> std::io::Result<()> {
+        match self.id(item) {
+            Some(id) => self.ids.add(id, count),
+            None => {
+                self.dropped += count;
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the item of `id`, if it was returned by `id` or `add`.
+    pub fn item(&self, id: StackId) -> Option<&T> {
+        match self.items.get(id.index)? {
+            Some((hash, item)) if *hash == id.hash => Some(item),
+            _ => None,
+        }
+    }
+
+    /// The total count of the items which were dropped, as they didn't fit in the side table,
+    /// or by the bound set on the ids with `Collector::with_max_spill_bytes`.
+    pub fn dropped_count(&self) -> isize {
+        self.dropped + self.ids.dropped_count()
+    }
+
+    /// Iterates over the entries like `Collector::try_iter`, with the ids resolved to their items.
+    pub fn try_iter(&self) -> std::io::Result<impl Iterator<Item = (&T, isize)>> {
+        Ok(self.ids.try_iter()?.map(move |entry| {
+            // every counted id was returned by `id`
+            (self.item(entry.item).unwrap(), entry.count)
+        }))
+    }
+
+    /// Takes every item out with its total count, like `Collector::drain`, emptying the side
+    /// table too.
+    pub fn drain(&mut self) -> std::io::Result<impl Iterator<Item = (T, isize)> + '_> {
+        let mut counts = HashMap::new();
+        for entry in self.ids.drain()? {
+            *counts.entry(entry.item.index).or_insert(0) += entry.count;
+        }
+
+        let items = self.items.iter_mut().enumerate();
+        Ok(items.filter_map(move |(index, slot)| {
+            let (_, item) = slot.take()?;
+            counts.get(&index).map(|count| (item, *count))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::UnresolvedFrame;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashSet};
+    use std::ffi::c_void;
+
+    #[test]
+    fn stack_hash_counter() {
+        let mut stack_hash_counter = StackHashCounter::<usize>::default();
+        stack_hash_counter.add(0, 1);
+        stack_hash_counter.add(1, 1);
+        stack_hash_counter.add(1, 1);
+
+        stack_hash_counter.iter().for_each(|item| {
+            if item.item == 0 {
+                assert_eq!(item.count, 1);
+            } else if item.item == 1 {
+                assert_eq!(item.count, 2);
+            } else {
+                unreachable!();
+            }
+        });
+    }
+
+    /// Hashes every `usize` key to itself plus a fixed seed.
+    struct SeededHasher {
+        seed: u64,
+        state: u64,
+    }
+
+    impl Hasher for SeededHasher {
+        fn finish(&self) -> u64 {
+            self.state
+        }
+
+        fn write(&mut self, _: &[u8]) {
+            unreachable!();
+        }
+
+        fn write_usize(&mut self, i: usize) {
+            self.state = self.seed.wrapping_add(i as u64);
+        }
+    }
+
+    struct SeededHasherBuilder(u64);
+
+    impl BuildHasher for SeededHasherBuilder {
+        type Hasher = SeededHasher;
+
+        fn build_hasher(&self) -> SeededHasher {
+            SeededHasher {
+                seed: self.0,
+                state: 0,
+            }
+        }
+    }
+
+    // hashes every key to the same value
+    #[derive(Default)]
+    struct CollidingHasher;
+
+    impl Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            42
+        }
+
+        fn write(&mut self, _: &[u8]) {}
+    }
+
+    #[test]
+    fn hashed_collector_collisions() {
+        let mut collector =
+            HashedCollector::with_hasher(BuildHasherDefault::<CollidingHasher>::default()).unwrap();
+        collector.add("first stack", 2).unwrap();
+        collector.add("second stack", 3).unwrap();
+        collector.add("first stack", 1).unwrap();
+
+        let first = collector.id("first stack").unwrap();
+        let second = collector.id("second stack").unwrap();
+        assert_eq!(first.hash, second.hash);
+        assert_ne!(first, second);
+
+        let mut counts = BTreeMap::new();
+        for (item, count) in collector.try_iter().unwrap() {
+            *counts.entry(*item).or_insert(0) += count;
+        }
+        let expected: BTreeMap<_, _> = vec![("first stack", 3), ("second stack", 3)]
+            .into_iter()
+            .collect();
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn hashed_collector_full_side_table() {
+        let colliding = BuildHasherDefault::<CollidingHasher>::default();
+        let mut collector = HashedCollector::with_capacity(2, Collector::in_memory(), colliding);
+        collector.add("first stack", 2).unwrap();
+        collector.add("second stack", 3).unwrap();
+        // no room is left for a third stack, the known ones are still counted
+        collector.add("third stack", 4).unwrap();
+        collector.add("first stack", 1).unwrap();
+        assert_eq!(collector.id("third stack"), None);
+        assert_eq!(collector.dropped_count(), 4);
+
+        let mut counts: Vec<_> = collector.drain().unwrap().collect();
+        counts.sort_unstable();
+        assert_eq!(counts, [("first stack", 3), ("second stack", 3)]);
+        assert_eq!(collector.try_iter().unwrap().count(), 0);
+        // the drained slots are free again
+        collector.add("third stack", 1).unwrap();
+        assert_eq!(collector.try_iter().unwrap().count(), 1);
+
+        // colliding items only take the slots following their hash, however large the table
+        let colliding = BuildHasherDefault::<CollidingHasher>::default();
+        let mut collector = HashedCollector::with_capacity(64, Collector::in_memory(), colliding);
+        for item in 0..=HASHED_PROBES {
+            collector.add(item, 1).unwrap();
+        }
+        assert!(collector.id(0).is_some());
+        assert_eq!(collector.id(HASHED_PROBES), None);
+        assert_eq!(collector.dropped_count(), 1);
+        assert_eq!(collector.try_iter().unwrap().count(), HASHED_PROBES);
+    }
+
+    #[test]
+    fn seeded_eviction() {
+        let mut stack_hash_counter = StackHashCounter::with_hasher(SeededHasherBuilder(7));
+
+        // all of these keys fall into the same bucket
+        let keys: Vec<usize> = (0..=BUCKETS_ASSOCIATIVITY).map(|i| i * BUCKETS).collect();
+        let counts = [3, 1, 2, 4];
+        for (key, count) in keys.iter().zip(counts.iter()) {
+            assert!(stack_hash_counter.add(*key, *count).is_none());
+        }
+
+        let evict = stack_hash_counter.add(keys[4], 5).unwrap();
+        assert_eq!(evict.item, keys[1]);
+        assert_eq!(evict.count, 1);
+    }
+
+    #[test]
+    fn iter_full_counter() {
+        let mut stack_hash_counter = StackHashCounter::with_hasher(SeededHasherBuilder(0));
+
+        // fills every slot of every bucket without evicting
+        let total = BUCKETS * BUCKETS_ASSOCIATIVITY;
+        for key in 0..total {
+            assert!(stack_hash_counter.add(key, 1).is_none());
+        }
+
+        // the iterator is flat, so `next` stays shallow however many buckets there are
+        let mut keys: Vec<usize> = stack_hash_counter.iter().map(|entry| entry.item).collect();
+        keys.sort_unstable();
+        assert_eq!(keys.len(), total);
+        assert_eq!(keys, (0..total).collect::<Vec<_>>());
+    }
+
+    fn add_map(hashmap: &mut BTreeMap<usize, isize>, entry: &Entry<usize>) {
+        match hashmap.get_mut(&entry.item) {
+            None => {
+                hashmap.insert(entry.item, entry.count);
+            }
+            Some(count) => *count += entry.count,
+        }
+    }
+
+    #[test]
+    fn evict_test() {
+        let mut stack_hash_counter = StackHashCounter::<usize>::default();
+        let mut real_map = BTreeMap::new();
+
+        for item in 0..(1 << 10) * 4 {
+            for _ in 0..(item % 4) {
+                match stack_hash_counter.add(item, 1) {
+                    None => {}
+                    Some(evict) => {
+                        add_map(&mut real_map, &evict);
+                    }
+                }
+            }
+        }
+
+        stack_hash_counter.iter().for_each(|entry| {
+            add_map(&mut real_map, entry);
+        });
+
+        for item in 0..(1 << 10) * 4 {
+            let count = (item % 4) as isize;
+            match real_map.get(&item) {
+                Some(item) => {
+                    assert_eq!(*item, count);
+                }
+                None => {
+                    assert_eq!(count, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn collector_test() {
+        let mut collector = Collector::new().unwrap();
+        let mut real_map = BTreeMap::new();
+
+        for item in 0..(1 << 12) * 4 {
+            for _ in 0..(item % 4) {
+                collector.add(item, 1).unwrap();
+            }
+        }
+
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut real_map, entry);
+        });
+
+        for item in 0..(1 << 12) * 4 {
             let count = (item % 4) as isize;
             match real_map.get(&item) {
                 Some(value) => {
@@ -342,6 +1561,851 @@ mod tests {
         }
     }
 
+    #[test]
+    fn spilled_length_overflow() {
+        assert_eq!(spilled_length(0, 8).unwrap(), 0);
+        assert_eq!(spilled_length(16, 8).unwrap(), 2);
+        assert!(spilled_length(17, 8).is_err());
+        assert_eq!(
+            spilled_length(isize::MAX as u64, 1).unwrap(),
+            isize::MAX as usize
+        );
+        assert!(spilled_length(isize::MAX as u64 + 1, 1).is_err());
+        assert!(spilled_length(u64::MAX, 8).is_err());
+    }
+
+    // Reading the file back reinterprets its bytes as `T`s, so CI runs this under Miri
+    // (`MIRIFLAGS="-Zmiri-disable-isolation -Zmiri-ignore-leaks" cargo miri test temp_fd_array`)
+    // to check alignment. The buffer is leaked on purpose.
+    #[test]
+    fn temp_fd_array_round_trip() {
+        let mut array = TempFdArray::<u64>::new().unwrap();
+        let length = BUFFER_LENGTH * 2 + 3;
+        for i in 0..length {
+            array.push(i as u64 * 3).unwrap();
+        }
+
+        let items: Vec<u64> = array.try_iter().unwrap().copied().collect();
+        let mut expected: Vec<u64> = (0..length).map(|i| i as u64 * 3).collect();
+        // the buffered items are yielded before the spilled ones
+        expected.rotate_left(BUFFER_LENGTH * 2);
+        assert_eq!(items, expected);
+
+        let mut taken = array.take_all().unwrap();
+        taken.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(taken, expected);
+        assert_eq!(array.try_iter().unwrap().count(), 0);
+    }
+
+    // The buffered items are moved out by `take_all`, the next pushes must not drop them again.
+    // They stay in the buffer, as one spilled to the file would lose its provenance under Miri.
+    #[test]
+    fn temp_fd_array_take_all_owned_items() {
+        let mut array = TempFdArray::<String>::with_buffer(4, None).unwrap();
+        for round in 0..3 {
+            for i in 0..3 {
+                array.push(format!("{}-{}", round, i)).unwrap();
+            }
+            array
+                .replace(1, "replaced".to_owned())
+                .map(ManuallyDrop::into_inner)
+                .unwrap();
+            let taken = array.take_all().unwrap();
+            let expected = [
+                format!("{}-0", round),
+                "replaced".to_owned(),
+                format!("{}-2", round),
+            ];
+            assert_eq!(taken, expected);
+        }
+        array.push("dropped with the array".to_owned()).unwrap();
+    }
+
+    // The iterators borrow the cached file contents, which a read while they are alive must not
+    // replace. CI runs it under Miri, like `temp_fd_array_round_trip`, to check that they never
+    // read freed memory.
+    #[test]
+    fn temp_fd_array_iterators_outlive_reads() {
+        let mut array = TempFdArray::<u64>::new().unwrap();
+        let length = BUFFER_LENGTH * 2 + 3;
+        for i in 0..length {
+            array.push(i as u64).unwrap();
+        }
+        let expected: Vec<u64> = (0..length as u64).collect();
+
+        let mut first = array.try_iter().unwrap();
+        let first_item = first.next().copied();
+        let cached = array.cached_file().unwrap().as_ptr();
+        let ordered: Vec<u64> = array.try_iter_ordered().unwrap().copied().collect();
+        assert_eq!(ordered, expected);
+        assert!(array.usage().0 > 0);
+        assert_eq!(array.cached_file().unwrap().as_ptr(), cached);
+        let mut items: Vec<u64> = first_item.into_iter().chain(first.copied()).collect();
+        items.sort_unstable();
+        assert_eq!(items, expected);
+
+        // a write to the file, which no iterator can outlive, makes the next read replace the
+        // cache
+        let writes = array.writes;
+        for i in length..length + BUFFER_LENGTH {
+            array.push(i as u64).unwrap();
+        }
+        assert!(array.writes > writes);
+        let length = length + BUFFER_LENGTH;
+        let ordered = array.try_iter_ordered().unwrap();
+        assert_eq!(array.try_iter().unwrap().count(), length);
+        assert!(ordered.copied().eq(0..length as u64));
+        assert_eq!(array.take_all().unwrap().len(), length);
+    }
+
+    #[test]
+    fn trace_collector() {
+        let mut collector = TraceCollector::<usize>::new().unwrap();
+        assert!(collector.is_empty());
+        // spilling some of them to the file
+        let length = BUFFER_LENGTH * 2 + 3;
+        for i in 0..length {
+            collector.push(1_000 + i as u64 * 10, length - i).unwrap();
+        }
+        assert_eq!(collector.len(), length);
+
+        let expected: Vec<(u64, usize)> = (0..length)
+            .map(|i| (1_000 + i as u64 * 10, length - i))
+            .collect();
+        let items: Vec<(u64, usize)> = collector
+            .try_iter()
+            .unwrap()
+            .map(|(timestamp, item)| (timestamp, *item))
+            .collect();
+        assert_eq!(items, expected);
+
+        assert_eq!(collector.drain().unwrap(), expected);
+        assert!(collector.is_empty());
+        assert_eq!(collector.try_iter().unwrap().count(), 0);
+
+        collector.push(7, 1).unwrap();
+        let items: Vec<_> = collector.try_iter().unwrap().collect();
+        assert_eq!(items, [(7, &1)]);
+    }
+
+    #[test]
+    fn report_between() {
+        let stack = |ip: usize| {
+            let frame = UnresolvedFrame {
+                ip,
+                symbol_address: ip,
+            };
+            UnresolvedFrames::new(&[frame], b"main", 1)
+        };
+        // one stack per phase of 30 milliseconds, sampled every millisecond
+        let mut collector = TraceCollector::new().unwrap();
+        for millis in 0..90 {
+            let phase = millis / 30;
+            collector
+                .push(millis * 1_000_000, stack(0x100 + phase as usize))
+                .unwrap();
+        }
+        collector.push(40_000_000, stack(0x200)).unwrap();
+        collector
+            .push(41_000_000, UnresolvedFrames::new(&[], b"main", 1))
+            .unwrap();
+
+        let report = collector.report_between(25_000_000, 45_000_000).unwrap();
+        let mut counts: Vec<(String, isize)> = report
+            .data
+            .iter()
+            .map(|(frames, count)| (frames.frames[0][0].name(), *count))
+            .collect();
+        counts.sort();
+        let expected = [
+            ("<addr 0x100>", 5),
+            ("<addr 0x101>", 15),
+            ("<addr 0x200>", 1),
+        ];
+        let expected: Vec<(String, isize)> = expected
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count))
+            .collect();
+        assert_eq!(counts, expected);
+        assert_eq!(report.dropped_samples, 1);
+
+        let report = collector.report_between(90_000_000, u64::MAX).unwrap();
+        assert!(report.data.is_empty());
+        assert_eq!(collector.report_between(0, u64::MAX).unwrap().data.len(), 4);
+    }
+
+    #[test]
+    fn partial_entry() {
+        let mut array = TempFdArray::<u64>::new().unwrap();
+        for i in 0..BUFFER_LENGTH + 1 {
+            array.push(i as u64).unwrap();
+        }
+        assert_eq!(array.try_iter().unwrap().count(), BUFFER_LENGTH + 1);
+
+        // a torn write leaves a partial entry at the end of the file
+        array.file.write_all(&[0xff; 3]).unwrap();
+        array.flush_buffer().unwrap();
+        let err = array.try_iter().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(array.take_all().is_err());
+    }
+
+    #[test]
+    fn spill_header() {
+        let mut array = TempFdArray::<u64>::new().unwrap();
+        for i in 0..BUFFER_LENGTH + 1 {
+            array.push(i as u64).unwrap();
+        }
+        assert_eq!(array.try_iter().unwrap().count(), BUFFER_LENGTH + 1);
+
+        let write_at = |array: &mut TempFdArray<u64>, offset: u64, bytes: &[u8]| {
+            use std::os::unix::fs::FileExt;
+
+            array.file.as_file().write_all_at(bytes, offset).unwrap();
+            // so that the file is read again
+            array.writes += 1;
+        };
+        let tamper = |array: &mut TempFdArray<u64>, offset: u64, bytes: &[u8]| {
+            write_at(array, offset, bytes);
+            array.try_iter().err().unwrap()
+        };
+        let err = tamper(&mut array, 8, &(SPILL_VERSION + 1).to_le_bytes());
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let version = format!("version {}", SPILL_VERSION + 1);
+        assert!(err.to_string().contains(&version), "{}", err);
+        let err = tamper(&mut array, 8, &super::spill_header(4)[8..16]);
+        assert!(err.to_string().contains("entries of 4 bytes"), "{}", err);
+        let err = tamper(&mut array, 0, b"NOTSPILL");
+        assert!(err.to_string().contains("not a spill file"), "{}", err);
+        assert!(array.take_all().is_err());
+
+        // restored, the file reads fine again
+        write_at(&mut array, 0, &super::spill_header(8));
+        assert_eq!(array.take_all().unwrap().len(), BUFFER_LENGTH + 1);
+    }
+
+    #[test]
+    fn cached_iter() {
+        let mut array = TempFdArray::<u64>::new().unwrap();
+        for i in 0..BUFFER_LENGTH * 2 + 3 {
+            array.push(i as u64).unwrap();
+        }
+
+        let first: Vec<u64> = array.try_iter().unwrap().copied().collect();
+        // had the file been read again, the second iteration would end after the buffer
+        array.file.as_file().set_len(SPILL_HEADER_LEN).unwrap();
+        array.file.seek(SeekFrom::Start(SPILL_HEADER_LEN)).unwrap();
+        let second: Vec<u64> = array.try_iter().unwrap().copied().collect();
+        assert_eq!(first, second);
+
+        // flushing the buffer invalidates the cache
+        for i in 0..BUFFER_LENGTH {
+            array.push(i as u64).unwrap();
+        }
+        assert_eq!(array.try_iter().unwrap().count(), BUFFER_LENGTH + 3);
+    }
+
+    #[test]
+    fn drain_test() {
+        let mut collector = Collector::new().unwrap();
+        for item in 0..(1 << 12) * 8 {
+            collector.add(item, (item % 4) as isize).unwrap();
+        }
+
+        let mut iterated = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut iterated, entry);
+        });
+
+        let mut drained = BTreeMap::new();
+        collector.drain().unwrap().for_each(|entry| {
+            add_map(&mut drained, &entry);
+        });
+
+        assert_eq!(iterated, drained);
+        assert_eq!(collector.try_iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn compact_test() {
+        let mut collector = Collector::new().unwrap();
+        for _ in 0..3 {
+            for item in 0..(1 << 12) * 8 {
+                collector.add(item, 1).unwrap();
+            }
+        }
+
+        let mut before = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut before, entry);
+        });
+        let file_len_before = collector
+            .temp_array
+            .as_ref()
+            .unwrap()
+            .file
+            .as_file()
+            .metadata()
+            .unwrap()
+            .len();
+
+        collector.compact().unwrap();
+
+        let mut after = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut after, entry);
+        });
+        let file_len_after = collector
+            .temp_array
+            .as_ref()
+            .unwrap()
+            .file
+            .as_file()
+            .metadata()
+            .unwrap()
+            .len();
+
+        assert!(file_len_after < file_len_before);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn preallocate() {
+        let entry_size = std::mem::size_of::<Entry<usize>>() as u64;
+        let mut collector = CollectorBuilder::default()
+            .preallocate(BUFFER_LENGTH as u64 * entry_size)
+            .build()
+            .unwrap();
+        let file_len = |collector: &Collector<usize>| {
+            let temp_array = collector.temp_array.as_ref().unwrap();
+            temp_array.file.as_file().metadata().unwrap().len()
+        };
+        // the space is reserved, the file only has its header
+        assert_eq!(file_len(&collector), SPILL_HEADER_LEN);
+
+        let mut real_map = BTreeMap::new();
+        for item in 0..(1 << 12) * 16 {
+            collector.add(item, 1).unwrap();
+        }
+        assert!(file_len(&collector) > BUFFER_LENGTH as u64 * entry_size);
+
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut real_map, entry);
+        });
+        assert_eq!(real_map.len(), (1 << 12) * 16);
+        assert!(real_map.values().all(|count| *count == 1));
+    }
+
+    #[test]
+    fn resource_usage() {
+        let config = CollectorConfig {
+            buckets: 64,
+            associativity: 2,
+            buffer_len: 16,
+            spill_dir: None,
+        };
+        let entry_size = std::mem::size_of::<Entry<usize>>() as u64;
+        let table_bytes = (config.buckets * config.associativity) as u64 * entry_size;
+        let buffer_len = config.buffer_len as u64;
+        let items = config.buckets * config.associativity * 2;
+
+        let builder = CollectorBuilder::default().config(config);
+        let mut collector = builder.clone().build().unwrap();
+        for item in 0..items {
+            collector.add(item, 1).unwrap();
+        }
+        // every entry is either in the table, the temp file or the buffer
+        let usage = collector.resource_usage();
+        assert_eq!(usage.in_memory_bytes, table_bytes + buffer_len * entry_size);
+        let temp_array = collector.temp_array.as_ref().unwrap();
+        assert_eq!(
+            usage.spill_bytes,
+            temp_array.file.as_file().metadata().unwrap().len()
+        );
+        let spilled = (usage.spill_bytes - SPILL_HEADER_LEN) / entry_size;
+        assert!(spilled > 0);
+        assert_eq!(spilled % buffer_len, 0);
+        let buffered = usage.buffer_bytes / entry_size;
+        assert_eq!(usage.buffer_bytes % entry_size, 0);
+        assert!(buffered < buffer_len);
+        let in_table = collector.map.iter().count() as u64;
+        assert_eq!(in_table + spilled + buffered, items as u64);
+        // iterating caches the file in memory
+        assert_eq!(collector.try_iter().unwrap().count(), items);
+        let cached = collector.resource_usage().in_memory_bytes - usage.in_memory_bytes;
+        assert_eq!(cached, usage.spill_bytes - SPILL_HEADER_LEN);
+
+        let mut collector = builder.in_memory(true).build().unwrap();
+        for item in 0..items {
+            collector.add(item, 1).unwrap();
+        }
+        let usage = collector.resource_usage();
+        assert_eq!((usage.spill_bytes, usage.buffer_bytes), (0, 0));
+        let evicted = collector.evicted.capacity() as u64 * entry_size;
+        assert!(!collector.evicted.is_empty());
+        assert_eq!(usage.in_memory_bytes, table_bytes + evicted);
+    }
+
+    #[test]
+    fn iter_resolved() {
+        // addresses below any mapping, left unresolved in a report too
+        let stack = |index: usize| {
+            let frames: Vec<UnresolvedFrame> = (0..3)
+                .map(|depth| {
+                    let ip = 0x100 * (depth + 1) + (index >> (5 * depth)) % 32;
+                    UnresolvedFrame {
+                        ip,
+                        symbol_address: ip,
+                    }
+                })
+                .collect();
+            UnresolvedFrames::new(&frames, b"main", 1)
+        };
+        // twice as many stacks as the table holds, added twice so that some are spilled twice
+        let mut collector = Collector::new().unwrap();
+        for _ in 0..2 {
+            for index in 0..BUCKETS * BUCKETS_ASSOCIATIVITY * 2 {
+                collector.add(stack(index), 1).unwrap();
+            }
+        }
+
+        let mut resolved = HashSet::new();
+        let mut streamed: HashMap<Frames, usize> = HashMap::new();
+        let symbolizer = |ip: usize, caller: bool| {
+            assert!(resolved.insert((ip, caller)));
+            vec![Symbol::unresolved(ip as *mut c_void)]
+        };
+        for (frames, count) in collector.iter_resolved(symbolizer).unwrap() {
+            *streamed.entry(frames).or_insert(0) += count;
+        }
+        // the leaves and the callers of both depths
+        assert_eq!(resolved.len(), 3 * 32);
+
+        let guard = crate::ProfilerGuardBuilder::default()
+            .timer_kind(crate::TimerKind::Manual)
+            .collector(collector)
+            .build()
+            .unwrap();
+        let report = guard.report().build().unwrap();
+        let reported: HashMap<Frames, usize> = report
+            .data
+            .into_iter()
+            .map(|(frames, count)| (frames, count as usize))
+            .collect();
+        assert_eq!(streamed.len(), BUCKETS * BUCKETS_ASSOCIATIVITY * 2);
+        assert!(streamed == reported);
+    }
+
+    #[test]
+    fn collector_config() {
+        let config = CollectorConfig {
+            buckets: 16,
+            associativity: 2,
+            buffer_len: 8,
+            spill_dir: None,
+        };
+        let mut collector: Collector<usize> = CollectorBuilder::default()
+            .config(config.clone())
+            .build()
+            .unwrap();
+        assert_eq!(collector.config(), &config);
+        assert_eq!(collector.map.buckets.len(), 16);
+        assert!(collector
+            .map
+            .buckets
+            .iter()
+            .all(|bucket| bucket.entries.len() == 2));
+        let temp_array = collector.temp_array.as_ref().unwrap();
+        assert_eq!(temp_array.buffer.len(), 8);
+
+        let items = 16 * 2 * 4;
+        for item in 0..items {
+            collector.add(item, 1).unwrap();
+        }
+        let entry_size = std::mem::size_of::<Entry<usize>>() as u64;
+        let usage = collector.resource_usage();
+        assert_eq!(collector.map.iter().count(), 16 * 2);
+        assert_eq!((usage.spill_bytes - SPILL_HEADER_LEN) % (8 * entry_size), 0);
+        let mut counted: Vec<usize> = collector.try_iter().unwrap().map(|e| e.item).collect();
+        counted.sort_unstable();
+        assert_eq!(counted, (0..items).collect::<Vec<_>>());
+
+        // sizes of 0 are taken as 1
+        let collector: Collector<usize> = CollectorBuilder::default()
+            .in_memory(true)
+            .config(CollectorConfig {
+                buckets: 0,
+                associativity: 0,
+                buffer_len: 0,
+                spill_dir: None,
+            })
+            .build()
+            .unwrap();
+        let config = collector.config();
+        assert_eq!(
+            (config.buckets, config.associativity, config.buffer_len),
+            (1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn in_memory_test() {
+        let mut collector = Collector::in_memory();
+        assert!(collector.temp_array.is_none());
+
+        let mut real_map = BTreeMap::new();
+        for item in 0..(1 << 12) * 8 {
+            collector.add(item, (item % 4 + 1) as isize).unwrap();
+        }
+        assert!(!collector.evicted.is_empty());
+
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut real_map, entry);
+        });
+        for item in 0..(1 << 12) * 8 {
+            assert_eq!(real_map[&item], (item % 4 + 1) as isize);
+        }
+
+        collector.compact().unwrap();
+        let mut drained = BTreeMap::new();
+        collector.drain().unwrap().for_each(|entry| {
+            add_map(&mut drained, &entry);
+        });
+        assert_eq!(real_map, drained);
+        assert_eq!(collector.try_iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn max_spill_bytes() {
+        let chunk = (BUFFER_LENGTH * std::mem::size_of::<Entry<u64>>()) as u64;
+        let max_bytes = chunk * 2 + chunk / 2;
+        for collector in [Collector::new().unwrap(), Collector::in_memory()] {
+            let mut collector = collector.with_max_spill_bytes(max_bytes);
+            // a quarter of the samples are of the items which are multiples of 4, every item is
+            // only sampled a few times, so that most of them are evicted
+            let added = 200_000;
+            for _ in 0..added {
+                collector.add(rand::random::<u64>() >> 8, 1).unwrap();
+            }
+
+            match &collector.temp_array {
+                Some(temp_array) => {
+                    let file_size = temp_array.file.as_file().metadata().unwrap().len();
+                    assert!(file_size > 0 && file_size <= max_bytes, "{}", file_size);
+                }
+                None => {
+                    let evicted_size = collector.evicted.len() * std::mem::size_of::<Entry<u64>>();
+                    assert!(evicted_size as u64 <= max_bytes);
+                }
+            }
+            let dropped = collector.dropped_count();
+            assert!(dropped > added / 2, "{}", dropped);
+
+            let (mut quarter, mut kept) = (0, 0);
+            for entry in collector.try_iter().unwrap() {
+                kept += entry.count;
+                if entry.item % 4 == 0 {
+                    quarter += entry.count;
+                }
+            }
+            assert_eq!(kept + dropped, added);
+            let share = quarter as f64 / kept as f64;
+            assert!((share - 0.25).abs() < 0.03, "{}", share);
+
+            // draining starts sampling over
+            collector.drain().unwrap().for_each(drop);
+            collector.add(1, 1).unwrap();
+            assert_eq!(collector.try_iter().unwrap().count(), 1);
+        }
+    }
+
+    #[test]
+    fn bucket_length() {
+        let mut bucket = Bucket::<usize>::default();
+        let mut evicted = 0;
+        for key in 0..100 {
+            for _ in 0..key % 3 + 1 {
+                evicted += bucket.add(key, 1, &MinCount).is_some() as usize;
+                assert!(bucket.length <= BUCKETS_ASSOCIATIVITY);
+                assert_eq!(bucket.iter().count(), bucket.length);
+            }
+        }
+        assert_eq!(evicted, 100 - BUCKETS_ASSOCIATIVITY);
+
+        // a length past the entries is never followed
+        bucket.length = BUCKETS_ASSOCIATIVITY + 10;
+        assert_eq!(bucket.iter().count(), BUCKETS_ASSOCIATIVITY);
+        assert_eq!(bucket.drain().count(), BUCKETS_ASSOCIATIVITY);
+        assert_eq!(bucket.length, 0);
+    }
+
+    #[test]
+    fn bucket_owned_items() {
+        let mut bucket = Bucket::<String>::with_associativity(2);
+        for round in 0..3 {
+            for i in 0..4 {
+                bucket.add(format!("{}-{}", round, i), 1, &MinCount);
+            }
+            let items: Vec<String> = bucket.drain().map(|entry| entry.item).collect();
+            assert_eq!(items, [format!("{}-2", round), format!("{}-3", round)]);
+        }
+    }
+
+    #[test]
+    fn bucket_eviction_ties() {
+        let mut bucket = Bucket::<usize>::default();
+        for item in 0..BUCKETS_ASSOCIATIVITY {
+            assert!(bucket.add(item, 1, &MinCount).is_none());
+        }
+
+        // every entry has the same count, the oldest ones are evicted first
+        for item in BUCKETS_ASSOCIATIVITY..BUCKETS_ASSOCIATIVITY * 3 {
+            let evicted = bucket.add(item, 1, &MinCount).unwrap();
+            assert_eq!(evicted.item, item - BUCKETS_ASSOCIATIVITY);
+        }
+
+        // an added count doesn't change the insertion order
+        bucket.add(BUCKETS_ASSOCIATIVITY * 2, 1, &MinCount);
+        let evicted = bucket.add(0, 1, &MinCount).unwrap();
+        assert_eq!(evicted.item, BUCKETS_ASSOCIATIVITY * 2 + 1);
+    }
+
+    // Evicts the oldest inserted entry, whatever its count.
+    struct EvictOldest;
+
+    impl<T> EvictionPolicy<T> for EvictOldest {
+        fn victim(&self, _: &[Entry<T>]) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn eviction_policy() {
+        let fill = |bucket: &mut Bucket<usize>| {
+            for (item, count) in [(0, 5), (1, 1), (2, 3), (3, 4)] {
+                assert!(bucket.add(item, count, &MinCount).is_none());
+            }
+        };
+
+        let mut bucket = Bucket::<usize>::default();
+        fill(&mut bucket);
+        assert_eq!(bucket.add(9, 1, &MinCount).unwrap().item, 1);
+        let mut bucket = Bucket::<usize>::default();
+        fill(&mut bucket);
+        assert_eq!(bucket.add(9, 1, &EvictOldest).unwrap().item, 0);
+        // the new entry is the newest one, the others are still in insertion order
+        let items: Vec<usize> = bucket.iter().map(|entry| entry.item).collect();
+        assert_eq!(items, [1, 2, 3, 9]);
+
+        // every key lands in the same bucket
+        let hasher = BuildHasherDefault::<CollidingHasher>::default();
+        let mut counter = StackHashCounter::with_policy(hasher, EvictOldest);
+        for item in 0..BUCKETS_ASSOCIATIVITY {
+            counter.add(item, 10);
+        }
+        assert_eq!(counter.add(100, 1).unwrap().item, 0);
+        assert_eq!(counter.add(101, 1).unwrap().item, 1);
+
+        // a hit makes the entry the most recently used one, whatever its count
+        let mut bucket = Bucket::<usize>::default();
+        fill(&mut bucket);
+        assert!(bucket.add(0, 1, &LeastRecentlyUsed).is_none());
+        let items: Vec<usize> = bucket.iter().map(|entry| entry.item).collect();
+        assert_eq!(items, [1, 2, 3, 0]);
+        assert_eq!(bucket.add(9, 1, &LeastRecentlyUsed).unwrap().item, 1);
+        assert_eq!(bucket.add(1, 1, &LeastRecentlyUsed).unwrap().item, 2);
+        let counts: Vec<(usize, isize)> = bucket
+            .iter()
+            .map(|entry| (entry.item, entry.count))
+            .collect();
+        assert_eq!(counts, [(3, 4), (0, 6), (9, 1), (1, 1)]);
+
+        // the policy of a collector is the one of its builder
+        let config = CollectorConfig {
+            buckets: 1,
+            ..CollectorConfig::default()
+        };
+        let builder = CollectorBuilder::default().in_memory(true).config(config);
+        let mut collector = builder.policy(LeastRecentlyUsed).build().unwrap();
+        for (item, count) in [(0, 100), (1, 1), (2, 1), (3, 1), (0, 1), (4, 1)] {
+            collector.add(item, count).unwrap();
+        }
+        let evicted: Vec<usize> = collector.evicted.iter().map(|entry| entry.item).collect();
+        assert_eq!(evicted, [1]);
+    }
+
+    #[test]
+    fn extend() {
+        let pairs: Vec<(usize, usize)> = (0..(1 << 12) * 8)
+            .chain(0..1 << 12)
+            .map(|item| (item, item % 4 + 1))
+            .collect();
+        let mut expected = BTreeMap::new();
+        for (item, count) in pairs.iter() {
+            *expected.entry(*item).or_insert(0) += *count as isize;
+        }
+
+        let mut collector = Collector::new().unwrap();
+        collector.extend(pairs).unwrap();
+        let mut merged = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut merged, entry);
+        });
+        assert_eq!(merged, expected);
+
+        let err = collector.extend(vec![(0, usize::MAX)]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn retain() {
+        // enough items to evict some of them, every even one is added twice
+        let mut collector = Collector::new().unwrap();
+        for item in 0..(1 << 12) * 8 {
+            collector.add(item, 1).unwrap();
+        }
+        for item in (0..(1 << 12) * 8).step_by(2) {
+            collector.add(item, 1).unwrap();
+        }
+
+        collector.retain(|_, count| count >= 2).unwrap();
+        let mut merged = BTreeMap::new();
+        collector.try_iter().unwrap().for_each(|entry| {
+            add_map(&mut merged, entry);
+        });
+        let expected: BTreeMap<usize, isize> = (0..(1 << 12) * 8)
+            .step_by(2)
+            .map(|item| (item, 2))
+            .collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn iter_sorted() {
+        // enough items to evict some of them, with counts repeating to exercise the ties
+        let mut collector = Collector::new().unwrap();
+        let mut expected = BTreeMap::new();
+        for item in 0..(1 << 12) * 8 {
+            let count = item as isize % 7 + 1;
+            collector.add(item, count).unwrap();
+            collector.add(item % 100, 1).unwrap();
+            *expected.entry(item).or_insert(0) += count;
+            *expected.entry(item % 100).or_insert(0) += 1;
+        }
+
+        let sorted = collector.iter_sorted().unwrap();
+        assert!(sorted.windows(2).all(|pair| pair[0].count >= pair[1].count));
+        let merged: BTreeMap<usize, isize> = sorted
+            .iter()
+            .map(|entry| (entry.item, entry.count))
+            .collect();
+        assert_eq!(sorted.len(), merged.len());
+        assert_eq!(merged, expected);
+
+        // the ties are broken the same way every time
+        let items: Vec<usize> = sorted.iter().map(|entry| entry.item).collect();
+        let again: Vec<usize> = collector
+            .iter_sorted()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.item)
+            .collect();
+        assert_eq!(items, again);
+    }
+
+    #[test]
+    fn snapshot() {
+        use parking_lot::RwLock;
+        use std::sync::Arc;
+
+        const TOTAL: isize = 1 << 16;
+
+        // the collector and the total count added to it
+        let shared = Arc::new(RwLock::new((Collector::new().unwrap(), 0)));
+
+        let sampler = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for chunk in 0..TOTAL / 64 {
+                    let mut shared = shared.write();
+                    let (collector, total) = &mut *shared;
+                    for item in chunk * 64..(chunk + 1) * 64 {
+                        // enough distinct items to spill into the temp file
+                        collector.add(item % (1 << 15), 1).unwrap();
+                        *total += 1;
+                    }
+                }
+            })
+        };
+
+        let mut last_total = 0;
+        while last_total < TOTAL {
+            let shared = shared.read();
+            let (collector, total) = (&shared.0, shared.1);
+            let snapshot = collector.snapshot().unwrap();
+            drop(shared);
+
+            let items: HashSet<isize> = snapshot.iter().map(|entry| entry.item).collect();
+            assert_eq!(items.len(), snapshot.len());
+            let sum: isize = snapshot.iter().map(|entry| entry.count).sum();
+            assert_eq!(sum, total);
+            assert!(sum >= last_total);
+            last_total = sum;
+        }
+
+        sampler.join().unwrap();
+        let snapshot = shared.read().0.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 1 << 15);
+        assert!(snapshot.iter().all(|entry| entry.count == TOTAL >> 15));
+    }
+
+    #[test]
+    fn in_memory_without_temp_dir() {
+        // tempfile creates files in `TMPDIR`, so this runs itself again in a child process with an
+        // unusable one, where only the in-memory collector works
+        if std::env::var_os("PPROF_TEST_NO_TEMP_DIR").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "collector::tests::in_memory_without_temp_dir"])
+                .env("PPROF_TEST_NO_TEMP_DIR", "1")
+                .env("TMPDIR", "/nonexistent/pprof")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        // the errors name the directory and the alternatives, whoever creates the temp file
+        let descriptive = |message: String| {
+            let parts = [
+                "in /nonexistent/pprof",
+                "CollectorConfig::spill_dir",
+                "in-memory",
+            ];
+            assert!(
+                parts.iter().all(|part| message.contains(part)),
+                "{}",
+                message
+            );
+        };
+        let err = Collector::<usize>::new().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        descriptive(err.to_string());
+        descriptive(TraceCollector::<usize>::new().err().unwrap().to_string());
+        let err = crate::ProfilerGuardBuilder::default()
+            .timer_kind(crate::TimerKind::Manual)
+            .build()
+            .err()
+            .unwrap();
+        descriptive(err.to_string());
+
+        let mut collector = Collector::in_memory();
+        for item in 0..(1 << 12) * 8 {
+            collector.add(item, 1).unwrap();
+        }
+        assert_eq!(collector.try_iter().unwrap().count(), (1 << 12) * 8);
+    }
+
     extern "C" {
         static mut __malloc_hook: Option<extern "C" fn(size: usize) -> *mut c_void>;
 
@@ -349,7 +2413,7 @@ mod tests {
     }
 
     thread_local! {
-        static FLAG: RefCell<bool> = RefCell::new(false);
+        static FLAG: RefCell<bool> = const { RefCell::new(false) };
     }
 
     extern "C" fn malloc_hook(size: usize) -> *mut c_void {
@@ -388,11 +2452,11 @@ mod tests {
         }
 
         FLAG.with(|flag| {
-            assert_eq!(*flag.borrow(), false);
+            assert!(!*flag.borrow());
         });
 
         collector.try_iter().unwrap().for_each(|entry| {
-            add_map(&mut real_map, &entry);
+            add_map(&mut real_map, entry);
         });
 
         for item in 0..(1 << 10) * 4 {
@@ -0,0 +1,175 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Reads the registers of the interrupted context from the `ucontext_t` given to the signal
+//! handler. The layout of `ucontext_t` is specific to every OS and architecture, supporting a new
+//! target starts by implementing `pc`, `sp` and `fp` for it here.
+
+#![cfg_attr(not(target_os = "linux"), allow(dead_code, unused_imports))]
+
+/// Whether the registers can be read on this target.
+pub(crate) const SUPPORTED: bool = cfg!(any(
+    all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+    all(
+        target_os = "macos",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+));
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod imp {
+    pub(crate) fn pc(uc: &libc::ucontext_t) -> usize {
+        uc.uc_mcontext.gregs[libc::REG_RIP as usize] as usize
+    }
+
+    pub(crate) fn sp(uc: &libc::ucontext_t) -> usize {
+        uc.uc_mcontext.gregs[libc::REG_RSP as usize] as usize
+    }
+
+    pub(crate) fn fp(uc: &libc::ucontext_t) -> usize {
+        uc.uc_mcontext.gregs[libc::REG_RBP as usize] as usize
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+mod imp {
+    pub(crate) fn pc(uc: &libc::ucontext_t) -> usize {
+        uc.uc_mcontext.pc as usize
+    }
+
+    pub(crate) fn sp(uc: &libc::ucontext_t) -> usize {
+        uc.uc_mcontext.sp as usize
+    }
+
+    // x29 is the frame pointer
+    pub(crate) fn fp(uc: &libc::ucontext_t) -> usize {
+        uc.uc_mcontext.regs[29] as usize
+    }
+}
+
+// `uc_mcontext` points to the machine context on macOS, it is valid in a signal handler.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+mod imp {
+    pub(crate) fn pc(uc: &libc::ucontext_t) -> usize {
+        unsafe { (*uc.uc_mcontext).__ss.__rip as usize }
+    }
+
+    pub(crate) fn sp(uc: &libc::ucontext_t) -> usize {
+        unsafe { (*uc.uc_mcontext).__ss.__rsp as usize }
+    }
+
+    pub(crate) fn fp(uc: &libc::ucontext_t) -> usize {
+        unsafe { (*uc.uc_mcontext).__ss.__rbp as usize }
+    }
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+mod imp {
+    pub(crate) fn pc(uc: &libc::ucontext_t) -> usize {
+        unsafe { (*uc.uc_mcontext).__ss.__pc as usize }
+    }
+
+    pub(crate) fn sp(uc: &libc::ucontext_t) -> usize {
+        unsafe { (*uc.uc_mcontext).__ss.__sp as usize }
+    }
+
+    pub(crate) fn fp(uc: &libc::ucontext_t) -> usize {
+        unsafe { (*uc.uc_mcontext).__ss.__fp as usize }
+    }
+}
+
+#[cfg(any(
+    all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+    all(
+        target_os = "macos",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+))]
+pub(crate) use imp::{fp, pc, sp};
+
+/// The program counter, stack pointer and frame pointer of the interrupted context, if they can
+/// be read.
+#[cfg(any(
+    all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+    all(
+        target_os = "macos",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+))]
+pub(crate) fn registers(ucontext: *mut libc::c_void) -> Option<[usize; 3]> {
+    if ucontext.is_null() {
+        return None;
+    }
+    let uc = unsafe { &*(ucontext as *const libc::ucontext_t) };
+
+    Some([pc(uc), sp(uc), fp(uc)])
+}
+
+#[cfg(not(any(
+    all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+    all(
+        target_os = "macos",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+)))]
+pub(crate) fn registers(_: *mut libc::c_void) -> Option<[usize; 3]> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn linux_x86_64() {
+        let mut uc: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        uc.uc_mcontext.gregs[libc::REG_RIP as usize] = 1;
+        uc.uc_mcontext.gregs[libc::REG_RSP as usize] = 2;
+        uc.uc_mcontext.gregs[libc::REG_RBP as usize] = 3;
+
+        assert_eq!((super::pc(&uc), super::sp(&uc), super::fp(&uc)), (1, 2, 3));
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    #[test]
+    fn linux_aarch64() {
+        let mut uc: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        uc.uc_mcontext.pc = 1;
+        uc.uc_mcontext.sp = 2;
+        uc.uc_mcontext.regs[29] = 3;
+
+        assert_eq!((super::pc(&uc), super::sp(&uc), super::fp(&uc)), (1, 2, 3));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn macos() {
+        let mut mcontext: libc::__darwin_mcontext64 = unsafe { std::mem::zeroed() };
+        #[cfg(target_arch = "x86_64")]
+        {
+            mcontext.__ss.__rip = 1;
+            mcontext.__ss.__rsp = 2;
+            mcontext.__ss.__rbp = 3;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            mcontext.__ss.__pc = 1;
+            mcontext.__ss.__sp = 2;
+            mcontext.__ss.__fp = 3;
+        }
+        let mut uc: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        uc.uc_mcontext = &mut mcontext;
+
+        assert_eq!((super::pc(&uc), super::sp(&uc), super::fp(&uc)), (1, 2, 3));
+    }
+}
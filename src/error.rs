@@ -12,6 +12,14 @@ pub enum Error {
     Running,
     #[error("stop running cpu profiler error")]
     NotRunning,
+    #[error("unwinder {0:?} is not supported on this platform")]
+    UnsupportedUnwinder(crate::Unwinder),
+    #[error("malformed input at line {0}: {1}")]
+    MalformedInput(usize, &'static str),
+    #[error("{0}")]
+    IncompleteStacks(String),
+    #[error("malformed pprof profile: {0}")]
+    MalformedProfile(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
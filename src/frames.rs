@@ -1,35 +1,79 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::os::raw::c_void;
-use std::path::PathBuf;
+use std::path::Path;
+use std::sync::Arc;
 
 use backtrace::Frame;
 use symbolic_demangle::demangle;
 
+use crate::profiler::ThreadState;
+use crate::task::task_name;
 use crate::{MAX_DEPTH, MAX_THREAD_NAME};
 
+/// A frame captured by an unwinder, which is only resolved to symbols when building a report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnresolvedFrame {
+    /// The instruction pointer of the frame, i.e. a return address for all but the leaf frame.
+    pub ip: usize,
+
+    /// The start address of the function containing `ip`, or `ip` itself when the unwinder can't
+    /// tell. Frames are compared by it, so samples anywhere in the same function are merged.
+    pub symbol_address: usize,
+}
+
+impl From<&Frame> for UnresolvedFrame {
+    fn from(frame: &Frame) -> Self {
+        UnresolvedFrame {
+            ip: frame.ip() as usize,
+            symbol_address: frame.symbol_address() as usize,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UnresolvedFramesSlice<'a> {
-    pub frames: &'a [Frame],
+    pub frames: &'a [UnresolvedFrame],
     pub thread_name: &'a [u8],
     pub thread_id: u64,
 }
 
 pub struct UnresolvedFrames {
-    pub frames: [Frame; MAX_DEPTH],
+    pub frames: [UnresolvedFrame; MAX_DEPTH],
     pub depth: usize,
     pub thread_name: [u8; MAX_THREAD_NAME],
     pub thread_name_length: usize,
     pub thread_id: u64,
+    /// The CPU the sample was taken on, if recorded, see `ProfilerGuardBuilder::record_cpu`.
+    pub cpu: Option<u32>,
+    /// The id of the task the sample was taken in, if any, see `instrument_task`.
+    pub task: Option<u32>,
+    /// The scheduler state of the thread when the sample was taken, if recorded, see
+    /// `ProfilerGuardBuilder::thread_states`.
+    pub state: Option<ThreadState>,
+    /// Whether the stack was deeper than `MAX_DEPTH`, its outermost frames being left out.
+    pub truncated: bool,
+    /// Whether the leaf frame is a return address too, the stack being captured by a call rather
+    /// than by interrupting the thread, e.g. by `ProfilerGuard::sample_now`. Every frame is then
+    /// resolved as a caller.
+    pub caller_leaf: bool,
 }
 
 impl Clone for UnresolvedFrames {
     fn clone(&self) -> Self {
         let slice = self.slice().clone();
-        Self::new(slice.frames, slice.thread_name, slice.thread_id)
+        Self {
+            cpu: self.cpu,
+            task: self.task,
+            state: self.state,
+            truncated: self.truncated,
+            caller_leaf: self.caller_leaf,
+            ..Self::new(slice.frames, slice.thread_name, slice.thread_id)
+        }
     }
 }
 
@@ -40,11 +84,12 @@ impl Debug for UnresolvedFrames {
 }
 
 impl UnresolvedFrames {
-    pub fn new(bt: &[Frame], tn: &[u8], thread_id: u64) -> Self {
-        let depth = bt.len();
-        let mut frames: [Frame; MAX_DEPTH] =
-            unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-        frames[0..depth].clone_from_slice(bt);
+    /// Creates the stack of the frames `bt`, from the leaf. A stack of more than `MAX_DEPTH`
+    /// frames keeps the innermost ones, and is marked as `truncated`.
+    pub fn new(bt: &[UnresolvedFrame], tn: &[u8], thread_id: u64) -> Self {
+        let depth = bt.len().min(MAX_DEPTH);
+        let mut frames = [UnresolvedFrame::default(); MAX_DEPTH];
+        frames[0..depth].copy_from_slice(&bt[0..depth]);
 
         let thread_name_length = tn.len();
         let mut thread_name = [0; MAX_THREAD_NAME];
@@ -56,10 +101,28 @@ impl UnresolvedFrames {
             thread_name,
             thread_name_length,
             thread_id,
+            cpu: None,
+            task: None,
+            state: None,
+            truncated: bt.len() > MAX_DEPTH,
+            caller_leaf: false,
+        }
+    }
+
+    /// Returns `true` if no frame was captured.
+    pub fn is_empty(&self) -> bool {
+        self.depth == 0
+    }
+
+    /// Maps the addresses of every frame with `rebase`, see `ReportBuilder::rebase`.
+    pub fn rebase<F: Fn(usize) -> usize>(&mut self, rebase: F) {
+        for frame in &mut self.frames[0..self.depth] {
+            frame.ip = rebase(frame.ip);
+            frame.symbol_address = rebase(frame.symbol_address);
         }
     }
 
-    fn slice(&self) -> UnresolvedFramesSlice {
+    fn slice(&self) -> UnresolvedFramesSlice<'_> {
         UnresolvedFramesSlice {
             frames: &self.frames[0..self.depth],
             thread_name: &self.thread_name[0..self.thread_name_length],
@@ -71,12 +134,18 @@ impl UnresolvedFrames {
 impl PartialEq for UnresolvedFrames {
     fn eq(&self, other: &Self) -> bool {
         let (frames1, frames2) = (self.slice().frames, other.slice().frames);
-        if self.thread_id != other.thread_id || frames1.len() != frames2.len() {
+        if self.thread_id != other.thread_id
+            || self.cpu != other.cpu
+            || self.task != other.task
+            || self.state != other.state
+            || self.truncated != other.truncated
+            || self.caller_leaf != other.caller_leaf
+            || frames1.len() != frames2.len()
+        {
             false
         } else {
             Iterator::zip(frames1.iter(), frames2.iter())
-                .map(|(s1, s2)| s1.symbol_address() == s2.symbol_address())
-                .all(|equal| equal)
+                .all(|(s1, s2)| s1.symbol_address == s2.symbol_address)
         }
     }
 }
@@ -88,26 +157,41 @@ impl Hash for UnresolvedFrames {
         self.slice()
             .frames
             .iter()
-            .for_each(|frame| frame.symbol_address().hash(state));
+            .for_each(|frame| frame.symbol_address.hash(state));
         self.thread_id.hash(state);
+        self.cpu.hash(state);
+        self.task.hash(state);
+        self.state.hash(state);
+        self.truncated.hash(state);
+        self.caller_leaf.hash(state);
     }
 }
 
 /// Symbol is a representation of a function symbol. It contains name and addr of it. If built with
 /// debug message, it can also provide line number and filename. The name in it is not demangled.
+///
+/// The name and filename are shared, so cloning a symbol doesn't copy them, and the symbols of a
+/// report built by `ReportBuilder` share a single copy of every distinct name and filename.
 #[derive(Debug, Clone)]
 pub struct Symbol {
     /// This name is raw name of a symbol (which hasn't been demangled).
-    pub name: Option<Vec<u8>>,
+    pub name: Option<Arc<[u8]>>,
 
-    /// The address of the function. It is not 100% trustworthy.
+    /// The address of the function. It is not 100% trustworthy: a resolved symbol gets the start
+    /// of the function as found in the unwind info, that of the outermost one if inlined, or else
+    /// what the debug info has, which may be a file offset.
     pub addr: Option<*mut c_void>,
 
+    /// The instruction pointer this symbol was resolved at, i.e. the sampled address in the
+    /// function, see `offset`. The symbols are compared by name only, so in a report merging the
+    /// samples of several addresses of a function it is the one of the first resolved.
+    pub ip: Option<*mut c_void>,
+
     /// Line number of this symbol. If compiled with debug message, you can get it.
     pub lineno: Option<u32>,
 
     /// Filename of this symbol. If compiled with debug message, you can get it.
-    pub filename: Option<PathBuf>,
+    pub filename: Option<Arc<Path>>,
 }
 
 impl Symbol {
@@ -119,11 +203,25 @@ impl Symbol {
         demangle(&String::from_utf8_lossy(self.raw_name())).into_owned()
     }
 
-    pub fn sys_name(&self) -> Cow<str> {
+    /// The crate of this symbol, i.e. the first segment of its demangled path, e.g. `serde` for
+    /// `serde::de::Deserialize::deserialize` or `<serde::Value as core::fmt::Debug>::fmt`. It is
+    /// `None` for a symbol without a path, e.g. a C function.
+    pub fn crate_name(&self) -> Option<String> {
+        let name = self.name();
+        let path = name.trim_start_matches('<');
+        let krate = &path[..path.find("::")?];
+        if !krate.is_empty() && krate.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            Some(krate.to_owned())
+        } else {
+            None
+        }
+    }
+
+    pub fn sys_name(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(self.raw_name())
     }
 
-    pub fn filename(&self) -> Cow<str> {
+    pub fn filename(&self) -> Cow<'_, str> {
         self.filename
             .as_ref()
             .map(|name| name.as_os_str().to_string_lossy())
@@ -133,6 +231,150 @@ impl Symbol {
     pub fn lineno(&self) -> u32 {
         self.lineno.unwrap_or(0)
     }
+
+    /// The offset of `ip` in the function starting at `addr`, e.g. to link a frame to its
+    /// instruction in a disassembly, if both are known.
+    pub fn offset(&self) -> Option<usize> {
+        let (ip, addr) = (self.ip? as usize, self.addr? as usize);
+        ip.checked_sub(addr)
+    }
+
+    /// A placeholder for an address which can't be resolved to any symbol. It is named
+    /// `<addr 0x...>` after the address.
+    pub fn unresolved(addr: *mut c_void) -> Self {
+        Symbol {
+            name: Some(format!("<addr {:#x}>", addr as usize).as_bytes().into()),
+            addr: Some(addr),
+            ip: Some(addr),
+            lineno: None,
+            filename: None,
+        }
+    }
+}
+
+// Strips the `::h0123456789abcdef` hash rustc appends to legacy symbol names, if any.
+fn strip_hash_suffix(name: &str) -> &str {
+    if let Some(index) = name.rfind("::h") {
+        let hash = &name[index + 3..];
+        if hash.len() == 16 && hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return &name[..index];
+        }
+    }
+
+    name
+}
+
+// Splits `text` at the `separator`s outside of any brackets, or returns `None` if its brackets
+// are unbalanced, e.g. in a C++ `operator<`.
+fn split_outside_brackets<'a>(text: &'a str, separator: &str) -> Option<Vec<&'a str>> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start, mut index) = (0_usize, 0, 0);
+    let bytes = text.as_bytes();
+    while index < bytes.len() {
+        match bytes[index] {
+            b'<' | b'(' | b'[' => depth += 1,
+            // the `>` of an arrow, e.g. in `fn() -> u8`, doesn't close anything
+            b'>' if index > 0 && bytes[index - 1] == b'-' => {}
+            b'>' | b')' | b']' => depth = depth.checked_sub(1)?,
+            _ if depth == 0 && text[index..].starts_with(separator) => {
+                parts.push(&text[start..index]);
+                index += separator.len();
+                start = index;
+                continue;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    if depth != 0 {
+        return None;
+    }
+    parts.push(&text[start..]);
+
+    Some(parts)
+}
+
+// Shortens the path `path` to its last `keep` segments, the closures in them not counted, see
+// `SymbolDisplay::Short`.
+fn short_path(path: &str, keep: usize) -> Option<String> {
+    let segments = split_outside_brackets(path, "::")?;
+    let mut first = segments.len();
+    let mut kept = 0;
+    while first > 0 && kept < keep {
+        first -= 1;
+        if !segments[first].starts_with("{{") {
+            kept += 1;
+        }
+    }
+    let short = segments[first..]
+        .iter()
+        .map(|segment| short_segment(segment))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(short.join("::"))
+}
+
+// The segment `segment` of a path without its generic arguments, or with the paths of its types
+// shortened to their last segment if it is qualified, e.g. `<a::B as c::D>`.
+fn short_segment(segment: &str) -> Option<String> {
+    if let Some(qualified) = segment
+        .strip_prefix('<')
+        .and_then(|segment| segment.strip_suffix('>'))
+    {
+        let types = split_outside_brackets(qualified, " as ")?
+            .into_iter()
+            .map(|path| short_path(path, 1))
+            .collect::<Option<Vec<_>>>()?;
+        return Some(format!("<{}>", types.join(" as ")));
+    }
+
+    let mut short = String::with_capacity(segment.len());
+    let (mut depth, mut previous) = (0_usize, ' ');
+    for c in segment.chars() {
+        match c {
+            '<' => depth += 1,
+            '>' if depth > 0 && previous != '-' => depth -= 1,
+            _ if depth == 0 => short.push(c),
+            _ => {}
+        }
+        previous = c;
+    }
+
+    Some(short)
+}
+
+impl Symbol {
+    /// Replaces the raw name with the demangled one, without any hash suffix, so that the symbols
+    /// of the monomorphizations of a generic function compare equal.
+    pub fn strip_hash_suffix(&mut self) {
+        let name = self.name();
+        self.name = Some(strip_hash_suffix(&name).as_bytes().into());
+    }
+
+    /// The demangled name shortened as described by `SymbolDisplay::Short`, e.g. `Vec::push` for
+    /// `alloc::vec::Vec<T>::push::h0123456789abcdef`. A name which can't be parsed is kept whole.
+    pub fn short_name(&self) -> String {
+        let name = self.name();
+        let name = strip_hash_suffix(&name);
+        short_path(name, 2).unwrap_or_else(|| name.to_owned())
+    }
+
+    /// Replaces the raw name with the short one, see `short_name`, so that the functions of the
+    /// same short name compare equal.
+    pub fn shorten_name(&mut self) {
+        self.name = Some(self.short_name().as_bytes().into());
+    }
+
+    /// A symbol which isn't backed by any address, e.g. a marker added to a report.
+    pub(crate) fn synthetic(name: &str) -> Self {
+        Symbol {
+            name: Some(name.as_bytes().into()),
+            addr: None,
+            ip: None,
+            lineno: None,
+            filename: None,
+        }
+    }
 }
 
 unsafe impl Send for Symbol {}
@@ -140,10 +382,11 @@ unsafe impl Send for Symbol {}
 impl From<&backtrace::Symbol> for Symbol {
     fn from(symbol: &backtrace::Symbol) -> Self {
         Symbol {
-            name: symbol.name().map(|name| name.as_bytes().to_vec()),
+            name: symbol.name().map(|name| name.as_bytes().into()),
             addr: symbol.addr(),
+            ip: None,
             lineno: symbol.lineno(),
-            filename: symbol.filename().map(|filename| filename.to_owned()),
+            filename: symbol.filename().map(Into::into),
         }
     }
 }
@@ -160,46 +403,213 @@ impl PartialEq for Symbol {
     }
 }
 
+impl Eq for Symbol {}
+
 impl Hash for Symbol {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.raw_name().hash(state)
     }
 }
 
+/// Resolves the symbols at `addr`, a return address if `caller`. A return address points after
+/// the call, maybe already at the next line or function, so like pprof and perf its previous byte
+/// is looked up instead, while the leaf's address is the interrupted instruction itself.
+///
+/// An address covering inlined functions resolves to several symbols, ordered from the innermost
+/// inlined callee to the outermost function, as reported by `backtrace`. An address without any
+/// symbol (e.g. missing debug info) resolves to a single placeholder named after the address, so
+/// the frame is still visible in the report.
+fn resolve_symbols(addr: *mut c_void, caller: bool) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+
+    // `backtrace::resolve` always looks up the byte before the address, taking it as a return one
+    let (lookup, resolved) = if caller {
+        ((addr as usize).saturating_sub(1), addr as usize)
+    } else {
+        (addr as usize, (addr as usize).wrapping_add(1))
+    };
+    let start = function_start(lookup as *mut c_void);
+    backtrace::resolve(resolved as *mut c_void, |symbol| {
+        let symbol = Symbol::from(symbol);
+        let symbol = Symbol {
+            addr: start.or(symbol.addr),
+            ip: Some(addr),
+            ..symbol
+        };
+        symbols.push(symbol);
+    });
+
+    if symbols.is_empty() {
+        symbols.push(Symbol::unresolved(addr));
+    }
+
+    symbols
+}
+
+// The start of the function containing `ip` in this process, from the unwind info.
+fn function_start(ip: *mut c_void) -> Option<*mut c_void> {
+    extern "C" {
+        fn _Unwind_FindEnclosingFunction(pc: *mut c_void) -> *mut c_void;
+    }
+
+    let start = unsafe { _Unwind_FindEnclosingFunction(ip) };
+    Some(start).filter(|start| !start.is_null())
+}
+
 /// A representation of a backtrace. `thread_name` and `thread_id` was got from `pthread_getname_np`
 /// and `pthread_self`. frames is a vector of symbols.
 #[derive(Clone, PartialEq, Hash)]
 pub struct Frames {
+    /// Frames from the leaf to the root. Each frame holds its symbols from the innermost inlined
+    /// function to the outermost one. This leaf-first order is the canonical one, use
+    /// `Frames::symbols` to get the symbols in a given order.
     pub frames: Vec<Vec<Symbol>>,
     pub thread_name: String,
     pub thread_id: u64,
+    /// The CPU the samples were taken on, if recorded, see `ProfilerGuardBuilder::record_cpu`.
+    pub cpu: Option<u32>,
+    /// The scheduler state of the thread when the samples were taken, if recorded, see
+    /// `ProfilerGuardBuilder::thread_states`.
+    pub state: Option<ThreadState>,
 }
 
 impl From<UnresolvedFrames> for Frames {
     fn from(frames: UnresolvedFrames) -> Self {
+        Self::resolve_with(frames, |ip, caller| {
+            resolve_symbols(ip as *mut c_void, caller)
+        })
+    }
+}
+
+/// Shares the names and filenames of the symbols of many `Frames`, so that every distinct one is
+/// stored once however many frames refer to it, and resolves every address once.
+#[derive(Default)]
+pub(crate) struct SymbolInterner {
+    names: HashSet<Arc<[u8]>>,
+    filenames: HashSet<Arc<Path>>,
+    // the interned symbols resolved at every address, as a return address or not
+    resolved: HashMap<(usize, bool), Vec<Symbol>>,
+}
+
+impl SymbolInterner {
+    /// Resolves `frames` like `Frames::from`, with the symbols of the addresses resolved before.
+    pub(crate) fn resolve(&mut self, frames: UnresolvedFrames) -> Frames {
+        self.resolve_by(frames, |ip, caller| {
+            resolve_symbols(ip as *mut c_void, caller)
+        })
+    }
+
+    /// Like `resolve`, with the addresses not resolved before resolved by `symbolizer` instead,
+    /// given whether they are return addresses.
+    pub(crate) fn resolve_by<S>(&mut self, frames: UnresolvedFrames, mut symbolizer: S) -> Frames
+    where
+        S: FnMut(usize, bool) -> Vec<Symbol>,
+    {
+        Frames::resolve_with(frames, |ip, caller| {
+            if let Some(symbols) = self.resolved.get(&(ip, caller)) {
+                return symbols.clone();
+            }
+            let mut symbols = symbolizer(ip, caller);
+            symbols.iter_mut().for_each(|symbol| self.intern(symbol));
+            self.resolved.insert((ip, caller), symbols.clone());
+
+            symbols
+        })
+    }
+
+    /// Replaces the name and filename of every symbol of `frames` by the interned ones, e.g.
+    /// after they were renamed.
+    pub(crate) fn intern_frames(&mut self, frames: &mut Frames) {
+        frames
+            .frames
+            .iter_mut()
+            .flatten()
+            .for_each(|symbol| self.intern(symbol));
+    }
+
+    fn intern(&mut self, symbol: &mut Symbol) {
+        if let Some(name) = &mut symbol.name {
+            *name = intern(&mut self.names, name);
+        }
+        if let Some(filename) = &mut symbol.filename {
+            *filename = intern(&mut self.filenames, filename);
+        }
+    }
+}
+
+fn intern<T: ?Sized + Eq + Hash>(interned: &mut HashSet<Arc<T>>, value: &Arc<T>) -> Arc<T> {
+    match interned.get(value) {
+        Some(value) => value.clone(),
+        None => {
+            interned.insert(value.clone());
+            value.clone()
+        }
+    }
+}
+
+/// The order of the symbols of a stack, see `Frames::symbols`. Formats disagree on it: pprof
+/// samples list their locations leaf-first, while folded stacks and flamegraphs are root-first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackOrder {
+    /// From the leaf, where the samples were taken, to the root. The order of `Frames::frames`.
+    LeafFirst,
+    /// From the root, e.g. `main`, to the leaf.
+    RootFirst,
+}
+
+/// How the names of the symbols of a report are rendered, see `ReportBuilder::symbol_display`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolDisplay {
+    /// The demangled names as they are, e.g. `alloc::vec::Vec<T>::push`.
+    Full,
+    /// The last two segments of the demangled paths, without their generic arguments nor hash
+    /// suffix, e.g. `Vec::push`. The types of a qualified segment keep their last segment, e.g.
+    /// `<Value as Debug>::fmt`, and closures aren't counted, e.g. `rt::lang_start::{{closure}}`.
+    Short,
+}
+
+/// Which end of a stack is kept when it is truncated by `Frames::truncate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepFrames {
+    /// Keep the frames starting from the leaf, where the samples were taken.
+    Leaf,
+    /// Keep the frames starting from the root, e.g. `main`.
+    Root,
+}
+
+impl Frames {
+    // Resolves the symbols of every frame address with `resolver`, given whether it is a return
+    // address, i.e. of a caller: the leaf is the first frame, or the one interrupted by the signal
+    // handler if it was captured too.
+    fn resolve_with<R>(frames: UnresolvedFrames, mut resolver: R) -> Self
+    where
+        R: FnMut(usize, bool) -> Vec<Symbol>,
+    {
         let mut fs = Vec::new();
 
         let mut frame_iter = frames.slice().frames.iter();
 
+        let mut caller = frames.caller_leaf;
         while let Some(frame) = frame_iter.next() {
-            let mut symbols = Vec::new();
-
-            backtrace::resolve_frame(frame, |symbol| {
-                let symbol = Symbol::from(symbol);
-                symbols.push(symbol);
-            });
+            let symbols = resolver(frame.ip, caller);
+            caller = true;
 
             if symbols
                 .iter()
                 .any(|symbol| symbol.name() == "perf_signal_handler")
             {
-                // ignore frame itself and its next one
+                // ignore frame itself and its next one, the interrupted frame follows
                 frame_iter.next();
+                caller = false;
                 continue;
             }
 
-            if !symbols.is_empty() {
-                fs.push(symbols);
+            fs.push(symbols);
+        }
+        // the task is the root of its stacks
+        if let Some(name) = frames.task.and_then(task_name) {
+            if !fs.is_empty() {
+                fs.push(vec![Symbol::synthetic(&name)]);
             }
         }
 
@@ -208,6 +618,140 @@ impl From<UnresolvedFrames> for Frames {
             thread_name: String::from_utf8_lossy(&frames.thread_name[0..frames.thread_name_length])
                 .into_owned(),
             thread_id: frames.thread_id,
+            cpu: frames.cpu,
+            state: frames.state,
+        }
+    }
+
+    /// Returns `true` if the stack doesn't contain any frame.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns the symbols of all the frames, inlined functions included, in the given order.
+    pub fn symbols(&self, order: StackOrder) -> Vec<&Symbol> {
+        let mut symbols: Vec<&Symbol> = self.frames.iter().flatten().collect();
+        if order == StackOrder::RootFirst {
+            symbols.reverse();
+        }
+
+        symbols
+    }
+
+    /// Strips the hash suffixes of all the symbols, see `Symbol::strip_hash_suffix`.
+    pub fn strip_hash_suffixes(&mut self) {
+        self.frames
+            .iter_mut()
+            .flatten()
+            .for_each(Symbol::strip_hash_suffix);
+    }
+
+    /// Shortens the names of all the symbols, see `Symbol::shorten_name`.
+    pub fn shorten_names(&mut self) {
+        self.frames
+            .iter_mut()
+            .flatten()
+            .for_each(Symbol::shorten_name);
+    }
+
+    /// Keeps only the symbols for which `keep` returns `true`, removing the frames left without
+    /// any, so that the callers and callees of the removed ones become adjacent.
+    pub fn retain_symbols<F: FnMut(&Symbol) -> bool>(&mut self, mut keep: F) {
+        for frame in self.frames.iter_mut() {
+            frame.retain(|symbol| keep(symbol));
+        }
+        self.frames.retain(|frame| !frame.is_empty());
+    }
+
+    /// Removes the frames above the outermost symbol whose name contains `name`, so that the
+    /// stack starts at it, see `Report::focus`. Returns whether there is such a symbol, the stack
+    /// is left as is otherwise.
+    pub fn focus(&mut self, name: &str) -> bool {
+        for (depth, frame) in self.frames.iter_mut().enumerate().rev() {
+            // the symbols of a frame go from the innermost inlined function to the outermost
+            if let Some(index) = frame
+                .iter()
+                .rposition(|symbol| symbol.name().contains(name))
+            {
+                frame.truncate(index + 1);
+                self.frames.truncate(depth + 1);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Removes the callees of the outermost symbol `matches` is true for, so that the stack ends
+    /// at it, see `Report::prune_from`. Returns whether there is such a symbol, the stack is left
+    /// as is otherwise.
+    pub fn prune_from<F: FnMut(&Symbol) -> bool>(&mut self, mut matches: F) -> bool {
+        for depth in (0..self.frames.len()).rev() {
+            if let Some(index) = self.frames[depth].iter().rposition(&mut matches) {
+                self.frames[depth].drain(..index);
+                self.frames.drain(..depth);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Adds a root frame named after the crate of the leaf symbol, or `[unknown]` if it has none,
+    /// so that a flamegraph first splits the samples by the crate they were taken in.
+    pub fn prepend_crate_frame(&mut self) {
+        let name = self
+            .frames
+            .first()
+            .and_then(|frame| frame.first())
+            .and_then(Symbol::crate_name)
+            .unwrap_or_else(|| "[unknown]".to_owned());
+        self.frames.push(vec![Symbol::synthetic(&name)]);
+    }
+
+    /// Collapses the immediate repeats of a frame, i.e. direct recursion, into a single frame
+    /// whose outermost symbol is suffixed with the repeat count, e.g. `fib [x3]`. Mutual
+    /// recursion is kept as is.
+    pub fn collapse_recursion(&mut self) {
+        let mut collapsed: Vec<(Vec<Symbol>, usize)> = Vec::with_capacity(self.frames.len());
+        for frame in self.frames.drain(..) {
+            match collapsed.last_mut() {
+                Some((last, repeats)) if *last == frame => *repeats += 1,
+                _ => collapsed.push((frame, 1)),
+            }
+        }
+
+        self.frames = collapsed
+            .into_iter()
+            .map(|(mut frame, repeats)| {
+                if repeats > 1 {
+                    if let Some(symbol) = frame.last_mut() {
+                        let name = format!("{} [x{}]", symbol.name(), repeats);
+                        symbol.name = Some(name.as_bytes().into());
+                    }
+                }
+                frame
+            })
+            .collect();
+    }
+
+    /// Truncates the stack to its `depth` frames at the `keep` end. The other frames, if any, are
+    /// replaced by a single `[truncated]` frame.
+    pub fn truncate(&mut self, depth: usize, keep: KeepFrames) {
+        if self.frames.len() <= depth {
+            return;
+        }
+
+        let marker = vec![Symbol::synthetic("[truncated]")];
+        match keep {
+            KeepFrames::Leaf => {
+                self.frames.truncate(depth);
+                self.frames.push(marker);
+            }
+            KeepFrames::Root => {
+                let removed = self.frames.len() - depth;
+                self.frames.splice(0..removed, std::iter::once(marker));
+            }
         }
     }
 }
@@ -235,11 +779,144 @@ impl Debug for Frames {
 mod tests {
     use super::*;
 
+    #[test]
+    fn rebase() {
+        const SLIDE: usize = 0x1000;
+        let captured: Vec<UnresolvedFrame> = [0x2010, 0x3020]
+            .iter()
+            .map(|&ip| UnresolvedFrame {
+                ip,
+                symbol_address: ip,
+            })
+            .collect();
+        let mut frames = UnresolvedFrames::new(&captured, b"main", 1);
+        frames.rebase(|address| address + SLIDE);
+
+        // the resolver only knows the rebased addresses
+        let mut looked_up = Vec::new();
+        let frames = Frames::resolve_with(frames, |ip, _| {
+            looked_up.push(ip);
+            let name = match ip {
+                0x3010 => "leaf",
+                0x4020 => "root",
+                _ => "unknown",
+            };
+            vec![Symbol::synthetic(name)]
+        });
+        assert_eq!(looked_up, [0x3010, 0x4020]);
+        let names: Vec<String> = frames
+            .symbols(StackOrder::LeafFirst)
+            .iter()
+            .map(|symbol| symbol.name())
+            .collect();
+        assert_eq!(names, ["leaf", "root"]);
+    }
+
+    #[inline(never)]
+    fn interned_leaf() -> usize {
+        line!() as usize
+    }
+
+    #[test]
+    fn symbol_interner() {
+        let leaf = interned_leaf as *const () as usize;
+        let frames = |ips: &[usize]| {
+            let captured: Vec<UnresolvedFrame> = ips
+                .iter()
+                .map(|&ip| UnresolvedFrame {
+                    ip,
+                    symbol_address: ip,
+                })
+                .collect();
+            UnresolvedFrames::new(&captured, b"main", 1)
+        };
+
+        let mut interner = SymbolInterner::default();
+        // two addresses within the same function resolve to the same name, stored once
+        let first = interner.resolve(frames(&[leaf + 1]));
+        let second = interner.resolve(frames(&[leaf + 2, leaf + 1]));
+        let name = |frames: &Frames, index: usize| frames.frames[index][0].name.clone().unwrap();
+        assert!(first.frames[0][0].name().contains("interned_leaf"));
+        assert!(Arc::ptr_eq(&name(&first, 0), &name(&second, 0)));
+        assert!(Arc::ptr_eq(&name(&first, 0), &name(&second, 1)));
+        assert_eq!(Frames::from(frames(&[leaf + 1])), first);
+
+        let mut renamed = [first.clone(), second.clone()];
+        for frames in renamed.iter_mut() {
+            frames.strip_hash_suffixes();
+            assert!(!Arc::ptr_eq(&name(frames, 0), &name(&first, 0)));
+            interner.intern_frames(frames);
+        }
+        assert!(Arc::ptr_eq(&name(&renamed[0], 0), &name(&renamed[1], 0)));
+    }
+
+    #[test]
+    fn caller_addresses() {
+        // the callers' return addresses are looked up one byte before, the leaf's isn't
+        let mut resolved = Vec::new();
+        let captured: Vec<UnresolvedFrame> = [0x1000, 0x2000, 0x3000]
+            .iter()
+            .map(|&ip| UnresolvedFrame {
+                ip,
+                symbol_address: ip,
+            })
+            .collect();
+        Frames::resolve_with(
+            UnresolvedFrames::new(&captured, b"main", 1),
+            |ip, caller| {
+                resolved.push((ip, caller));
+                Vec::new()
+            },
+        );
+        assert_eq!(resolved, [(0x1000, false), (0x2000, true), (0x3000, true)]);
+
+        // unless the leaf called the unwinder, e.g. in `sample_now`
+        resolved.clear();
+        let mut called = UnresolvedFrames::new(&captured, b"main", 1);
+        called.caller_leaf = true;
+        assert_ne!(called, UnresolvedFrames::new(&captured, b"main", 1));
+        assert!(called.clone().caller_leaf);
+        Frames::resolve_with(called, |ip, caller| {
+            resolved.push((ip, caller));
+            Vec::new()
+        });
+        assert_eq!(resolved, [(0x1000, true), (0x2000, true), (0x3000, true)]);
+
+        // right at the start of a function, a leaf is in it but a caller called from before it
+        let start = interned_leaf as *const () as usize;
+        let captured = [UnresolvedFrame {
+            ip: start,
+            symbol_address: start,
+        }; 2];
+        let frames = Frames::from(UnresolvedFrames::new(&captured, b"main", 1));
+        assert!(frames.frames[0][0].name().contains("interned_leaf"));
+        assert!(!frames.frames[1][0].name().contains("interned_leaf"));
+        assert_eq!(frames.frames[1][0].ip, Some(start as *mut c_void));
+    }
+
+    #[test]
+    fn symbol_addresses() {
+        let leaf = interned_leaf as *const () as usize;
+        let captured = [UnresolvedFrame {
+            ip: leaf + 4,
+            symbol_address: leaf,
+        }];
+        let frames = Frames::from(UnresolvedFrames::new(&captured, b"main", 1));
+
+        let symbol = &frames.frames[0][0];
+        assert!(symbol.name().contains("interned_leaf"));
+        assert_eq!(symbol.ip, Some((leaf + 4) as *mut c_void));
+        assert_eq!(symbol.addr, Some(leaf as *mut c_void));
+        assert_eq!(symbol.offset(), Some(4));
+        assert_eq!(Symbol::synthetic("task").offset(), None);
+    }
+
     #[test]
     fn demangle_rust() {
         let symbol = Symbol {
-            name: Some(b"_ZN3foo3barE".to_vec()),
+            name: Some(b"_ZN3foo3barE".as_ref().into()),
             addr: None,
+            ip: None,
             lineno: None,
             filename: None,
         };
@@ -247,6 +924,186 @@ mod tests {
         assert_eq!(&symbol.name(), "foo::bar")
     }
 
+    #[test]
+    fn crate_name() {
+        let crate_name = |name: &str| Symbol::synthetic(name).crate_name();
+        assert_eq!(
+            crate_name("serde::de::deserialize"),
+            Some("serde".to_owned())
+        );
+        assert_eq!(
+            crate_name("<tokio::runtime::Runtime as core::ops::Drop>::drop"),
+            Some("tokio".to_owned())
+        );
+        assert_eq!(crate_name("__libc_start_main"), None);
+        assert_eq!(crate_name("<addr 0x1234>"), None);
+    }
+
+    #[test]
+    fn collapse_recursion() {
+        let mut frames = Frames {
+            frames: ["fib", "fib", "fib", "a", "b", "a", "b", "main"]
+                .iter()
+                .map(|name| vec![Symbol::synthetic(name)])
+                .collect(),
+            thread_name: String::new(),
+            thread_id: 0,
+            cpu: None,
+            state: None,
+        };
+        frames.collapse_recursion();
+
+        let names: Vec<String> = frames.frames.iter().map(|frame| frame[0].name()).collect();
+        assert_eq!(names, ["fib [x3]", "a", "b", "a", "b", "main"]);
+    }
+
+    #[test]
+    fn truncate() {
+        let frames = Frames {
+            frames: (0..10)
+                .map(|i| vec![Symbol::synthetic(&i.to_string())])
+                .collect(),
+            thread_name: String::new(),
+            thread_id: 0,
+            cpu: None,
+            state: None,
+        };
+        let names = |frames: &Frames| -> Vec<String> {
+            frames.frames.iter().map(|frame| frame[0].name()).collect()
+        };
+
+        let mut leaf = frames.clone();
+        leaf.truncate(3, KeepFrames::Leaf);
+        assert_eq!(names(&leaf), vec!["0", "1", "2", "[truncated]"]);
+
+        let mut root = frames.clone();
+        root.truncate(3, KeepFrames::Root);
+        assert_eq!(names(&root), vec!["[truncated]", "7", "8", "9"]);
+
+        let mut untouched = frames.clone();
+        untouched.truncate(10, KeepFrames::Leaf);
+        assert_eq!(untouched.frames.len(), 10);
+    }
+
+    #[test]
+    fn strip_hash_suffixes() {
+        let monomorphization = |name: &[u8]| Frames {
+            frames: vec![
+                vec![Symbol {
+                    name: Some(name.into()),
+                    addr: None,
+                    ip: None,
+                    lineno: None,
+                    filename: None,
+                }],
+                vec![Symbol::synthetic("main::h0000000000000001")],
+            ],
+            thread_name: String::new(),
+            thread_id: 0,
+            cpu: None,
+            state: None,
+        };
+        let mut frames = vec![
+            monomorphization(b"_ZN5alloc3vec12Vec$LT$T$GT$4push17h0123456789abcdefE"),
+            monomorphization(b"_ZN5alloc3vec12Vec$LT$T$GT$4push17hfedcba9876543210E"),
+        ];
+        assert_ne!(frames[0], frames[1]);
+
+        frames.iter_mut().for_each(Frames::strip_hash_suffixes);
+        assert_eq!(frames[0], frames[1]);
+        assert_eq!(frames[0].frames[0][0].name(), "alloc::vec::Vec<T>::push");
+        assert_eq!(frames[0].frames[1][0].name(), "main");
+
+        let merged: std::collections::HashMap<_, _> =
+            frames.into_iter().map(|frames| (frames, 1)).collect();
+        assert_eq!(merged.len(), 1);
+    }
+
+    #[test]
+    fn truncated_stacks() {
+        let captured: Vec<UnresolvedFrame> = (1..=MAX_DEPTH + 8)
+            .map(|ip| UnresolvedFrame {
+                ip,
+                symbol_address: ip,
+            })
+            .collect();
+
+        let deep = UnresolvedFrames::new(&captured, b"main", 1);
+        assert!(deep.truncated);
+        assert_eq!(deep.depth, MAX_DEPTH);
+        assert_eq!(deep.frames[MAX_DEPTH - 1].ip, MAX_DEPTH);
+        assert!(deep.clone().truncated);
+
+        // the same frames, but the whole stack
+        let complete = UnresolvedFrames::new(&captured[..MAX_DEPTH], b"main", 1);
+        assert!(!complete.truncated);
+        assert_ne!(deep, complete);
+    }
+
+    #[test]
+    fn short_names() {
+        let short_name = |name: &str| Symbol::synthetic(name).short_name();
+        assert_eq!(short_name("alloc::vec::Vec<T,A>::push"), "Vec::push");
+        assert_eq!(
+            short_name("alloc::vec::Vec<T>::push::h0123456789abcdef"),
+            "Vec::push"
+        );
+        assert_eq!(
+            short_name("<serde_json::Value as core::fmt::Debug>::fmt"),
+            "<Value as Debug>::fmt"
+        );
+        assert_eq!(
+            short_name("<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop"),
+            "<Vec as Drop>::drop"
+        );
+        assert_eq!(
+            short_name("std::rt::lang_start::{{closure}}"),
+            "rt::lang_start::{{closure}}"
+        );
+        assert_eq!(
+            short_name("core::ops::function::FnOnce<fn() -> u8>::call_once"),
+            "FnOnce::call_once"
+        );
+        assert_eq!(
+            short_name("std::vector<int>::push_back(int const&)"),
+            "vector::push_back(int const&)"
+        );
+        assert_eq!(short_name("main"), "main");
+        assert_eq!(short_name("<addr 0x10>"), "<addr 0x10>");
+        assert_eq!(short_name("operator<"), "operator<");
+
+        let stack = |leaf: &str| Frames {
+            frames: vec![
+                vec![Symbol::synthetic(leaf)],
+                vec![Symbol::synthetic("app::main")],
+            ],
+            thread_name: String::new(),
+            thread_id: 0,
+            cpu: None,
+            state: None,
+        };
+        let mut frames = [
+            stack("alloc::vec::Vec<T>::push"),
+            stack("smallvec::inline::Vec<T>::push"),
+        ];
+        assert_eq!(frames[0].frames[0][0].name(), "alloc::vec::Vec<T>::push");
+        assert_ne!(frames[0], frames[1]);
+
+        frames.iter_mut().for_each(Frames::shorten_names);
+        assert_eq!(frames[0], frames[1]);
+        assert_eq!(frames[0].frames[0][0].name(), "Vec::push");
+        assert_eq!(frames[0].frames[1][0].name(), "app::main");
+    }
+
+    #[test]
+    fn unresolved_placeholder() {
+        let symbols = resolve_symbols(0x10 as *mut c_void, false);
+
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(&symbols[0].name(), "<addr 0x10>");
+        assert_eq!(symbols[0].addr, Some(0x10 as *mut c_void));
+    }
+
     #[test]
     fn demangle_cpp() {
         let name =
@@ -254,8 +1111,9 @@ mod tests {
                 .to_vec();
 
         let symbol = Symbol {
-            name: Some(name),
+            name: Some(name.into()),
             addr: None,
+            ip: None,
             lineno: None,
             filename: None,
         };
@@ -24,25 +24,76 @@
 /// Define the MAX supported stack depth. TODO: make this variable mutable.
 pub const MAX_DEPTH: usize = 32;
 
-/// Define the MAX supported thread name length. TODO: make this variable mutable.
+/// Define the MAX supported thread name length, including the terminating NUL. The OS may keep
+/// shorter names already, see `register_thread_name` to report the full ones.
 pub const MAX_THREAD_NAME: usize = 16;
 
 mod collector;
+mod context;
 mod error;
 mod frames;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod process;
 mod profiler;
 mod report;
+mod task;
 mod timer;
+mod unwinder;
 
-pub use self::collector::{Collector, StackHashCounter};
+pub use self::collector::{
+    Collector, CollectorBuilder, CollectorConfig, EvictionPolicy, HashedCollector,
+    LeastRecentlyUsed, MinCount, ResourceUsage, StackHashCounter, StackId, TraceCollector,
+};
 pub use self::error::{Error, Result};
-pub use self::frames::{Frames, Symbol};
-pub use self::profiler::ProfilerGuard;
-pub use self::report::{Report, ReportBuilder};
+pub use self::frames::{
+    Frames, KeepFrames, StackOrder, Symbol, SymbolDisplay, UnresolvedFrame, UnresolvedFrames,
+};
+#[cfg(feature = "flamegraph")]
+pub use self::profiler::profile_test;
+pub use self::profiler::{
+    register_thread_name, Profiler, ProfilerGuard, ProfilerGuardBuilder, ProfilerHandle,
+    ThreadState,
+};
+pub use self::report::{Report, ReportBuilder, ReportIter};
+#[cfg(feature = "tokio")]
+pub use self::task::spawn_task;
+pub use self::task::{instrument_task, InstrumentedTask};
+pub use self::timer::{IntervalStats, ReportTiming, TimerKind};
+pub use self::unwinder::Unwinder;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use self::process::ProcessProfiler;
+
+#[cfg(feature = "protobuf")]
+pub use self::report::WriteSummary;
 
 #[cfg(feature = "flamegraph")]
 pub use inferno::flamegraph;
 
+/// Profiles the block `$body`, e.g. in a `#[test]`, and writes its flamegraph to
+/// `target/pprof/<$name>.svg`, evaluating to the value of the block. See `profile_test`, which
+/// it calls with the block as a closure. **only available with `flamegraph` feature**
+///
+/// ```no_run
+/// # fn parse(input: &[u8]) -> usize { input.len() }
+/// // in a `#[test]`
+/// let parsed = pprof::profile_test!("parse_large_input", { parse(&[0; 1 << 20]) });
+/// assert_eq!(parsed, 1 << 20);
+/// ```
+#[cfg(feature = "flamegraph")]
+#[macro_export]
+macro_rules! profile_test {
+    ($name:expr, $body:block) => {
+        $crate::profile_test($name, || $body)
+    };
+}
+
 #[cfg(feature = "protobuf")]
 pub mod protos {
     pub use prost::Message;
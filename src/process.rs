@@ -0,0 +1,322 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_void};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::collector::CollectorBuilder;
+use crate::frames::UnresolvedFrame;
+use crate::profiler::Profiler;
+use crate::report::ReportBuilder;
+use crate::unwinder::trace_process;
+use crate::{Result, MAX_DEPTH};
+
+/// Samples the stacks of another process by its pid, e.g. a worker subprocess, without changing
+/// its code. Every sample stops each thread of the process for a moment with `ptrace` to read its
+/// registers, then walks its frame pointers, so the process has to be built with frame pointers,
+/// and this process has to be allowed to trace it, e.g. by being its parent.
+///
+/// The addresses are resolved against the executables and libraries mapped by both processes,
+/// e.g. when the process runs the same binary as this one, as they were mapped at the last
+/// sample. The others are left unresolved, as `<addr 0x0>`. Only supported on Linux x86_64 and
+/// aarch64.
+pub struct ProcessProfiler {
+    pid: libc::pid_t,
+    frequency: c_int,
+    profiler: RwLock<Profiler>,
+    // the contents of `/proc/<pid>/maps` at the last sample
+    maps: Mutex<String>,
+}
+
+impl ProcessProfiler {
+    /// Creates a profiler of the process `pid`, sampling at `frequency` once run.
+    pub fn new(pid: i32, frequency: c_int) -> Result<Self> {
+        let data = CollectorBuilder::default().build()?;
+
+        Ok(Self {
+            pid,
+            frequency,
+            profiler: RwLock::new(Profiler::new(frequency, data)),
+            maps: Mutex::new(String::new()),
+        })
+    }
+
+    /// Samples the process at the frequency for `duration`. It stops at the first failure of
+    /// `sample`, e.g. when the process exits.
+    pub fn run_for(&self, duration: Duration) -> Result<()> {
+        let period = Duration::from_secs(1) / self.frequency.max(1) as u32;
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            let sampled = Instant::now();
+            self.sample()?;
+            std::thread::sleep(period.saturating_sub(sampled.elapsed()));
+        }
+
+        Ok(())
+    }
+
+    /// Samples every thread of the process once, skipping those which exit meanwhile. It fails
+    /// if the process can't be traced, e.g. it exited or this process isn't allowed to.
+    pub fn sample(&self) -> Result<()> {
+        *self.maps.lock() = read_maps(&self.pid.to_string())?;
+        let tasks = std::fs::read_dir(format!("/proc/{}/task", self.pid))?;
+        let mut profiler = self.profiler.write();
+        for task in tasks {
+            let tid: libc::pid_t = match task?.file_name().to_str().and_then(|tid| tid.parse().ok())
+            {
+                Some(tid) => tid,
+                None => continue,
+            };
+
+            // one frame more tells a stack deeper than `MAX_DEPTH`
+            let mut frames = [UnresolvedFrame::default(); MAX_DEPTH + 1];
+            let depth = match capture(self.pid, tid, &mut frames) {
+                Ok(depth) => depth,
+                // the thread exited
+                Err(err) if err.raw_os_error() == Some(libc::ESRCH) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let comm = std::fs::read(format!("/proc/{}/task/{}/comm", self.pid, tid));
+            let mut name = comm.unwrap_or_default();
+            if name.last() == Some(&b'\n') {
+                name.pop();
+            }
+
+            profiler.sample(&frames[..depth], &name, tid as u64);
+        }
+
+        Ok(())
+    }
+
+    /// Creates a `ReportBuilder` of the samples taken so far, even once the process exited. Its
+    /// `rebase` is set to translate the addresses of the process into this one's.
+    pub fn report(&self) -> Result<ReportBuilder<'_>> {
+        let mappings = AddressMap::new(&self.maps.lock(), &read_maps("self")?);
+        let mut builder = ReportBuilder::new(&self.profiler);
+        builder.rebase(move |address| mappings.translate(address));
+
+        Ok(builder)
+    }
+}
+
+// Stops the thread `tid` of the process `pid` to capture its stack into `frames`, and returns
+// the number of captured frames.
+fn capture(
+    pid: libc::pid_t,
+    tid: libc::pid_t,
+    frames: &mut [UnresolvedFrame],
+) -> std::io::Result<usize> {
+    let ptrace = |request, data: usize| {
+        let ret = unsafe { libc::ptrace(request, tid, std::ptr::null_mut::<c_void>(), data) };
+        if ret == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+
+    ptrace(libc::PTRACE_SEIZE, 0)?;
+    let stopped = ptrace(libc::PTRACE_INTERRUPT, 0).and_then(|()| {
+        let mut status = 0;
+        if unsafe { libc::waitpid(tid, &mut status, libc::__WALL) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if !libc::WIFSTOPPED(status) {
+            return Err(std::io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        // a signal arriving before the interrupt stops the thread first, it is delivered when
+        // the thread is detached
+        let signal = if status >> 16 == 0 {
+            libc::WSTOPSIG(status)
+        } else {
+            0
+        };
+
+        Ok((registers(tid), signal))
+    });
+    let signal = stopped.as_ref().map(|(_, signal)| *signal).unwrap_or(0);
+    ptrace(libc::PTRACE_DETACH, signal as usize)?;
+
+    let (ip, fp, sp) = stopped?.0?;
+    let (depth, _) = trace_process(pid, ip, fp, sp, frames);
+
+    Ok(depth)
+}
+
+// The program counter, frame pointer and stack pointer of the stopped thread `tid`.
+#[cfg(target_arch = "x86_64")]
+fn registers(tid: libc::pid_t) -> std::io::Result<(usize, usize, usize)> {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    let regs_ptr = &mut regs as *mut libc::user_regs_struct as *mut c_void;
+    if unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETREGS,
+            tid,
+            std::ptr::null_mut::<c_void>(),
+            regs_ptr,
+        )
+    } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok((regs.rip as usize, regs.rbp as usize, regs.rsp as usize))
+}
+
+#[cfg(target_arch = "aarch64")]
+fn registers(tid: libc::pid_t) -> std::io::Result<(usize, usize, usize)> {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    let mut iovec = libc::iovec {
+        iov_base: &mut regs as *mut libc::user_regs_struct as *mut c_void,
+        iov_len: std::mem::size_of::<libc::user_regs_struct>(),
+    };
+    let iovec_ptr = &mut iovec as *mut libc::iovec as *mut c_void;
+    if unsafe { libc::ptrace(libc::PTRACE_GETREGSET, tid, libc::NT_PRSTATUS, iovec_ptr) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok((regs.pc as usize, regs.regs[29] as usize, regs.sp as usize))
+}
+
+// A file mapped at `start..end` from `offset`.
+struct Mapping {
+    start: usize,
+    end: usize,
+    offset: usize,
+}
+
+fn read_maps(pid: &str) -> std::io::Result<String> {
+    std::fs::read_to_string(format!("/proc/{}/maps", pid))
+}
+
+// Parses the file mappings of a process by path, from its `/proc/<pid>/maps`.
+fn parse_mappings(maps: &str) -> HashMap<String, Vec<Mapping>> {
+    let mut mappings: HashMap<String, Vec<Mapping>> = HashMap::new();
+    for line in maps.lines() {
+        // `start-end perms offset dev inode path`
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        let path = match fields.get(5).map(|path| path.trim_start()) {
+            Some(path) if path.starts_with('/') => path,
+            _ => continue,
+        };
+        let range = fields[0].split_once('-');
+        let hex = |value: &str| usize::from_str_radix(value, 16).ok();
+        if let (Some((start, end)), Some(offset)) = (range, hex(fields[2])) {
+            if let (Some(start), Some(end)) = (hex(start), hex(end)) {
+                mappings
+                    .entry(path.to_owned())
+                    .or_default()
+                    .push(Mapping { start, end, offset });
+            }
+        }
+    }
+
+    mappings
+}
+
+// Translates the addresses of a process into this one's, through the files both map.
+struct AddressMap {
+    target: Vec<(String, Mapping)>,
+    local: HashMap<String, Vec<Mapping>>,
+}
+
+impl AddressMap {
+    fn new(target_maps: &str, local_maps: &str) -> Self {
+        let target = parse_mappings(target_maps)
+            .into_iter()
+            .flat_map(|(path, mappings)| {
+                mappings
+                    .into_iter()
+                    .map(move |mapping| (path.clone(), mapping))
+            })
+            .collect();
+
+        Self {
+            target,
+            local: parse_mappings(local_maps),
+        }
+    }
+
+    // The address of this process mapping the same byte of the same file as `address` in the
+    // target, or 0 if there is none.
+    fn translate(&self, address: usize) -> usize {
+        let (path, target) = match self
+            .target
+            .iter()
+            .find(|(_, mapping)| mapping.start <= address && address < mapping.end)
+        {
+            Some(found) => found,
+            None => return 0,
+        };
+        let file_offset = address - target.start + target.offset;
+
+        self.local
+            .get(path)
+            .into_iter()
+            .flatten()
+            .find(|local| {
+                local.offset <= file_offset && file_offset - local.offset < local.end - local.start
+            })
+            .map(|local| local.start + file_offset - local.offset)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn busy_child_loop(duration: Duration) -> u64 {
+        let start = Instant::now();
+        let mut sum = 0_u64;
+        while start.elapsed() < duration {
+            for i in 0..10_000_u64 {
+                sum = unsafe { std::ptr::read_volatile(&sum) }.wrapping_add(i * i);
+            }
+        }
+
+        sum
+    }
+
+    #[test]
+    fn profile_child() {
+        // this runs itself again in a child process, which is then profiled by its pid
+        if std::env::var_os("PPROF_TEST_BUSY_CHILD").is_some() {
+            busy_child_loop(Duration::from_secs(10));
+            return;
+        }
+
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "process::tests::profile_child"])
+            .env("PPROF_TEST_BUSY_CHILD", "1")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        let profiler = ProcessProfiler::new(child.id() as i32, 99).unwrap();
+        let sampled = profiler.run_for(Duration::from_millis(500));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        sampled.unwrap();
+        assert!(profiler.sample().is_err());
+
+        let report = profiler.report().unwrap().build().unwrap();
+        assert!(!report.data.is_empty());
+        assert!(report.data.keys().all(|key| !key.frames.is_empty()));
+        let busy = report
+            .data
+            .iter()
+            .filter(|(key, _)| {
+                key.frames[0]
+                    .iter()
+                    .any(|symbol| symbol.name().contains("busy_child_loop"))
+            })
+            .map(|(_, count)| *count)
+            .sum::<isize>();
+        assert!(busy > 0, "{:?}", report);
+    }
+}
@@ -1,315 +1,3746 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::hash::BuildHasherDefault;
+#[cfg(any(feature = "flamegraph", feature = "protobuf"))]
+use std::io::Write;
+use std::marker::PhantomData;
 use std::os::raw::c_int;
+#[cfg(any(feature = "flamegraph", feature = "protobuf"))]
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use backtrace::Frame;
 use nix::sys::signal;
-use parking_lot::RwLock;
+use parking_lot::{Condvar, Mutex, RwLock};
 
-use crate::collector::Collector;
+use crate::collector::{
+    Collector, CollectorBuilder, CollectorConfig, EvictionPolicy, HashedCollector,
+};
+use crate::context;
 use crate::error::{Error, Result};
-use crate::frames::UnresolvedFrames;
+use crate::frames::{UnresolvedFrame, UnresolvedFrames};
 use crate::report::ReportBuilder;
-use crate::timer::Timer;
+use crate::task::current_task;
+use crate::timer::{
+    effective_frequency, IntervalStats, Intervals, ReportTiming, ThreadTimers, Timer, TimerKind,
+};
+use crate::unwinder::Unwinder;
 use crate::{MAX_DEPTH, MAX_THREAD_NAME};
 
 lazy_static::lazy_static! {
-    pub(crate) static ref PROFILER: RwLock<Result<Profiler>> = RwLock::new(Profiler::new());
+    static ref PROFILERS: RwLock<Registry> = RwLock::new(Registry::default());
 }
 
-pub struct Profiler {
-    pub(crate) data: Collector<UnresolvedFrames>,
-    sample_counter: i32,
+// The number of forks this process is the child of, counted in the child. The profilers started
+// before a fork are the parent's: their collector may share its temp file with the parent, so
+// the child never samples into them.
+static FORKS: AtomicUsize = AtomicUsize::new(0);
 
-    running: bool,
+thread_local! {
+    // the profiler a `ProfilerGuard::tick` of this thread is for, as the address of its lock, or
+    // 0, read by the signal handler
+    static MANUAL_TICK: Cell<usize> = const { Cell::new(0) };
 }
 
-/// RAII structure used to stop profiling when dropped. It is the only interface to access profiler.
-pub struct ProfilerGuard<'a> {
-    profiler: &'a RwLock<Result<Profiler>>,
-    timer: Option<Timer>,
+extern "C" fn forked_child() {
+    FORKS.fetch_add(1, Ordering::Relaxed);
 }
 
-fn trigger_lazy() {
-    let _ = backtrace::Backtrace::new();
-    let _ = PROFILER.read();
+/// All the running profilers. The profilers of a `TimerKind` share a single signal handler and a
+/// single process-wide timer, which runs at the highest frequency among them, or a single set of
+/// per-thread timers for those with `ProfilerGuardBuilder::per_thread_timers`.
+#[derive(Default)]
+struct Registry {
+    profilers: Vec<Registered>,
+    timers: [Option<Timer>; TimerKind::COUNT],
+    thread_timers: [Option<ThreadTimers>; TimerKind::COUNT],
+    // the disposition of `SIGUSR2` before the manual profilers took it over, restored once the
+    // last one stops
+    manual_previous: Option<signal::SigAction>,
 }
 
-impl ProfilerGuard<'_> {
-    /// Start profiling with given sample frequency.
-    pub fn new(frequency: c_int) -> Result<ProfilerGuard<'static>> {
-        trigger_lazy();
+// A stack captured by the signal handler: its frames, depth and whether the unwinder stopped at a
+// corrupt frame.
+type Captured = Option<([UnresolvedFrame; MAX_DEPTH + 1], usize, bool)>;
 
-        match PROFILER.write().as_mut() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
-            }
-            Ok(profiler) => match profiler.start() {
-                Ok(()) => Ok(ProfilerGuard::<'static> {
-                    profiler: &PROFILER,
-                    timer: Some(Timer::new(frequency)),
-                }),
-                Err(err) => Err(err),
-            },
+/// A running profiler, with what the signal handler needs when the profiler's lock is held, e.g.
+/// by the interrupted thread itself in the middle of an `add`.
+struct Registered {
+    profiler: Arc<RwLock<Profiler>>,
+    unwinder: Unwinder,
+    timer_kind: TimerKind,
+    per_thread_timers: bool,
+    record_cpu: bool,
+    drop_corrupt_stacks: bool,
+    // how long the signal handler may spin for the lock, in nanoseconds from its start
+    lock_deadline: u64,
+    // the samples taken while the lock was held, they are merged the next time it's acquired
+    deferred: Mutex<Vec<UnresolvedFrames>>,
+    // the samples which couldn't even be deferred, they are counted as dropped when merged
+    lost: AtomicUsize,
+    // `FORKS` when the profiler was started
+    forks: usize,
+    // see `Profiler::states`
+    states: Option<Arc<ThreadStates>>,
+}
+
+impl Registered {
+    // the number of samples which can be deferred, those beyond it are lost
+    const DEFERRED_CAPACITY: usize = 16;
+
+    fn new(profiler: Arc<RwLock<Profiler>>) -> Self {
+        let (
+            unwinder,
+            timer_kind,
+            per_thread_timers,
+            record_cpu,
+            drop_corrupt_stacks,
+            deadline,
+            forks,
+            states,
+        ) = {
+            let profiler = profiler.read();
+            (
+                profiler.unwinder,
+                profiler.timer_kind,
+                profiler.per_thread_timers,
+                profiler.record_cpu,
+                profiler.drop_corrupt_stacks,
+                profiler.lock_deadline,
+                profiler.forks,
+                profiler.states.clone(),
+            )
+        };
+        Self {
+            profiler,
+            unwinder,
+            timer_kind,
+            per_thread_timers,
+            record_cpu,
+            drop_corrupt_stacks,
+            lock_deadline: deadline,
+            deferred: Mutex::new(Vec::with_capacity(Self::DEFERRED_CAPACITY)),
+            lost: AtomicUsize::new(0),
+            forks,
+            states,
         }
     }
 
-    /// Generate a report
-    pub fn report(&self) -> ReportBuilder {
-        ReportBuilder::new(&self.profiler)
+    // Records the deferred samples into `profiler`, those it is due for, and counts the lost ones
+    // it was due for as dropped. The CPU time of their threads can't be read from here, so they
+    // weigh a single period. This function has to be AS-safe.
+    fn merge(
+        &self,
+        profiler: &mut Profiler,
+        deferred: &mut Vec<UnresolvedFrames>,
+        timer_frequency: c_int,
+    ) {
+        for frames in deferred.drain(..) {
+            if profiler.tick(timer_frequency) {
+                profiler.record(frames, 1);
+            }
+        }
+        for _ in 0..self.lost.swap(0, Ordering::Relaxed) {
+            if profiler.tick(timer_frequency) {
+                profiler.sample_counter += 1;
+                profiler.dropped_sample_counter += 1;
+            }
+        }
     }
-}
 
-impl<'a> Drop for ProfilerGuard<'a> {
-    fn drop(&mut self) {
-        drop(self.timer.take());
+    // Merges the deferred samples without waiting for the next one, blocking until the lock of the
+    // profiler is acquired.
+    fn flush(&self, timer_frequency: c_int) {
+        let mut profiler = self.profiler.write();
+        self.merge(&mut profiler, &mut self.deferred.lock(), timer_frequency);
+        profiler.flush_repeated();
+    }
+
+    // Samples the interrupted thread if the profiler is due, capturing its stack at most once per
+    // unwinder into `captured`. If the profiler's lock is still held at the deadline from `start`,
+    // the time the handler started, the sample is deferred instead, and whether it is due is
+    // decided when it is merged. This function has to be AS-safe.
+    fn sample(
+        &self,
+        start: u64,
+        timer_frequency: c_int,
+        ucontext: *mut libc::c_void,
+        thread_name: &[u8],
+        thread_id: u64,
+        captured: &mut [Captured; Unwinder::COUNT],
+    ) {
+        // a stack the unwinder stopped at a corrupt frame of is empty if such stacks are dropped,
+        // so that it is counted as dropped
+        fn capture(
+            captured: &mut Captured,
+            unwinder: Unwinder,
+            ucontext: *mut libc::c_void,
+            drop_corrupt_stacks: bool,
+        ) -> &[UnresolvedFrame] {
+            let (bt, depth, corrupt) = captured.get_or_insert_with(|| {
+                // one frame more tells a stack deeper than `MAX_DEPTH`
+                let mut bt = [UnresolvedFrame::default(); MAX_DEPTH + 1];
+                let (depth, corrupt) = unwinder.trace(ucontext, &mut bt);
+                (bt, depth, corrupt)
+            });
+            if *corrupt && drop_corrupt_stacks {
+                &[]
+            } else {
+                &bt[0..*depth]
+            }
+        }
+
+        let unwinder = self.unwinder;
+        let captured = &mut captured[unwinder as usize];
+        let drop_corrupt_stacks = self.drop_corrupt_stacks;
+
+        let locked = loop {
+            match self.profiler.try_write() {
+                Some(profiler) => break Some(profiler),
+                None if monotonic_time().saturating_sub(start) >= self.lock_deadline => break None,
+                None => std::hint::spin_loop(),
+            }
+        };
+        match locked {
+            Some(mut profiler) => {
+                if let Some(mut deferred) = self.deferred.try_lock() {
+                    self.merge(&mut profiler, &mut deferred, timer_frequency);
+                }
 
-        match self.profiler.write().as_mut() {
-            Err(_) => {}
-            Ok(profiler) => match profiler.stop() {
-                Ok(()) => {}
-                Err(err) => log::error!("error while stopping profiler {}", err),
+                if profiler.tick(timer_frequency) {
+                    if let Some(intervals) = profiler.intervals.as_mut() {
+                        intervals.record(start);
+                    }
+                    let start = thread_cpu_time();
+                    let registers = context::registers(ucontext);
+                    let task = current_task();
+                    if !profiler.sample_repeated(thread_id, registers, task) {
+                        let bt = capture(captured, unwinder, ucontext, drop_corrupt_stacks);
+                        profiler.sample_at(bt, thread_name, thread_id, registers, task);
+                    }
+                    profiler.overhead += thread_cpu_time().saturating_sub(start);
+                }
+            }
+            None => match self.deferred.try_lock() {
+                Some(mut deferred) if deferred.len() < Self::DEFERRED_CAPACITY => {
+                    let bt = capture(captured, unwinder, ucontext, drop_corrupt_stacks);
+                    let mut frames = UnresolvedFrames::new(bt, thread_name, thread_id);
+                    if self.record_cpu {
+                        frames.cpu = current_cpu();
+                    }
+                    frames.task = current_task();
+                    frames.state = thread_state(&self.states, thread_id);
+                    deferred.push(frames);
+                }
+                _ => {
+                    self.lost.fetch_add(1, Ordering::Relaxed);
+                }
             },
         }
     }
 }
 
-fn write_thread_name_fallback(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
-    let mut len = 0;
-    let mut base = 1;
+impl Registry {
+    // The frequency of the process-wide timer of `kind`, or of its per-thread timers.
+    fn frequency(&self, kind: TimerKind, per_thread: bool) -> c_int {
+        if per_thread {
+            self.thread_timers[kind as usize]
+                .as_ref()
+                .map(|timers| timers.frequency)
+                .unwrap_or(0)
+        } else {
+            self.timers[kind as usize]
+                .as_ref()
+                .map(|timer| timer.frequency)
+                .unwrap_or(0)
+        }
+    }
 
-    while current_thread as u128 > base && len < MAX_THREAD_NAME {
-        base *= 10;
-        len += 1;
+    fn has_profilers(&self, kind: TimerKind) -> bool {
+        self.profilers.iter().any(|item| item.timer_kind == kind)
     }
 
-    let mut index = 0;
-    while index < len && base > 1 {
-        base /= 10;
+    fn register(&mut self, profiler: Arc<RwLock<Profiler>>) -> Result<()> {
+        let registered = Registered::new(profiler);
+        let kind = registered.timer_kind;
+        if !self.has_profilers(kind) {
+            let previous = register_signal_handler(kind)?;
+            if kind == TimerKind::Manual {
+                self.manual_previous = Some(previous);
+            }
+        }
+        let per_thread = registered.per_thread_timers;
+        self.profilers.push(registered);
+        self.rearm(kind);
+        // the shared timers may predate some threads
+        if per_thread {
+            self.refresh_threads();
+        }
 
-        name[index] = match (48 + (current_thread as u128 / base) % 10).try_into() {
-            Ok(digit) => digit,
-            Err(_) => {
-                log::error!("fail to convert thread_id to string");
-                0
+        Ok(())
+    }
+
+    // Merges the deferred samples of `profiler`, see `ProfilerGuard::flush`.
+    fn flush(&self, profiler: &Arc<RwLock<Profiler>>) {
+        if let Some(item) = self
+            .profilers
+            .iter()
+            .find(|item| Arc::ptr_eq(&item.profiler, profiler))
+        {
+            item.flush(self.frequency(item.timer_kind, item.per_thread_timers));
+        }
+    }
+
+    fn unregister(&mut self, profiler: &Arc<RwLock<Profiler>>) -> Result<()> {
+        let kind = match self
+            .profilers
+            .iter()
+            .find(|item| Arc::ptr_eq(&item.profiler, profiler))
+        {
+            Some(item) => {
+                // the deferred samples would be lost with it
+                item.flush(self.frequency(item.timer_kind, item.per_thread_timers));
+                item.timer_kind
             }
+            None => return Ok(()),
         };
+        self.profilers
+            .retain(|item| !Arc::ptr_eq(&item.profiler, profiler));
+        self.rearm(kind);
+        if !self.has_profilers(kind) {
+            let previous = match kind {
+                TimerKind::Manual => self.manual_previous.take(),
+                _ => None,
+            };
+            unregister_signal_handler(kind, previous)?;
+        }
 
-        index += 1;
+        Ok(())
     }
-}
 
-#[cfg(not(any(target_os = "linux", target_os = "macos")))]
-fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
-    write_thread_name_fallback(current_thread, name);
-}
+    // Arms the timers of `kind` with the highest frequency among their profilers, or disarms
+    // them if there is no profiler of this kind left.
+    fn rearm(&mut self, kind: TimerKind) {
+        let frequency = |per_thread: bool| {
+            self.profilers
+                .iter()
+                .filter(|item| item.timer_kind == kind && item.per_thread_timers == per_thread)
+                .map(|item| item.profiler.read().frequency)
+                .max()
+                .unwrap_or(0)
+        };
+        let (process_frequency, thread_frequency) = (frequency(false), frequency(true));
 
-#[cfg(any(target_os = "linux", target_os = "macos"))]
-fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
-    let name_ptr = name as *mut [libc::c_char] as *mut libc::c_char;
-    let ret = unsafe { libc::pthread_getname_np(current_thread, name_ptr, MAX_THREAD_NAME) };
+        if process_frequency != self.frequency(kind, false) {
+            let timer = &mut self.timers[kind as usize];
+            drop(timer.take());
+            if process_frequency > 0 {
+                *timer = Some(Timer::new(kind, process_frequency));
+            }
+        }
+        if thread_frequency != self.frequency(kind, true) {
+            let timers = &mut self.thread_timers[kind as usize];
+            drop(timers.take());
+            if thread_frequency > 0 {
+                *timers = Some(ThreadTimers::new(kind, thread_frequency));
+            }
+        }
+    }
 
-    if ret != 0 {
-        write_thread_name_fallback(current_thread, name);
+    // Creates the per-thread timers of the threads started since they were created.
+    fn refresh_threads(&mut self) {
+        for timers in self.thread_timers.iter_mut().flatten() {
+            timers.refresh();
+        }
     }
 }
 
-#[no_mangle]
-extern "C" fn perf_signal_handler(_signal: c_int) {
-    if let Some(mut guard) = PROFILER.try_write() {
-        if let Ok(profiler) = guard.as_mut() {
-            let mut bt: [Frame; MAX_DEPTH] =
-                unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-            let mut index = 0;
+// The samples counted by the hash of their stacks, see `ProfilerGuardBuilder::hashed_stacks`.
+type HashedStacks = HashedCollector<UnresolvedFrames>;
 
-            unsafe {
-                backtrace::trace_unsynchronized(|frame| {
-                    if index < MAX_DEPTH {
-                        bt[index] = frame.clone();
-                        index += 1;
-                        true
-                    } else {
-                        false
-                    }
-                });
-            }
+// The samples, evicted from their buckets by the policy of the collector they were collected into,
+// see `ProfilerGuardBuilder::collector`.
+type Stacks = Collector<UnresolvedFrames, Box<dyn EvictionPolicy<UnresolvedFrames> + Send + Sync>>;
+
+/// The state of a running profiler, owned by its `ProfilerGuard`.
+pub struct Profiler {
+    pub(crate) data: Stacks,
+    // the samples, if counted by the hash of their stacks, `data` then staying empty
+    hashed: Option<HashedStacks>,
+    sample_counter: i32,
+    pub(crate) dropped_sample_counter: usize,
+    cpu_times: Option<ThreadTable<u64>>,
+    // the monotonic time of the first and last samples of every thread
+    lifetimes: Option<ThreadTable<(u64, u64)>>,
+    // the scheduler states of the sampled threads, polled off the signal handler
+    states: Option<Arc<ThreadStates>>,
+    // the last sample of every thread, counting its repeats until they are added to `data`
+    last_samples: Option<ThreadTable<LastSample>>,
+    unwinder: Unwinder,
+    timer_kind: TimerKind,
+    per_thread_timers: bool,
+    record_cpu: bool,
+    drop_corrupt_stacks: bool,
+    // see `ProfilerGuardBuilder::lock_deadline`, in nanoseconds
+    lock_deadline: u64,
+    // the intervals between the samples taken by the signal handler, if recorded
+    intervals: Option<Intervals>,
+    // `FORKS` when the profiler was created, it doesn't sample in a forked child
+    forks: usize,
+    // CPU time spent sampling, in nanoseconds
+    overhead: u64,
 
-            let current_thread = unsafe { libc::pthread_self() };
-            let mut name = [0_i8; MAX_THREAD_NAME];
-            let name_ptr = &mut name as *mut [libc::c_char] as *mut libc::c_char;
+    frequency: c_int,
+    // grows by `frequency` on every tick of the shared timer, a sample is taken each time it
+    // reaches the timer's frequency
+    credit: c_int,
+    start_time: SystemTime,
+    start_instant: Instant,
+    // when the profiler stopped itself, see `ProfilerGuardBuilder::duration`
+    stop_instant: Option<Instant>,
+}
+
+/// A value per thread, e.g. its CPU time at its last sample. It is allocated up front so that the
+/// signal handler doesn't need to, threads beyond its capacity are not tracked.
+struct ThreadTable<V> {
+    slots: Vec<Option<(u64, V)>>,
+}
+
+impl<V: Clone> ThreadTable<V> {
+    const CAPACITY: usize = 1024;
+
+    fn new() -> Self {
+        Self {
+            slots: vec![None; Self::CAPACITY],
+        }
+    }
+
+    // The index of the slot of `thread_id`, or of the free one it would be recorded in. This
+    // function has to be AS-safe.
+    fn slot(&self, thread_id: u64) -> Option<usize> {
+        let start = (thread_id.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize;
+        (0..Self::CAPACITY)
+            .map(|index| (start + index) % Self::CAPACITY)
+            .find(|index| match &self.slots[*index] {
+                Some((id, _)) => *id == thread_id,
+                None => true,
+            })
+    }
 
-            write_thread_name(current_thread, &mut name);
+    // Returns the value recorded for `thread_id`, or records `value` for it and returns `None`.
+    // This function has to be AS-safe.
+    fn get_or_insert(&mut self, thread_id: u64, value: V) -> Option<&mut V> {
+        let index = self.slot(thread_id)?;
 
-            let name = unsafe { std::ffi::CStr::from_ptr(name_ptr) };
-            profiler.sample(&bt[0..index], name.to_bytes(), current_thread as u64);
+        match &mut self.slots[index] {
+            Some((_, recorded)) => Some(recorded),
+            slot => {
+                *slot = Some((thread_id, value));
+                None
+            }
         }
     }
+
+    // Returns the value recorded for `thread_id`, if any. This function has to be AS-safe.
+    fn get_mut(&mut self, thread_id: u64) -> Option<&mut V> {
+        let index = self.slot(thread_id)?;
+        self.slots[index].as_mut().map(|(_, recorded)| recorded)
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.slots.iter_mut().flatten().map(|(_, value)| value)
+    }
 }
 
-impl Profiler {
-    fn new() -> Result<Self> {
-        Ok(Profiler {
-            data: Collector::new()?,
-            sample_counter: 0,
-            running: false,
-        })
+impl<V: Copy> ThreadTable<V> {
+    // Records `value` for `thread_id` and returns the previously recorded one. This function has
+    // to be AS-safe.
+    fn replace(&mut self, thread_id: u64, value: V) -> Option<V> {
+        self.get_or_insert(thread_id, value)
+            .map(|last| std::mem::replace(last, value))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u64, V)> + '_ {
+        self.slots.iter().flatten().copied()
     }
 }
 
-impl Profiler {
-    pub fn start(&mut self) -> Result<()> {
-        log::info!("starting cpu profiler");
-        if self.running {
-            Err(Error::Running)
-        } else {
-            self.register_signal_handler()?;
-            self.running = true;
+/// The last sample of a thread, see `ProfilerGuardBuilder::dedup_samples`.
+#[derive(Clone)]
+struct LastSample {
+    // the registers of the interrupted context, which a repeated sample has the same
+    registers: [usize; 3],
+    frames: UnresolvedFrames,
+    // the weight of the repeats not added to the collector yet
+    pending: isize,
+}
+
+/// The scheduler state of a sampled thread, see `ProfilerGuardBuilder::thread_states`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ThreadState {
+    /// Running or runnable, i.e. on CPU or waiting for one.
+    Running,
+    /// Sleeping interruptibly, e.g. waiting on a lock, a channel or a timer.
+    Sleeping,
+    /// Sleeping uninterruptibly, usually waiting on disk I/O.
+    Blocked,
+    /// Stopped, e.g. by a signal or a debugger.
+    Stopped,
+    /// The thread has exited.
+    Exited,
+}
 
-            Ok(())
+impl ThreadState {
+    // Parses the state field of `/proc/<pid>/task/<tid>/stat`.
+    fn from_stat(stat: &str) -> Option<ThreadState> {
+        // the name in parentheses may contain spaces and parentheses itself
+        let state = stat[stat.rfind(')')? + 1..].split_whitespace().next()?;
+        match state {
+            "R" => Some(ThreadState::Running),
+            "S" | "I" => Some(ThreadState::Sleeping),
+            "D" => Some(ThreadState::Blocked),
+            "T" | "t" => Some(ThreadState::Stopped),
+            "Z" | "X" | "x" => Some(ThreadState::Exited),
+            _ => None,
         }
     }
 
-    fn init(&mut self) -> Result<()> {
-        self.sample_counter = 0;
-        self.data = Collector::new()?;
-        self.running = false;
-
-        Ok(())
+    // Reads the state of the thread of kernel id `tid` of this process.
+    fn read(tid: i32) -> Option<ThreadState> {
+        match std::fs::read_to_string(format!("/proc/self/task/{}/stat", tid)) {
+            Ok(stat) => ThreadState::from_stat(&stat),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(ThreadState::Exited),
+            Err(_) => None,
+        }
     }
 
-    pub fn stop(&mut self) -> Result<()> {
-        log::info!("stopping cpu profiler");
-        if self.running {
-            self.unregister_signal_handler()?;
-            self.init()?;
+    const ALL: [ThreadState; 5] = [
+        ThreadState::Running,
+        ThreadState::Sleeping,
+        ThreadState::Blocked,
+        ThreadState::Stopped,
+        ThreadState::Exited,
+    ];
 
-            Ok(())
-        } else {
-            Err(Error::NotRunning)
+    /// The name of the state, e.g. the value of the `state` label of the pprof samples.
+    pub fn name(self) -> &'static str {
+        match self {
+            ThreadState::Running => "running",
+            ThreadState::Sleeping => "sleeping",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Stopped => "stopped",
+            ThreadState::Exited => "exited",
         }
     }
 
-    fn register_signal_handler(&self) -> Result<()> {
-        let handler = signal::SigHandler::Handler(perf_signal_handler);
-        unsafe { signal::signal(signal::SIGPROF, handler) }?;
+    /// The state of name `name`, see `name`.
+    pub fn from_name(name: &str) -> Option<ThreadState> {
+        Self::ALL.iter().copied().find(|state| state.name() == name)
+    }
 
-        Ok(())
+    // The state as stored in a `StateSlot`, 0 for none.
+    fn encode(state: Option<ThreadState>) -> u8 {
+        state.map_or(0, |state| state as u8 + 1)
     }
 
-    fn unregister_signal_handler(&self) -> Result<()> {
-        let handler = signal::SigHandler::SigIgn;
-        unsafe { signal::signal(signal::SIGPROF, handler) }?;
+    #[cfg(target_os = "linux")]
+    fn decode(encoded: u8) -> Option<ThreadState> {
+        Self::ALL.get((encoded as usize).checked_sub(1)?).copied()
+    }
+}
 
-        Ok(())
+// The scheduler state of the sampled threads, see `ProfilerGuardBuilder::thread_states`. `/proc`
+// can't be read by the signal handler, so it registers every thread it samples here, and labels
+// the sample with the last state a thread of its own polled. The slots are allocated up front,
+// the threads beyond them are not tracked.
+struct ThreadStates {
+    slots: Vec<StateSlot>,
+    // whether the guard was dropped, which stops the polling thread
+    stopped: Mutex<bool>,
+    stopping: Condvar,
+}
+
+#[derive(Default)]
+struct StateSlot {
+    // the pthread id of the thread, 0 while the slot is free
+    thread_id: AtomicU64,
+    // the kernel id of the thread, 0 until it is known
+    tid: AtomicI32,
+    // its last polled state, see `ThreadState::encode`
+    state: AtomicU8,
+}
+
+impl ThreadStates {
+    const CAPACITY: usize = 1024;
+
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            slots: std::iter::repeat_with(StateSlot::default)
+                .take(Self::CAPACITY)
+                .collect(),
+            stopped: Mutex::new(false),
+            stopping: Condvar::new(),
+        })
     }
 
-    // This function has to be AS-safe
-    pub fn sample(&mut self, backtrace: &[Frame], thread_name: &[u8], thread_id: u64) {
-        let frames = UnresolvedFrames::new(backtrace, thread_name, thread_id);
-        self.sample_counter += 1;
+    // Starts polling the states of the sampled threads every `period`, until `stop`.
+    fn poll_every(self: &Arc<Self>, period: Duration) {
+        let poller = self.clone();
+        std::thread::spawn(move || {
+            let mut stopped = poller.stopped.lock();
+            while !*stopped {
+                poller.poll();
+                poller.stopping.wait_for(&mut stopped, period);
+            }
+        });
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.stopping.notify_all();
+    }
 
-        if let Ok(()) = self.data.add(frames, 1) {}
+    // Reads the state of every registered thread which didn't exit.
+    fn poll(&self) {
+        for slot in &self.slots {
+            let tid = slot.tid.load(Ordering::Acquire);
+            let exited = ThreadState::encode(Some(ThreadState::Exited));
+            if tid > 0 && slot.state.load(Ordering::Relaxed) != exited {
+                let state = ThreadState::encode(ThreadState::read(tid));
+                slot.state.store(state, Ordering::Relaxed);
+                // the slot was taken over meanwhile by a new thread with the same pthread id
+                if slot.tid.load(Ordering::Acquire) != tid {
+                    slot.state.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    // Registers the calling thread, of pthread id `thread_id` and kernel id `tid`, and returns
+    // its last polled state, if it was polled yet. This function is AS-safe.
+    #[cfg(target_os = "linux")]
+    fn sampled(&self, thread_id: u64, tid: i32) -> Option<ThreadState> {
+        let start = thread_id as usize % self.slots.len();
+        for offset in 0..self.slots.len() {
+            let slot = &self.slots[(start + offset) % self.slots.len()];
+            let known = match slot.thread_id.compare_exchange(
+                0,
+                thread_id,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => thread_id,
+                Err(known) => known,
+            };
+            if known == thread_id {
+                // a new thread reusing the pthread id of an exited one has no state yet
+                if slot.tid.swap(tid, Ordering::AcqRel) != tid {
+                    slot.state.store(0, Ordering::Relaxed);
+                    return None;
+                }
+                return ThreadState::decode(slot.state.load(Ordering::Relaxed));
+            }
+        }
+
+        None
+    }
+
+    // The current state of every registered thread, read now.
+    fn current(&self) -> Vec<(u64, ThreadState)> {
+        self.slots
+            .iter()
+            .filter_map(|slot| {
+                let tid = slot.tid.load(Ordering::Acquire);
+                if tid <= 0 {
+                    return None;
+                }
+                let thread_id = slot.thread_id.load(Ordering::Acquire);
+                Some((thread_id, ThreadState::read(tid)?))
+            })
+            .collect()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cell::RefCell;
-    use std::ffi::c_void;
+// The last polled state of the calling thread, `thread_id`, if the states are recorded. This
+// function is AS-safe.
+fn thread_state(states: &Option<Arc<ThreadStates>>, thread_id: u64) -> Option<ThreadState> {
+    #[cfg(target_os = "linux")]
+    {
+        let states = states.as_ref()?;
+        states.sampled(thread_id, kernel_thread_id())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (states, thread_id);
+        None
+    }
+}
 
-    extern "C" {
-        static mut __malloc_hook: Option<extern "C" fn(size: usize) -> *mut c_void>;
+// Returns the kernel id of the calling thread, which is not its pthread id. This function is
+// AS-safe.
+#[cfg(target_os = "linux")]
+fn kernel_thread_id() -> i32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as i32 }
+}
 
-        fn malloc(size: usize) -> *mut c_void;
+// Returns the CPU the calling thread runs on. This function is AS-safe.
+#[cfg(target_os = "linux")]
+fn current_cpu() -> Option<u32> {
+    use std::convert::TryFrom;
+
+    u32::try_from(unsafe { libc::sched_getcpu() }).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_cpu() -> Option<u32> {
+    None
+}
+
+// Returns the CPU time consumed by the calling thread in nanoseconds. This function is AS-safe.
+fn thread_cpu_time() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// Adds `count` to `frames` in `hashed` if the profiler counts the hashes of the stacks, or else
+// in `data`. This function has to be AS-safe.
+fn add_sample(
+    data: &mut Stacks,
+    hashed: &mut Option<HashedStacks>,
+    frames: UnresolvedFrames,
+    count: isize,
+) {
+    let added = match hashed {
+        Some(hashed) => hashed.add(frames, count),
+        None => data.add(frames, count),
+    };
+    if let Ok(()) = added {}
+}
+
+// Returns the time of the monotonic clock, which `Instant` uses, in nanoseconds. This function is
+// AS-safe.
+fn monotonic_time() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// A builder of `ProfilerGuard`, to start profiling with custom options.
+pub struct ProfilerGuardBuilder {
+    frequency: c_int,
+    weighted_by_cpu_time: bool,
+    in_memory: bool,
+    unwinder: Unwinder,
+    collector: Option<Stacks>,
+    collector_config: CollectorConfig,
+    hashed_stacks: usize,
+    thread_lifetimes: bool,
+    thread_states: bool,
+    record_cpu: bool,
+    drop_corrupt_stacks: bool,
+    dedup_samples: bool,
+    timer_kind: TimerKind,
+    per_thread_timers: bool,
+    wall_time: bool,
+    lock_deadline: Duration,
+    record_intervals: bool,
+    duration: Option<Duration>,
+}
+
+impl Default for ProfilerGuardBuilder {
+    fn default() -> ProfilerGuardBuilder {
+        ProfilerGuardBuilder {
+            frequency: 99,
+            weighted_by_cpu_time: false,
+            in_memory: false,
+            unwinder: Unwinder::default(),
+            collector: None,
+            collector_config: CollectorConfig::default(),
+            hashed_stacks: 0,
+            thread_lifetimes: false,
+            thread_states: false,
+            record_cpu: false,
+            drop_corrupt_stacks: false,
+            dedup_samples: false,
+            timer_kind: TimerKind::default(),
+            per_thread_timers: false,
+            wall_time: false,
+            lock_deadline: Duration::from_secs(0),
+            record_intervals: false,
+            duration: None,
+        }
     }
+}
 
-    thread_local! {
-        static FLAG: RefCell<bool> = RefCell::new(false);
+impl ProfilerGuardBuilder {
+    /// Set the sample frequency.
+    pub fn frequency(self, frequency: c_int) -> Self {
+        Self { frequency, ..self }
     }
 
-    extern "C" fn malloc_hook(size: usize) -> *mut c_void {
-        unsafe {
-            __malloc_hook = None;
+    /// Weight every sample by the CPU time its thread consumed since its previous sample, in
+    /// periods, instead of counting it once. Ticks skipped or delayed by the timer are then still
+    /// accounted for. The first sample of a thread weighs one period.
+    pub fn weighted_by_cpu_time(self, weighted_by_cpu_time: bool) -> Self {
+        Self {
+            weighted_by_cpu_time,
+            ..self
         }
+    }
 
-        FLAG.with(|flag| {
-            flag.replace(true);
-        });
-        let p = unsafe { malloc(size) };
+    /// Keep every sample in memory instead of spilling them to a temp file, so profiling works
+    /// where no temp file can be created. Every entry evicted from the collector is then kept,
+    /// even one of a stack evicted before, so memory grows with the number of evictions. To
+    /// bound it, pass a collector built with `Collector::in_memory().with_max_spill_bytes(..)`
+    /// to `collector` instead.
+    pub fn in_memory(self, in_memory: bool) -> Self {
+        Self { in_memory, ..self }
+    }
 
-        unsafe {
-            __malloc_hook = Some(malloc_hook);
+    /// Set the unwinder capturing the stacks, `Unwinder::Backtrace` by default. Building fails
+    /// with `Error::UnsupportedUnwinder` if it isn't supported here.
+    pub fn unwinder(self, unwinder: Unwinder) -> Self {
+        Self { unwinder, ..self }
+    }
+
+    /// Collect the samples into `collector` instead of a new one, e.g. to start from entries
+    /// merged beforehand, to test an integration deterministically, or to evict the stacks with
+    /// another `EvictionPolicy`, set with `CollectorBuilder::policy`. `in_memory` is then
+    /// ignored.
+    pub fn collector<P>(self, collector: Collector<UnresolvedFrames, P>) -> Self
+    where
+        P: EvictionPolicy<UnresolvedFrames> + Send + Sync + 'static,
+    {
+        Self {
+            collector: Some(collector.boxed_policy()),
+            ..self
         }
+    }
 
-        p
+    /// Set the sizes of the collectors of the samples and the directory of their temp files, see
+    /// `CollectorConfig`, e.g. to give a long-running profiler more buckets. They are ignored with
+    /// `collector`.
+    pub fn with_collector_config(self, collector_config: CollectorConfig) -> Self {
+        Self {
+            collector_config,
+            ..self
+        }
     }
 
-    #[inline(never)]
-    fn is_prime_number(v: usize, prime_numbers: &[usize]) -> bool {
-        if v < 10000 {
-            let r = prime_numbers.binary_search(&v);
-            return r.is_ok();
+    /// Record the times of the first and last samples of every thread, see
+    /// `Report::thread_lifetimes`.
+    pub fn thread_lifetimes(self, thread_lifetimes: bool) -> Self {
+        Self {
+            thread_lifetimes,
+            ..self
         }
+    }
 
-        for n in prime_numbers {
-            if v % n == 0 {
-                return false;
-            }
+    /// Record the scheduler state of the sampled threads, running, sleeping or blocked, with every
+    /// sample, as `Frames::state` and the `state` label of the pprof samples, so that a report
+    /// tells on-CPU from off-CPU samples, and the state of every thread when the report is built,
+    /// see `Report::thread_states`. The state can't be read from the signal handler, so a thread
+    /// polls the states of the sampled threads from `/proc` as often as the samples are taken,
+    /// and every sample gets the last polled state of its thread. The CPU timer only samples
+    /// running threads, the off-CPU ones are sampled by `TimerKind::WallTime` with
+    /// `per_thread_timers`. It is only supported on Linux.
+    pub fn thread_states(self, thread_states: bool) -> Self {
+        Self {
+            thread_states,
+            ..self
         }
+    }
 
-        true
+    /// Record the CPU every sample is taken on, written as the `cpu` label of the pprof samples,
+    /// e.g. to check the affinity of threads. The stacks sampled on different CPUs are then told
+    /// apart, so the same stack may appear once per CPU in the report. It is only supported on
+    /// Linux.
+    pub fn record_cpu(self, record_cpu: bool) -> Self {
+        Self { record_cpu, ..self }
     }
 
-    #[inline(never)]
-    fn prepare_prime_numbers() -> Vec<usize> {
-        // bootstrap: Generate a prime table of 0..10000
-        let mut prime_number_table: [bool; 10000] = [true; 10000];
-        prime_number_table[0] = false;
-        prime_number_table[1] = false;
-        for i in 2..10000 {
-            if prime_number_table[i] {
-                let mut v = i * 2;
-                while v < 10000 {
-                    prime_number_table[v] = false;
-                    v += i;
-                }
-            }
+    /// Drop the samples whose stack the unwinder stopped at a corrupt frame of, e.g. a frame
+    /// pointer to unmapped memory, instead of keeping them truncated at that frame. They are then
+    /// counted in `Report::dropped_samples`. The frames are read without ever faulting, so a
+    /// corrupt stack doesn't crash the process either way. Only `Unwinder::FramePointer` detects
+    /// corrupt frames.
+    pub fn drop_corrupt_stacks(self, drop_corrupt_stacks: bool) -> Self {
+        Self {
+            drop_corrupt_stacks,
+            ..self
         }
-        let mut prime_numbers = vec![];
-        for i in 2..10000 {
-            if prime_number_table[i] {
-                prime_numbers.push(i);
-            }
+    }
+
+    /// Count the samples by the hash of their stacks, storing every distinct stack only once in a
+    /// side table with room for `capacity` stacks, see `HashedCollector`, instead of keeping
+    /// full stacks in the collector and its temp file. It takes less memory and disk when few
+    /// distinct stacks are evicted many times, e.g. in a long-running profile; the samples of
+    /// the stacks which don't fit near their hash, e.g. once the side table is nearly full, are
+    /// dropped. 0, the default, counts full stacks. It is ignored with `collector`.
+    pub fn hashed_stacks(self, capacity: usize) -> Self {
+        Self {
+            hashed_stacks: capacity,
+            ..self
         }
-        prime_numbers
     }
 
-    #[test]
-    fn malloc_free() {
-        trigger_lazy();
+    /// Count the samples of a thread interrupted at the same registers (program counter, stack
+    /// and frame pointers) as its previous sample as that sample again, without capturing the
+    /// stack, which makes sampling a thread spinning in a tight loop much cheaper. Such a stack
+    /// is very likely the same, but not certainly, e.g. if another caller reached the same
+    /// instruction at the same stack depth in between. The repeats are added to the collector
+    /// when the thread's stack changes and before a report is built, so counts stay exact. It
+    /// needs the registers of the interrupted context, on Linux and macOS x86_64 and aarch64.
+    pub fn dedup_samples(self, dedup_samples: bool) -> Self {
+        Self {
+            dedup_samples,
+            ..self
+        }
+    }
 
-        let prime_numbers = prepare_prime_numbers();
+    /// Set the time driving the sampling timer, `TimerKind::CpuTime` by default.
+    pub fn timer_kind(self, timer_kind: TimerKind) -> Self {
+        Self { timer_kind, ..self }
+    }
 
-        let mut _v = 0;
+    /// Drive the sampling with a timer per thread on Linux, on the CPU time of that thread and
+    /// signaling it, instead of a process-wide timer. The kernel sends the signals of the latter to
+    /// any running thread, so with several busy threads some may get most of the samples; a
+    /// thread gets the samples of its own CPU time with its own timer. The timers are created for
+    /// the threads running when profiling starts, those started later are only sampled after
+    /// `ProfilerGuard::refresh_threads`. It is ignored with `TimerKind::Manual` and on other
+    /// platforms, which then use the process-wide timer.
+    pub fn per_thread_timers(self, per_thread_timers: bool) -> Self {
+        Self {
+            per_thread_timers,
+            ..self
+        }
+    }
 
-        unsafe {
-            __malloc_hook = Some(malloc_hook);
+    /// Also sample the wall-clock time, with `TimerKind::WallTime`, into a second profiler of the
+    /// same options, so that a single run gives both a report of the CPU time and one of the
+    /// wall-clock time, see `ProfilerGuard::wall_report`. The time threads spend off-CPU, e.g.
+    /// sleeping or waiting for IO, only shows in the latter. The samples aren't weighted by CPU
+    /// time there, and a collector given with `collector` only holds the samples of the timer
+    /// kind. It is ignored with `TimerKind::WallTime` or `TimerKind::Manual` as the timer kind.
+    pub fn wall_time(self, wall_time: bool) -> Self {
+        Self { wall_time, ..self }
+    }
+
+    /// Let the signal handler spin for the lock of the profiler for up to `lock_deadline` from
+    /// the moment it is interrupted, e.g. while a report is being built or the interrupted thread
+    /// is itself in the middle of recording a sample, instead of trying once. Past the deadline,
+    /// the sample is deferred until the lock is free, and once the few deferred samples fill up,
+    /// it is dropped and counted in `Report::dropped_samples`, so the handler never blocks the
+    /// interrupted thread beyond the deadline. It is zero by default.
+    pub fn lock_deadline(self, lock_deadline: Duration) -> Self {
+        Self {
+            lock_deadline,
+            ..self
         }
-        for i in 2..50000 {
-            if is_prime_number(i, &prime_numbers) {
-                _v += 1;
-                perf_signal_handler(27);
-            }
+    }
+
+    /// Record the distribution of the intervals between consecutive samples, on the monotonic
+    /// clock, see `ProfilerGuard::interval_stats`, e.g. to check that the timer signals arrive
+    /// on time. Only the samples the signal handler takes right away count, not those it defers
+    /// while the profiler is locked.
+    pub fn record_intervals(self, record_intervals: bool) -> Self {
+        Self {
+            record_intervals,
+            ..self
         }
-        unsafe {
+    }
+
+    /// Stop profiling by itself once `duration` elapsed, e.g. to keep the guard of a long-lived
+    /// profiler around and poll whether it finished with `ProfilerGuard::is_finished`, or wait
+    /// for it with `ProfilerGuard::wait_finished`. The report then only covers `duration`. A
+    /// thread is spawned to stop it, which exits early if the guard is dropped first.
+    pub fn duration(self, duration: Duration) -> Self {
+        Self {
+            duration: Some(duration),
+            ..self
+        }
+    }
+
+    // A profiler with the options of this builder, driven by a timer of `kind`.
+    fn profiler(
+        &self,
+        kind: TimerKind,
+        (data, hashed): (Stacks, Option<HashedStacks>),
+        states: &Option<Arc<ThreadStates>>,
+    ) -> Profiler {
+        let mut profiler = Profiler::new(self.frequency, data);
+        profiler.hashed = hashed;
+        profiler.states = states.clone();
+        if self.weighted_by_cpu_time && kind != TimerKind::WallTime {
+            profiler.cpu_times = Some(ThreadTable::new());
+        }
+        if self.thread_lifetimes {
+            profiler.lifetimes = Some(ThreadTable::new());
+        }
+        if self.dedup_samples && context::SUPPORTED {
+            profiler.last_samples = Some(ThreadTable::new());
+        }
+        if self.record_intervals {
+            profiler.intervals = Some(Intervals::default());
+        }
+        profiler.unwinder = self.unwinder;
+        profiler.timer_kind = kind;
+        profiler.per_thread_timers =
+            self.per_thread_timers && kind != TimerKind::Manual && cfg!(target_os = "linux");
+        profiler.record_cpu = self.record_cpu;
+        profiler.drop_corrupt_stacks = self.drop_corrupt_stacks;
+        profiler.lock_deadline = self.lock_deadline.as_nanos().try_into().unwrap_or(u64::MAX);
+
+        profiler
+    }
+
+    /// Start profiling. It fails with an `Error::IoError` telling how to do without a temp file
+    /// if the one of the samples can't be created, e.g. in a sandbox without a temp directory.
+    pub fn build(mut self) -> Result<ProfilerGuard<'static>> {
+        if !self.unwinder.is_supported() {
+            return Err(Error::UnsupportedUnwinder(self.unwinder));
+        }
+        self.unwinder.warn_if_incomplete();
+        trigger_lazy();
+
+        let in_memory = self.in_memory;
+        let collector_config = &self.collector_config;
+        let hashed_stacks = self.hashed_stacks;
+        let new_collector = || -> Result<(Stacks, Option<HashedStacks>)> {
+            let error = |err: std::io::Error| {
+                log::error!("Error in creating profiler: {}", err);
+                Error::from(err)
+            };
+            let builder = CollectorBuilder::default()
+                .in_memory(in_memory)
+                .config(collector_config.clone());
+            if hashed_stacks == 0 {
+                return Ok((builder.build().map_err(error)?.boxed_policy(), None));
+            }
+
+            // the samples are counted by their ids, the collector of full stacks stays empty
+            let empty = CollectorConfig {
+                buckets: 1,
+                associativity: 1,
+                buffer_len: 1,
+                spill_dir: None,
+            };
+            let data = CollectorBuilder::default().in_memory(true).config(empty);
+            let ids = builder.build().map_err(error)?;
+            let hashed =
+                HashedCollector::with_capacity(hashed_stacks, ids, BuildHasherDefault::default());
+            Ok((data.build().map_err(error)?.boxed_policy(), Some(hashed)))
+        };
+        let data = match self.collector.take() {
+            Some(collector) => (collector, None),
+            None => new_collector()?,
+        };
+        let wall_time = self.wall_time
+            && self.timer_kind != TimerKind::WallTime
+            && self.timer_kind != TimerKind::Manual;
+        let wall_data = if wall_time {
+            Some(new_collector()?)
+        } else {
+            None
+        };
+
+        let effective_frequency = effective_frequency(self.frequency);
+        if effective_frequency < self.frequency {
+            log::warn!(
+                "sampling frequency {} exceeds the timer resolution, samples will be taken at about {}",
+                self.frequency,
+                effective_frequency
+            );
+        }
+
+        let states = if self.thread_states && cfg!(target_os = "linux") {
+            Some(ThreadStates::new())
+        } else {
+            None
+        };
+
+        log::info!("starting cpu profiler");
+        let profiler = self.profiler(self.timer_kind, data, &states);
+        let forks = profiler.forks;
+        let profiler = Arc::new(RwLock::new(profiler));
+        PROFILERS.write().register(profiler.clone())?;
+        let wall_profiler = match wall_data {
+            Some(data) => {
+                let wall_profiler = self.profiler(TimerKind::WallTime, data, &states);
+                let wall_profiler = Arc::new(RwLock::new(wall_profiler));
+                let mut registry = PROFILERS.write();
+                if let Err(err) = registry.register(wall_profiler.clone()) {
+                    registry.unregister(&profiler)?;
+                    return Err(err);
+                }
+                Some(wall_profiler)
+            }
+            None => None,
+        };
+        let auto_stop = self.duration.map(|duration| {
+            let profilers = std::iter::once(profiler.clone()).chain(wall_profiler.clone());
+            AutoStop::spawn(profilers.collect(), duration)
+        });
+        if let Some(states) = &states {
+            // as often as the samples are taken
+            let period = Duration::from_secs(1) / effective_frequency.max(1) as u32;
+            states.poll_every(period.max(Duration::from_millis(1)));
+        }
+
+        Ok(ProfilerGuard::<'static> {
+            profiler,
+            wall_profiler,
+            effective_frequency,
+            auto_stop,
+            states,
+            forks,
+            _lifetime: PhantomData,
+        })
+    }
+}
+
+/// RAII structure used to stop profiling when dropped. It is the only interface to access profiler.
+///
+/// Several guards can be alive at the same time, each of them collecting into its own profiler at
+/// its own frequency.
+///
+/// Profiling doesn't continue in a child forked meanwhile: its timers aren't inherited, and the
+/// profilers inherited from the parent never sample in it, neither from a timer nor from
+/// `sample_now`. The child shouldn't build a report of them either, since their collector may
+/// share a temp file, and its offset, with the parent's. A child profiles itself with a guard
+/// of its own.
+pub struct ProfilerGuard<'a> {
+    profiler: Arc<RwLock<Profiler>>,
+    // the wall-clock profiler, see `ProfilerGuardBuilder::wall_time`
+    wall_profiler: Option<Arc<RwLock<Profiler>>>,
+    effective_frequency: c_int,
+    auto_stop: Option<Arc<AutoStop>>,
+    // polled until the guard is dropped, see `ProfilerGuardBuilder::thread_states`
+    states: Option<Arc<ThreadStates>>,
+    // the forks when the profiler was built, so that `sample_now` tells a forked child without
+    // taking the lock of the profiler, which may be held forever there
+    forks: usize,
+    _lifetime: PhantomData<&'a ()>,
+}
+
+// Stops a profiler once its duration elapsed, see `ProfilerGuardBuilder::duration`.
+#[derive(Default)]
+struct AutoStop {
+    // whether the profiler stopped, either by itself or by dropping its guard
+    stopped: Mutex<bool>,
+    finished: Condvar,
+}
+
+impl AutoStop {
+    fn spawn(profilers: Vec<Arc<RwLock<Profiler>>>, duration: Duration) -> Arc<Self> {
+        let auto_stop = Arc::new(Self::default());
+        let deadline = Instant::now() + duration;
+        let stopper = auto_stop.clone();
+        std::thread::spawn(move || {
+            let mut stopped = stopper.stopped.lock();
+            while !*stopped {
+                if stopper
+                    .finished
+                    .wait_until(&mut stopped, deadline)
+                    .timed_out()
+                {
+                    log::info!("stopping cpu profiler after {:?}", duration);
+                    for profiler in profilers.iter() {
+                        if let Err(err) = PROFILERS.write().unregister(profiler) {
+                            log::error!("error while stopping profiler {}", err);
+                        }
+                        profiler.write().stop_instant = Some(Instant::now());
+                    }
+                    *stopped = true;
+                    stopper.finished.notify_all();
+                }
+            }
+        });
+
+        auto_stop
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.finished.notify_all();
+    }
+}
+
+fn trigger_lazy() {
+    static AT_FORK: std::sync::Once = std::sync::Once::new();
+
+    let _ = backtrace::Backtrace::new();
+    lazy_static::initialize(&PROFILERS);
+    AT_FORK.call_once(|| {
+        let ret = unsafe { libc::pthread_atfork(None, None, Some(forked_child)) };
+        if ret != 0 {
+            log::warn!("fail to register the fork handler: {}", ret);
+        }
+    });
+}
+
+impl ProfilerGuard<'_> {
+    /// Start profiling with given sample frequency.
+    pub fn new(frequency: c_int) -> Result<ProfilerGuard<'static>> {
+        ProfilerGuardBuilder::default().frequency(frequency).build()
+    }
+
+    /// The frequency samples are actually taken at, which is lower than the requested one when
+    /// the timer can't fire that often, e.g. over the kernel tick rate. The report still counts
+    /// samples at the requested frequency.
+    pub fn effective_frequency(&self) -> c_int {
+        self.effective_frequency
+    }
+
+    /// Whether the profiler stopped by itself once its `ProfilerGuardBuilder::duration` elapsed.
+    /// It is always false without a duration.
+    pub fn is_finished(&self) -> bool {
+        self.auto_stop
+            .as_ref()
+            .map_or(false, |auto_stop| *auto_stop.stopped.lock())
+    }
+
+    /// Blocks until the profiler stops by itself, see `is_finished`, but no longer than
+    /// `timeout`. Returns whether it stopped.
+    pub fn wait_finished(&self, timeout: Duration) -> bool {
+        let auto_stop = match &self.auto_stop {
+            Some(auto_stop) => auto_stop,
+            None => return false,
+        };
+        let deadline = Instant::now() + timeout;
+        let mut stopped = auto_stop.stopped.lock();
+        while !*stopped {
+            if auto_stop
+                .finished
+                .wait_until(&mut stopped, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+
+        *stopped
+    }
+
+    /// Samples the calling thread now into this guard's profiler, not the other ones, if it was
+    /// built with `TimerKind::Manual`, before returning. It does nothing with the other timer
+    /// kinds.
+    pub fn tick(&self) {
+        let kind = self.profiler.read().timer_kind;
+        if kind == TimerKind::Manual {
+            // a signal raised by a thread is delivered to it before `raise` returns, so the
+            // handler samples this guard's profiler only, not those of the other manual guards
+            MANUAL_TICK.with(|ticked| ticked.set(Arc::as_ptr(&self.profiler) as usize));
+            if let Err(err) = signal::raise(kind.signal()) {
+                log::error!("fail to tick the manual timer: {}", err);
+            }
+            MANUAL_TICK.with(|ticked| ticked.set(0));
+        }
+    }
+
+    /// The distribution of the intervals between the samples taken so far, if they are recorded,
+    /// see `ProfilerGuardBuilder::record_intervals`.
+    pub fn interval_stats(&self) -> Option<IntervalStats> {
+        self.profiler
+            .read()
+            .intervals
+            .as_ref()
+            .map(Intervals::stats)
+    }
+
+    /// Samples the stack of the calling thread now, counted `weight` times, whatever the timer
+    /// kind, e.g. on every slow request. Unlike `tick`, no signal is raised: the stack is
+    /// captured here with `Unwinder::Backtrace`, from the caller of this function, and recorded
+    /// like a sample of the timer, except its weight isn't the CPU time of the thread.
+    #[inline(never)]
+    pub fn sample_now(&self, weight: isize) {
+        if self.forks != FORKS.load(Ordering::Relaxed) {
+            return;
+        }
+        // the frames up to this function's own are the unwinder's, they are skipped if found
+        let this = Self::sample_now as *const () as usize;
+        let mut bt = [UnresolvedFrame::default(); 2 * MAX_DEPTH];
+        let mut depth = 0;
+        backtrace::trace(|frame| {
+            bt[depth] = UnresolvedFrame::from(frame);
+            depth += 1;
+            depth < bt.len()
+        });
+        let bt = &bt[..depth];
+        let skipped = bt.iter().position(|frame| frame.symbol_address == this);
+        let bt = &bt[skipped.map_or(0, |index| index + 1)..];
+        let bt = &bt[..bt.len().min(MAX_DEPTH + 1)];
+
+        let current_thread = unsafe { libc::pthread_self() };
+        let mut name = [0 as libc::c_char; MAX_THREAD_NAME];
+        write_thread_name(current_thread, &mut name);
+        let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+        let mut profiler = self.profiler.write();
+        profiler.sample_weighted(bt, name.to_bytes(), current_thread as u64, weight);
+    }
+
+    /// Creates the timers of the threads started since profiling started, and of those started
+    /// since the last call, if the profiler was built with `per_thread_timers`. The profilers
+    /// with per-thread timers of the same kind share them, so this times new threads for all of
+    /// them.
+    pub fn refresh_threads(&self) {
+        if self.profiler.read().per_thread_timers {
+            PROFILERS.write().refresh_threads();
+        }
+    }
+
+    /// A handle to query the statistics of this profiler, e.g. from another thread.
+    pub fn handle(&self) -> ProfilerHandle {
+        ProfilerHandle {
+            profiler: self.profiler.clone(),
+        }
+    }
+
+    /// The CPU time spent by the signal handler to take the samples so far, i.e. the cost of
+    /// profiling. A stack captured for several profilers is only accounted to the first one.
+    pub fn overhead(&self) -> Duration {
+        Duration::from_nanos(self.profiler.read().overhead)
+    }
+
+    /// Records the samples which were deferred because they were taken while the profiler was
+    /// busy, e.g. building a report, instead of waiting for the next sample to record them. It
+    /// blocks until they are recorded. The report of a stopped profiler has them all already.
+    pub fn flush(&self) {
+        let registry = PROFILERS.read();
+        for profiler in std::iter::once(&self.profiler).chain(&self.wall_profiler) {
+            registry.flush(profiler);
+        }
+    }
+
+    /// Generate a report
+    pub fn report(&self) -> ReportBuilder<'_> {
+        ReportBuilder::new(&self.profiler)
+    }
+
+    /// Generate a report of the wall-clock samples, if the profiler was built with
+    /// `ProfilerGuardBuilder::wall_time`, whose timing is the one of `report`.
+    pub fn wall_report(&self) -> Option<ReportBuilder<'_>> {
+        self.wall_profiler.as_deref().map(ReportBuilder::new)
+    }
+
+    /// Builds a report and writes its flamegraph to `path`, e.g. from a panic hook. The file is
+    /// written next to `path` first and then renamed, so an interrupted write never leaves a
+    /// truncated flamegraph at `path`. **only available with `flamegraph` feature**
+    #[cfg(feature = "flamegraph")]
+    pub fn dump_flamegraph<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.flush();
+        let report = self.report().build()?;
+        write_atomically(path.as_ref(), |file| report.flamegraph(file))
+    }
+
+    /// Builds a report and writes it in pprof format to `path`, like `dump_flamegraph`. **only
+    /// available with `protobuf` feature**
+    #[cfg(feature = "protobuf")]
+    pub fn dump_pprof<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.flush();
+        let report = self.report().build()?;
+        write_atomically(path.as_ref(), |file| report.write_pprof(file).map(|_| ()))
+    }
+
+    /// Stop profiling and generate a report. The collected samples are moved into the report
+    /// instead of being cloned, which suits one-shot profiling. The wall-clock samples, if any,
+    /// are dropped with the guard.
+    pub fn into_report(self) -> ReportBuilder<'static> {
+        let profiler = self.profiler.clone();
+        drop(self);
+
+        ReportBuilder::owned(profiler)
+    }
+}
+
+// Writes a temp file in the directory of `path` with `write`, then renames it to `path`.
+#[cfg(any(feature = "flamegraph", feature = "protobuf"))]
+fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut std::io::BufWriter<&mut tempfile::NamedTempFile>) -> Result<()>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    {
+        let mut writer = std::io::BufWriter::new(&mut file);
+        write(&mut writer)?;
+        writer.flush()?;
+    }
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|err| err.error)?;
+
+    Ok(())
+}
+
+/// Profiles `f` at 1000 Hz, e.g. the body of a `#[test]`, then writes the flamegraph of its
+/// samples to `target/pprof/<name>.svg`, see `profile_test!`. The target directory is
+/// `CARGO_TARGET_DIR` if set, or else `target` in the current directory, which is the package's
+/// when run by `cargo test`. The other threads running meanwhile, e.g. the other tests, are
+/// sampled too. It panics if the profiler can't be started or the flamegraph written, and the
+/// flamegraph isn't written if `f` panics. **only available with `flamegraph` feature**
+#[cfg(feature = "flamegraph")]
+pub fn profile_test<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    let guard = ProfilerGuardBuilder::default()
+        .frequency(1000)
+        .build()
+        .expect("failed to start the profiler");
+    let output = f();
+
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    let dir = Path::new(&target).join("pprof");
+    let path = dir.join(format!("{}.svg", name));
+    if let Err(err) = std::fs::create_dir_all(&dir)
+        .map_err(Error::from)
+        .and_then(|()| guard.dump_flamegraph(&path))
+    {
+        panic!("failed to write {}: {}", path.display(), err);
+    }
+
+    output
+}
+
+impl<'a> Drop for ProfilerGuard<'a> {
+    fn drop(&mut self) {
+        log::info!("stopping cpu profiler");
+        if let Some(auto_stop) = &self.auto_stop {
+            auto_stop.stop();
+        }
+        if let Some(states) = &self.states {
+            states.stop();
+        }
+        let mut registry = PROFILERS.write();
+        for profiler in std::iter::once(&self.profiler).chain(&self.wall_profiler) {
+            if let Err(err) = registry.unregister(profiler) {
+                log::error!("error while stopping profiler {}", err);
+            }
+        }
+    }
+}
+
+/// A handle to query the live statistics of a running profiler from any thread, see
+/// `Profiler::current` and `ProfilerGuard::handle`. It keeps the profiler alive, so it stays
+/// valid after its guard is dropped, but its statistics then stop changing.
+#[derive(Clone)]
+pub struct ProfilerHandle {
+    profiler: Arc<RwLock<Profiler>>,
+}
+
+impl ProfilerHandle {
+    /// The number of samples taken so far, dropped ones included.
+    pub fn sample_count(&self) -> usize {
+        self.profiler.read().sample_counter as usize
+    }
+
+    /// The CPU time spent by the signal handler to take the samples so far, see
+    /// `ProfilerGuard::overhead`.
+    pub fn overhead(&self) -> Duration {
+        Duration::from_nanos(self.profiler.read().overhead)
+    }
+
+    /// The requested sample frequency.
+    pub fn frequency(&self) -> c_int {
+        self.profiler.read().frequency
+    }
+
+    /// The frequency samples are actually taken at, see `ProfilerGuard::effective_frequency`.
+    pub fn effective_frequency(&self) -> c_int {
+        effective_frequency(self.frequency())
+    }
+}
+
+// Installs the signal handler of the timers of `kind`, and returns the previous disposition of
+// their signal.
+fn register_signal_handler(kind: TimerKind) -> Result<signal::SigAction> {
+    let handler = signal::SigHandler::SigAction(perf_signal_handler);
+    let action = signal::SigAction::new(
+        handler,
+        signal::SaFlags::SA_SIGINFO | signal::SaFlags::SA_RESTART,
+        signal::SigSet::empty(),
+    );
+
+    Ok(unsafe { signal::sigaction(kind.signal(), &action) }?)
+}
+
+// Restores the `previous` disposition of the signal of `kind`, or ignores it: a signal of a
+// disarmed timer may still be pending, which must not kill the process.
+fn unregister_signal_handler(kind: TimerKind, previous: Option<signal::SigAction>) -> Result<()> {
+    match previous {
+        Some(previous) => unsafe { signal::sigaction(kind.signal(), &previous) }.map(|_| ())?,
+        None => unsafe { signal::signal(kind.signal(), signal::SigHandler::SigIgn) }.map(|_| ())?,
+    }
+
+    Ok(())
+}
+
+lazy_static::lazy_static! {
+    // the full names given by `register_thread_name`, by thread id
+    static ref THREAD_NAMES: Mutex<HashMap<u64, String>> = Mutex::new(HashMap::new());
+}
+
+/// Records the full name of the calling thread, e.g. from the thread start hook of a runtime, for
+/// reports built with `ReportBuilder::full_thread_names` to show it untruncated. The name is
+/// captured from the signal handler, where it is cut to `MAX_THREAD_NAME` bytes at most, and the
+/// OS may keep even less of it, e.g. 15 bytes on Linux.
+pub fn register_thread_name() {
+    if let Some(name) = std::thread::current().name() {
+        let thread_id = unsafe { libc::pthread_self() } as u64;
+        THREAD_NAMES.lock().insert(thread_id, name.to_owned());
+    }
+}
+
+// The full name of `thread_id` if `captured` is a truncation of it, either registered with
+// `register_thread_name` or, for the calling thread, its own name.
+pub(crate) fn full_thread_name(thread_id: u64, captured: &str) -> Option<String> {
+    let name = if thread_id == unsafe { libc::pthread_self() } as u64 {
+        std::thread::current().name().map(str::to_owned)
+    } else {
+        None
+    };
+    // the thread id of an exited thread may be reused by a thread with another name
+    name.or_else(|| THREAD_NAMES.lock().get(&thread_id).cloned())
+        .filter(|name| !captured.is_empty() && name.starts_with(captured))
+}
+
+fn write_thread_name_fallback(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+    let mut len = 0;
+    let mut base = 1;
+
+    while current_thread as u128 > base && len < MAX_THREAD_NAME {
+        base *= 10;
+        len += 1;
+    }
+
+    let mut index = 0;
+    while index < len && base > 1 {
+        base /= 10;
+
+        name[index] = match (48 + (current_thread as u128 / base) % 10).try_into() {
+            Ok(digit) => digit,
+            Err(_) => {
+                log::error!("fail to convert thread_id to string");
+                0
+            }
+        };
+
+        index += 1;
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+    write_thread_name_fallback(current_thread, name);
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn write_thread_name(current_thread: libc::pthread_t, name: &mut [libc::c_char]) {
+    let name_ptr = name as *mut [libc::c_char] as *mut libc::c_char;
+    let ret = unsafe { libc::pthread_getname_np(current_thread, name_ptr, MAX_THREAD_NAME) };
+
+    if ret != 0 {
+        write_thread_name_fallback(current_thread, name);
+    }
+}
+
+// Whether the signal was sent by a per-thread timer, see `ThreadTimers`, rather than by the
+// process-wide timer or by `raise`. This function is AS-safe.
+#[cfg(target_os = "linux")]
+fn from_thread_timer(siginfo: *const libc::siginfo_t) -> bool {
+    !siginfo.is_null() && unsafe { (*siginfo).si_code } == libc::SI_TIMER
+}
+
+#[cfg(not(target_os = "linux"))]
+fn from_thread_timer(_siginfo: *const libc::siginfo_t) -> bool {
+    false
+}
+
+#[no_mangle]
+extern "C" fn perf_signal_handler(
+    signal: c_int,
+    siginfo: *mut libc::siginfo_t,
+    ucontext: *mut libc::c_void,
+) {
+    #[cfg(test)]
+    let _handling = tests::Handling::enter();
+    let start = monotonic_time();
+
+    let kind = match TimerKind::from_signal(signal) {
+        Some(kind) => kind,
+        None => return,
+    };
+    if let Some(registry) = PROFILERS.try_read() {
+        if !registry.has_profilers(kind) {
+            return;
+        }
+
+        let current_thread = unsafe { libc::pthread_self() };
+        let mut name = [0 as libc::c_char; MAX_THREAD_NAME];
+        let name_ptr = &mut name as *mut [libc::c_char] as *mut libc::c_char;
+
+        write_thread_name(current_thread, &mut name);
+
+        let name = unsafe { std::ffi::CStr::from_ptr(name_ptr) };
+        let per_thread = from_thread_timer(siginfo);
+        let timer_frequency = registry.frequency(kind, per_thread);
+        // the stack is captured at most once per unwinder, and only if a profiler samples it
+        let mut captured: [Captured; Unwinder::COUNT] = [None; Unwinder::COUNT];
+        let forks = FORKS.load(Ordering::Relaxed);
+        // a manual tick is only for the profiler of the ticking guard, a `SIGUSR2` sent otherwise
+        // is for none
+        let ticked = match kind {
+            TimerKind::Manual => MANUAL_TICK.try_with(Cell::get).unwrap_or(0),
+            _ => 0,
+        };
+        for registered in registry.profilers.iter() {
+            if registered.timer_kind != kind
+                || registered.per_thread_timers != per_thread
+                || registered.forks != forks
+                || (kind == TimerKind::Manual
+                    && Arc::as_ptr(&registered.profiler) as usize != ticked)
+            {
+                continue;
+            }
+            registered.sample(
+                start,
+                timer_frequency,
+                ucontext,
+                name.to_bytes(),
+                current_thread as u64,
+                &mut captured,
+            );
+        }
+    }
+}
+
+impl Profiler {
+    pub(crate) fn new<P>(frequency: c_int, data: Collector<UnresolvedFrames, P>) -> Self
+    where
+        P: EvictionPolicy<UnresolvedFrames> + Send + Sync + 'static,
+    {
+        Profiler {
+            data: data.boxed_policy(),
+            hashed: None,
+            sample_counter: 0,
+            dropped_sample_counter: 0,
+            cpu_times: None,
+            lifetimes: None,
+            states: None,
+            last_samples: None,
+            unwinder: Unwinder::default(),
+            timer_kind: TimerKind::default(),
+            per_thread_timers: false,
+            record_cpu: false,
+            drop_corrupt_stacks: false,
+            lock_deadline: 0,
+            intervals: None,
+            forks: FORKS.load(Ordering::Relaxed),
+            overhead: 0,
+            frequency,
+            credit: 0,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            stop_instant: None,
+        }
+    }
+}
+
+impl Profiler {
+    /// A handle to the most recently started profiler which is still running, if any.
+    pub fn current() -> Option<ProfilerHandle> {
+        PROFILERS
+            .read()
+            .profilers
+            .last()
+            .map(|registered| ProfilerHandle {
+                profiler: registered.profiler.clone(),
+            })
+    }
+
+    /// Returns the frequency and start time of this profiler, and the time elapsed since its
+    /// creation as duration.
+    pub(crate) fn timing(&self) -> ReportTiming {
+        ReportTiming {
+            frequency: self.frequency,
+            start_time: self.start_time,
+            duration: self
+                .stop_instant
+                .unwrap_or_else(Instant::now)
+                .saturating_duration_since(self.start_instant),
+            overhead: Duration::from_nanos(self.overhead),
+            thread_lifetimes: self.thread_lifetimes(),
+        }
+    }
+
+    fn thread_lifetimes(&self) -> Vec<(u64, Instant, Instant)> {
+        let (now, now_instant) = (monotonic_time(), Instant::now());
+        let instant = |time: u64| {
+            let ago = Duration::from_nanos(now.saturating_sub(time));
+            now_instant.checked_sub(ago).unwrap_or(self.start_instant)
+        };
+
+        self.lifetimes
+            .iter()
+            .flat_map(ThreadTable::iter)
+            .map(|(thread_id, (first, last))| (thread_id, instant(first), instant(last)))
+            .collect()
+    }
+
+    /// Reads the current scheduler state of every sampled thread, if recorded, see
+    /// `ProfilerGuardBuilder::thread_states`.
+    pub(crate) fn thread_states(&self) -> Vec<(u64, ThreadState)> {
+        self.states
+            .as_ref()
+            .map_or_else(Vec::new, |states| states.current())
+    }
+
+    // Called on every tick of the shared timer, returns whether this tick should be sampled so that
+    // samples are taken at `self.frequency`.
+    fn tick(&mut self, timer_frequency: c_int) -> bool {
+        // every manual tick is for this profiler only
+        if self.timer_kind == TimerKind::Manual {
+            return true;
+        }
+        self.credit += self.frequency;
+        if self.credit >= timer_frequency {
+            self.credit -= timer_frequency;
+            true
+        } else {
+            false
+        }
+    }
+
+    // This function has to be AS-safe
+    pub fn sample(&mut self, backtrace: &[UnresolvedFrame], thread_name: &[u8], thread_id: u64) {
+        self.sample_at(backtrace, thread_name, thread_id, None, None);
+    }
+
+    // Samples `backtrace` counted `weight` times, out of the timer.
+    fn sample_weighted(
+        &mut self,
+        backtrace: &[UnresolvedFrame],
+        thread_name: &[u8],
+        thread_id: u64,
+        weight: isize,
+    ) {
+        let mut frames = UnresolvedFrames::new(backtrace, thread_name, thread_id);
+        if self.record_cpu {
+            frames.cpu = current_cpu();
+        }
+        frames.task = current_task();
+        frames.state = thread_state(&self.states, thread_id);
+        // the leaf is the caller of `sample_now`, its address is the one it returns to
+        frames.caller_leaf = true;
+        self.track(thread_id);
+        self.record(frames, weight);
+    }
+
+    // Samples `backtrace`, interrupted at `registers` if they could be read, which its repeats
+    // are then recognized by, in `task` if any. This function has to be AS-safe.
+    fn sample_at(
+        &mut self,
+        backtrace: &[UnresolvedFrame],
+        thread_name: &[u8],
+        thread_id: u64,
+        registers: Option<[usize; 3]>,
+        task: Option<u32>,
+    ) {
+        let mut frames = UnresolvedFrames::new(backtrace, thread_name, thread_id);
+        if self.record_cpu {
+            frames.cpu = current_cpu();
+        }
+        frames.task = task;
+        frames.state = thread_state(&self.states, thread_id);
+        self.track(thread_id);
+
+        let count = if frames.is_empty() {
+            0
+        } else {
+            self.weight(thread_id)
+        };
+        if let (Some(registers), Some(last_samples)) = (registers, self.last_samples.as_mut()) {
+            if !frames.is_empty() {
+                let last = LastSample {
+                    registers,
+                    frames: frames.clone(),
+                    pending: 0,
+                };
+                if let Some(previous) = last_samples.get_or_insert(thread_id, last.clone()) {
+                    let previous = std::mem::replace(previous, last);
+                    if previous.pending > 0 {
+                        let (data, hashed) = (&mut self.data, &mut self.hashed);
+                        add_sample(data, hashed, previous.frames, previous.pending);
+                    }
+                }
+            }
+        }
+        self.record(frames, count);
+    }
+
+    // Counts a sample of `thread_id` into its last one without capturing its stack again, if it
+    // was interrupted at the same `registers` in the same `task`. Returns whether it did. This
+    // function has to be AS-safe.
+    fn sample_repeated(
+        &mut self,
+        thread_id: u64,
+        registers: Option<[usize; 3]>,
+        task: Option<u32>,
+    ) -> bool {
+        let cpu = if self.record_cpu { current_cpu() } else { None };
+        let state = thread_state(&self.states, thread_id);
+        let repeated = match (registers, self.last_samples.as_mut()) {
+            (Some(registers), Some(last_samples)) => match last_samples.get_mut(thread_id) {
+                Some(last) => {
+                    last.registers == registers
+                        && last.frames.cpu == cpu
+                        && last.frames.task == task
+                        && last.frames.state == state
+                }
+                None => false,
+            },
+            _ => false,
+        };
+        if !repeated {
+            return false;
+        }
+
+        self.track(thread_id);
+        let count = self.weight(thread_id);
+        self.sample_counter += 1;
+        if let Some(last) = self
+            .last_samples
+            .as_mut()
+            .and_then(|last_samples| last_samples.get_mut(thread_id))
+        {
+            last.pending += count;
+        }
+
+        true
+    }
+
+    // Adds the pending repeats of the last samples to the collector, so that it has every sample.
+    pub(crate) fn flush_repeated(&mut self) {
+        let (data, hashed) = (&mut self.data, &mut self.hashed);
+        for last in self
+            .last_samples
+            .iter_mut()
+            .flat_map(ThreadTable::values_mut)
+        {
+            if last.pending > 0 {
+                let pending = std::mem::replace(&mut last.pending, 0);
+                add_sample(data, hashed, last.frames.clone(), pending);
+            }
+        }
+    }
+
+    // Records the per-thread state of a sample of `thread_id`, besides its stack. This function
+    // has to be AS-safe.
+    fn track(&mut self, thread_id: u64) {
+        if let Some(lifetimes) = self.lifetimes.as_mut() {
+            let now = monotonic_time();
+            if let Some((_, last)) = lifetimes.get_or_insert(thread_id, (now, now)) {
+                *last = now;
+            }
+        }
+    }
+
+    // Adds `count` to `frames`. This function has to be AS-safe.
+    fn record(&mut self, frames: UnresolvedFrames, count: isize) {
+        self.sample_counter += 1;
+
+        // the unwinder captured nothing, which would only show up as a rootless sample
+        if frames.is_empty() {
+            self.dropped_sample_counter += 1;
+            return;
+        }
+
+        if count > 0 {
+            add_sample(&mut self.data, &mut self.hashed, frames, count);
+        }
+    }
+
+    /// The entries counted so far, by `data` or by `hashed`.
+    pub(crate) fn try_iter(
+        &self,
+    ) -> std::io::Result<impl Iterator<Item = (&UnresolvedFrames, isize)>> {
+        let hashed = self
+            .hashed
+            .as_ref()
+            .map(HashedCollector::try_iter)
+            .transpose()?;
+        let entries = self
+            .data
+            .try_iter()?
+            .map(|entry| (&entry.item, entry.count));
+
+        Ok(entries.chain(hashed.into_iter().flatten()))
+    }
+
+    /// Moves the entries counted so far out of `data` or `hashed`, see `Collector::drain`.
+    pub(crate) fn drain(
+        &mut self,
+    ) -> std::io::Result<impl Iterator<Item = (UnresolvedFrames, isize)> + '_> {
+        let hashed = self
+            .hashed
+            .as_mut()
+            .map(HashedCollector::drain)
+            .transpose()?;
+        let entries = self.data.drain()?.map(|entry| (entry.item, entry.count));
+
+        Ok(entries.chain(hashed.into_iter().flatten()))
+    }
+
+    /// The total count of the samples dropped by `data` or `hashed`, see their `dropped_count`.
+    pub(crate) fn dropped_count(&self) -> isize {
+        let hashed = self
+            .hashed
+            .as_ref()
+            .map_or(0, HashedCollector::dropped_count);
+        self.data.dropped_count() + hashed
+    }
+
+    // The weight of a sample of `thread_id`, which is the CPU time consumed by the thread since its
+    // previous sample rounded to periods if `cpu_times` is enabled. This function has to be AS-safe.
+    fn weight(&mut self, thread_id: u64) -> isize {
+        let cpu_times = match self.cpu_times.as_mut() {
+            Some(cpu_times) => cpu_times,
+            None => return 1,
+        };
+
+        let now = thread_cpu_time();
+        match cpu_times.replace(thread_id, now) {
+            Some(last) if self.frequency > 0 => {
+                let period = 1_000_000_000 / self.frequency as u64;
+                ((now.saturating_sub(last) + period / 2) / period) as isize
+            }
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Symbol, SymbolDisplay};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::{Cell, RefCell};
+    use std::ffi::c_void;
+
+    thread_local! {
+        static IN_HANDLER: Cell<bool> = const { Cell::new(false) };
+        static HANDLER_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // Counts the allocations and deallocations made by the signal handler on every thread, which
+    // must make none: it may interrupt the allocator itself. They are counted rather than
+    // panicking right away, as the panic would allocate in turn.
+    struct HandlerAllocator;
+
+    fn count_handler_allocation() {
+        if IN_HANDLER.try_with(Cell::get).unwrap_or(false) {
+            let _ = HANDLER_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        }
+    }
+
+    unsafe impl GlobalAlloc for HandlerAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_handler_allocation();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count_handler_allocation();
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: HandlerAllocator = HandlerAllocator;
+
+    // Marks the calling thread as running the signal handler, until dropped.
+    pub(super) struct Handling {
+        previous: bool,
+    }
+
+    impl Handling {
+        pub(super) fn enter() -> Self {
+            let previous = IN_HANDLER.try_with(|flag| flag.replace(true));
+            Self {
+                previous: previous.unwrap_or(false),
+            }
+        }
+    }
+
+    impl Drop for Handling {
+        fn drop(&mut self) {
+            let _ = IN_HANDLER.try_with(|flag| flag.set(self.previous));
+        }
+    }
+
+    extern "C" {
+        static mut __malloc_hook: Option<extern "C" fn(size: usize) -> *mut c_void>;
+
+        fn malloc(size: usize) -> *mut c_void;
+    }
+
+    thread_local! {
+        static FLAG: RefCell<bool> = const { RefCell::new(false) };
+    }
+
+    extern "C" fn malloc_hook(size: usize) -> *mut c_void {
+        unsafe {
+            __malloc_hook = None;
+        }
+
+        FLAG.with(|flag| {
+            flag.replace(true);
+        });
+        let p = unsafe { malloc(size) };
+
+        unsafe {
+            __malloc_hook = Some(malloc_hook);
+        }
+
+        p
+    }
+
+    #[inline(never)]
+    fn is_prime_number(v: usize, prime_numbers: &[usize]) -> bool {
+        if v < 10000 {
+            let r = prime_numbers.binary_search(&v);
+            return r.is_ok();
+        }
+
+        for n in prime_numbers {
+            if v % n == 0 {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    #[inline(never)]
+    fn prepare_prime_numbers() -> Vec<usize> {
+        // bootstrap: Generate a prime table of 0..10000
+        let mut prime_number_table: [bool; 10000] = [true; 10000];
+        prime_number_table[0] = false;
+        prime_number_table[1] = false;
+        for i in 2..10000 {
+            if prime_number_table[i] {
+                let mut v = i * 2;
+                while v < 10000 {
+                    prime_number_table[v] = false;
+                    v += i;
+                }
+            }
+        }
+        let mut prime_numbers = vec![];
+        for (i, is_prime) in prime_number_table.iter().enumerate().skip(2) {
+            if *is_prime {
+                prime_numbers.push(i);
+            }
+        }
+        prime_numbers
+    }
+
+    #[test]
+    fn malloc_free() {
+        let _guard = ProfilerGuard::new(100).unwrap();
+
+        let prime_numbers = prepare_prime_numbers();
+
+        let mut _v = 0;
+
+        unsafe {
+            __malloc_hook = Some(malloc_hook);
+        }
+        for i in 2..50000 {
+            if is_prime_number(i, &prime_numbers) {
+                _v += 1;
+                perf_signal_handler(27, std::ptr::null_mut(), std::ptr::null_mut());
+            }
+        }
+        unsafe {
             __malloc_hook = None;
         }
 
-        FLAG.with(|flag| {
-            assert_eq!(*flag.borrow(), false);
+        FLAG.with(|flag| {
+            assert!(!*flag.borrow());
+        });
+    }
+
+    // Ticks `guard` `depth` nested frames deep, so that every depth is a distinct stack.
+    #[inline(never)]
+    fn handle_nested(guard: &ProfilerGuard, depth: usize) -> usize {
+        if depth == 0 {
+            guard.tick();
+            0
+        } else {
+            // not a tail call, so that the frame stays on the stack
+            let nested = handle_nested(guard, depth - 1);
+            unsafe { std::ptr::read_volatile(&nested) + 1 }
+        }
+    }
+
+    #[test]
+    fn handler_never_allocates() {
+        // every tick is counted once, unless the handler can't read the registry while another
+        // test registers a profiler, so this runs itself again in a child process
+        if std::env::var_os("PPROF_TEST_HANDLER_ALLOCATIONS").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "profiler::tests::handler_never_allocates"])
+                .env("PPROF_TEST_HANDLER_ALLOCATIONS", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let unwinders = [Unwinder::Backtrace, Unwinder::FramePointer];
+        for unwinder in unwinders.iter().filter(|unwinder| unwinder.is_supported()) {
+            // a manual timer takes no sample but those of the ticks, and a single bucket evicts
+            // all but one of their stacks to the temp file, in several writes
+            let config = CollectorConfig {
+                buckets: 1,
+                associativity: 1,
+                buffer_len: 4,
+                spill_dir: None,
+            };
+            let guard = ProfilerGuardBuilder::default()
+                .timer_kind(TimerKind::Manual)
+                .frequency(1000)
+                .with_collector_config(config)
+                .unwinder(*unwinder)
+                .thread_lifetimes(true)
+                .thread_states(true)
+                .record_cpu(true)
+                .dedup_samples(true)
+                .build()
+                .unwrap();
+            let before = HANDLER_ALLOCATIONS.with(Cell::get);
+            for depth in 0..MAX_DEPTH {
+                handle_nested(&guard, depth);
+            }
+            // a report in between leaves the cache of the temp file for the handler to replace
+            drop(guard.report().build().unwrap());
+            for depth in 0..MAX_DEPTH {
+                handle_nested(&guard, depth);
+            }
+
+            let report = guard.report().build().unwrap();
+            assert_eq!(
+                report.data.values().sum::<isize>(),
+                2 * MAX_DEPTH as isize,
+                "{:?}",
+                unwinder
+            );
+            assert_eq!(
+                HANDLER_ALLOCATIONS.with(Cell::get),
+                before,
+                "{:?}",
+                unwinder
+            );
+        }
+    }
+
+    #[test]
+    fn effective_frequency() {
+        let guard = ProfilerGuard::new(100).unwrap();
+        assert_eq!(guard.effective_frequency(), 100);
+        drop(guard);
+
+        // far over both the kernel tick rate and the microsecond resolution of the timer
+        let guard = ProfilerGuard::new(10_000_000).unwrap();
+        assert!(guard.effective_frequency() > 0);
+        assert!(guard.effective_frequency() <= 1000);
+    }
+
+    #[inline(never)]
+    fn rebased_function() -> usize {
+        rebased_function as *const () as usize
+    }
+
+    #[test]
+    fn rebase() {
+        const SLIDE: usize = 0x10_0000;
+        let profiler = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        let address = rebased_function() + 1;
+        let frame = UnresolvedFrame {
+            ip: address - SLIDE,
+            symbol_address: address - SLIDE,
+        };
+        profiler.write().sample(&[frame], b"rebased", 1);
+
+        let report = ReportBuilder::new(&profiler)
+            .rebase(|address| address + SLIDE)
+            .build()
+            .unwrap();
+        let (frames, _) = report.data.iter().next().unwrap();
+        assert!(frames.frames[0]
+            .iter()
+            .any(|symbol| symbol.name().ends_with("rebased_function")));
+    }
+
+    mod first {
+        pub mod shared {
+            #[inline(never)]
+            pub fn leaf() -> u32 {
+                1
+            }
+        }
+    }
+
+    mod second {
+        pub mod shared {
+            #[inline(never)]
+            pub fn leaf() -> u32 {
+                2
+            }
+        }
+    }
+
+    #[test]
+    fn symbol_display() {
+        let profiler = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        // two functions of the same short name
+        let leaves = [first::shared::leaf, second::shared::leaf];
+        for leaf in leaves.iter() {
+            let address = *leaf as *const () as usize + 1;
+            let frame = UnresolvedFrame {
+                ip: address,
+                symbol_address: address,
+            };
+            profiler.write().sample(&[frame], b"main", 1);
+        }
+
+        let names = |display: SymbolDisplay| {
+            let report = ReportBuilder::new(&profiler)
+                .symbol_display(display)
+                .build()
+                .unwrap();
+            let mut names: Vec<_> = report
+                .data
+                .iter()
+                .map(|(frames, count)| (frames.frames[0][0].name(), *count))
+                .collect();
+            names.sort();
+            names
+        };
+        let full = names(SymbolDisplay::Full);
+        assert_eq!(full.len(), 2, "{:?}", full);
+        assert!(full[0].0.ends_with("profiler::tests::first::shared::leaf"));
+        assert!(full[1].0.ends_with("profiler::tests::second::shared::leaf"));
+        assert_eq!(
+            names(SymbolDisplay::Short),
+            vec![("shared::leaf".to_owned(), 2)]
+        );
+    }
+
+    #[inline(never)]
+    fn slow_request(guard: &ProfilerGuard, weight: isize) {
+        guard.sample_now(weight);
+    }
+
+    #[inline(never)]
+    fn slower_request(guard: &ProfilerGuard) {
+        guard.sample_now(3);
+    }
+
+    #[test]
+    fn sample_now() {
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            slow_request(&guard, 1);
+        }
+        slower_request(&guard);
+
+        let report = guard.report().build().unwrap();
+        let leaves = |name: &str| {
+            let leaf = |frames: &crate::Frames| frames.frames[0][0].name().ends_with(name);
+            let samples = report.data.iter().filter(|(frames, _)| leaf(frames));
+            samples.map(|(_, count)| *count).sum::<isize>()
+        };
+        assert_eq!(leaves("slow_request"), 5);
+        assert_eq!(leaves("slower_request"), 3);
+        assert_eq!(report.data.values().sum::<isize>(), 8);
+    }
+
+    #[test]
+    fn hashed_stacks() {
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .hashed_stacks(1)
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            slow_request(&guard, 1);
+        }
+        // the side table is full, the samples of another stack are dropped
+        slower_request(&guard);
+        assert_eq!(guard.profiler.read().data.try_iter().unwrap().count(), 0);
+
+        let report = guard.report().build().unwrap();
+        assert_eq!(report.data.len(), 1);
+        let (frames, count) = report.data.iter().next().unwrap();
+        assert!(frames.frames[0][0].name().ends_with("slow_request"));
+        assert_eq!(*count, 5);
+        assert_eq!(report.dropped_samples, 3);
+
+        // the stacks are moved out of the side table, which has room again
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .hashed_stacks(16)
+            .build()
+            .unwrap();
+        slow_request(&guard, 2);
+        slower_request(&guard);
+        let report = guard.into_report().build().unwrap();
+        assert_eq!(report.data.len(), 2);
+        assert_eq!(report.data.values().sum::<isize>(), 5);
+        assert_eq!(report.dropped_samples, 0);
+    }
+
+    #[test]
+    fn forked_child() {
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .build()
+            .unwrap();
+        for _ in 0..3 {
+            guard.tick();
+        }
+
+        // locked at the fork, like by a thread of the parent, it stays locked in the child
+        let locked = guard.profiler.write();
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // only async-signal-safe calls in the child, then exit without unwinding
+            MANUAL_TICK.with(|ticked| ticked.set(Arc::as_ptr(&guard.profiler) as usize));
+            for _ in 0..5 {
+                unsafe { libc::raise(TimerKind::Manual.signal() as c_int) };
+            }
+            guard.sample_now(1);
+            let samples = locked.sample_counter;
+            unsafe { libc::_exit(if samples == 3 { 0 } else { 1 }) };
+        }
+        drop(locked);
+
+        // a child waiting for the lock fails the test instead of hanging it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut status = 0;
+        while unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == 0 {
+            if Instant::now() > deadline {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+                panic!("the forked child is blocked");
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+        for _ in 0..2 {
+            guard.tick();
+        }
+        let report = guard.report().build().unwrap();
+        assert_eq!(report.data.values().sum::<isize>(), 5);
+    }
+
+    #[test]
+    fn ensure_frames() {
+        let profiler = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        let mut bt = Vec::new();
+        backtrace::trace(|frame| {
+            bt.push(UnresolvedFrame::from(frame));
+            bt.len() < MAX_DEPTH
+        });
+        profiler.write().sample(&bt, b"main", 1);
+
+        let sampled = ReportBuilder::new(&profiler).build().unwrap();
+        let sampled_name = sampled.data.keys().next().unwrap().frames[0][0].name();
+
+        let report = ReportBuilder::new(&profiler)
+            .ensure_frames(&["never_sampled", &sampled_name])
+            .build()
+            .unwrap();
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+
+        // only the function which wasn't sampled gets a placeholder
+        assert_eq!(report.data.len(), 2);
+        assert!(folded.lines().any(|line| line == "0;never_sampled 0"));
+        assert!(!folded.contains(&format!("0;{} 0", sampled_name)));
+    }
+
+    #[inline(never)]
+    fn captured_stack() -> Vec<UnresolvedFrame> {
+        let mut bt = Vec::new();
+        backtrace::trace(|frame| {
+            bt.push(UnresolvedFrame::from(frame));
+            bt.len() < MAX_DEPTH
+        });
+
+        bt
+    }
+
+    #[inline(never)]
+    fn kept_subsystem(nested: bool) -> Vec<UnresolvedFrame> {
+        let bt = if nested {
+            kept_subsystem(false)
+        } else {
+            captured_stack()
+        };
+        // not a tail call, so that the frame stays on the stack
+        unsafe { std::ptr::read_volatile(&bt.len()) };
+
+        bt
+    }
+
+    #[inline(never)]
+    fn other_subsystem() -> Vec<UnresolvedFrame> {
+        let bt = captured_stack();
+        unsafe { std::ptr::read_volatile(&bt.len()) };
+
+        bt
+    }
+
+    #[test]
+    fn only_stacks_containing() {
+        let profiler = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        profiler.write().sample(&kept_subsystem(false), b"main", 1);
+        profiler.write().sample(&kept_subsystem(true), b"main", 1);
+        profiler.write().sample(&other_subsystem(), b"main", 1);
+
+        let report = ReportBuilder::new(&profiler)
+            .only_stacks_containing("kept_subsystem")
+            .build()
+            .unwrap();
+        let matches = |frames: &crate::Frames, name: &str| {
+            let names = frames.frames.iter().flatten().map(Symbol::name);
+            names.filter(|frame| frame.contains(name)).count()
+        };
+        let mut nesting: Vec<usize> = report
+            .data
+            .keys()
+            .map(|frames| matches(frames, "kept_subsystem"))
+            .collect();
+        nesting.sort_unstable();
+        assert_eq!(nesting, vec![1, 2]);
+        assert_eq!(report.data.values().sum::<isize>(), 2);
+        assert_eq!(report.dropped_samples, 0);
+        // the callers of the matching frame are kept too
+        assert!(report
+            .data
+            .keys()
+            .all(|frames| matches(frames, "only_stacks_containing") > 0
+                && matches(frames, "other_subsystem") == 0));
+
+        let unfiltered = ReportBuilder::new(&profiler).build().unwrap();
+        assert_eq!(unfiltered.data.len(), 3);
+    }
+
+    #[test]
+    fn duration() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .duration(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let handle = guard.handle();
+        assert!(!guard.is_finished());
+
+        busy_for(Duration::from_millis(100));
+        assert!(handle.sample_count() > 0);
+        // the samples stop growing once the duration elapsed
+        assert!(guard.wait_finished(Duration::from_secs(5)));
+        let stopped = handle.sample_count();
+        busy_for(Duration::from_millis(200));
+        assert_eq!(handle.sample_count(), stopped);
+        assert!(guard.is_finished());
+
+        let report = guard.report().build().unwrap();
+        assert!(report.timing.duration < Duration::from_millis(400));
+
+        let guard = ProfilerGuard::new(100).unwrap();
+        assert!(!guard.is_finished());
+        assert!(!guard.wait_finished(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn current() {
+        let guard = ProfilerGuard::new(100).unwrap();
+        let handle = guard.handle();
+
+        let counts = std::thread::spawn(move || {
+            // other tests may have started a profiler since, but one is running at least
+            let current = Profiler::current().unwrap();
+            let counts = (current.sample_count(), handle.sample_count());
+            busy_for(Duration::from_millis(300));
+            assert!(current.sample_count() >= counts.0);
+            (counts.1, handle.sample_count())
+        })
+        .join()
+        .unwrap();
+
+        assert!(counts.1 > counts.0);
+        assert_eq!(guard.handle().effective_frequency(), 100);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn thread_states() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc;
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .thread_states(true)
+            .build()
+            .unwrap();
+
+        // one thread keeps spinning, the other one sleeps on a channel once it was sampled
+        let stop = Arc::new(AtomicBool::new(false));
+        let (busy_id, busy_id_receiver) = mpsc::channel();
+        let busy = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                busy_id
+                    .send(unsafe { libc::pthread_self() as u64 })
+                    .unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    busy_for(Duration::from_millis(10));
+                }
+            })
+        };
+        let (sleeping_id, sleeping_id_receiver) = mpsc::channel();
+        let (wake, wake_receiver) = mpsc::channel::<()>();
+        let sleeping = std::thread::spawn(move || {
+            busy_for(Duration::from_millis(200));
+            sleeping_id
+                .send(unsafe { libc::pthread_self() as u64 })
+                .unwrap();
+            wake_receiver.recv().unwrap();
+        });
+        let busy_id = busy_id_receiver.recv().unwrap();
+        let sleeping_id = sleeping_id_receiver.recv().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let report = guard.report().build().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        wake.send(()).unwrap();
+        busy.join().unwrap();
+        sleeping.join().unwrap();
+
+        let state = |thread_id| {
+            report
+                .thread_states
+                .iter()
+                .find(|(id, _)| *id == thread_id)
+                .map(|(_, state)| *state)
+        };
+        assert_eq!(state(busy_id), Some(ThreadState::Running));
+        assert_eq!(state(sleeping_id), Some(ThreadState::Sleeping));
+    }
+
+    #[test]
+    fn thread_state_from_stat() {
+        let stat = "1234 (worker (1)) S 1 1234 1234 0 -1 4194560";
+        assert_eq!(ThreadState::from_stat(stat), Some(ThreadState::Sleeping));
+        assert_eq!(
+            ThreadState::from_stat("7 (a) R 1"),
+            Some(ThreadState::Running)
+        );
+        assert_eq!(
+            ThreadState::from_stat("7 (a) D 1"),
+            Some(ThreadState::Blocked)
+        );
+        assert_eq!(ThreadState::from_stat("garbage"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sample_states() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc;
+
+        // one thread keeps spinning, the other one keeps sleeping, both sampled by wall-clock
+        // timers of their own
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_id, thread_ids) = mpsc::channel();
+        let spawn = |sleeping: bool| {
+            let (stop, thread_id) = (stop.clone(), thread_id.clone());
+            std::thread::spawn(move || {
+                let id = unsafe { libc::pthread_self() } as u64;
+                thread_id.send((sleeping, id)).unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    if sleeping {
+                        std::thread::sleep(Duration::from_millis(20));
+                    } else {
+                        busy_for(Duration::from_millis(1));
+                    }
+                }
+            })
+        };
+        let threads = [spawn(false), spawn(true)];
+        let thread_ids: HashMap<bool, u64> = thread_ids.iter().take(2).collect();
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(200)
+            .timer_kind(TimerKind::WallTime)
+            .per_thread_timers(true)
+            .thread_states(true)
+            .build()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        let report = guard.report().build().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let samples = |sleeping: bool, state: ThreadState| {
+            let frames = report.data.iter().filter(|(frames, _)| {
+                frames.thread_id == thread_ids[&sleeping] && frames.state == Some(state)
+            });
+            frames.map(|(_, count)| *count).sum::<isize>()
+        };
+        let busy = (
+            samples(false, ThreadState::Running),
+            samples(false, ThreadState::Sleeping),
+        );
+        let sleeping = (
+            samples(true, ThreadState::Running),
+            samples(true, ThreadState::Sleeping),
+        );
+        assert!(busy.0 > busy.1, "{:?} {:?}", busy, sleeping);
+        assert!(sleeping.1 > sleeping.0, "{:?} {:?}", busy, sleeping);
+    }
+
+    #[test]
+    fn flush() {
+        let guard = ProfilerGuard::new(1000).unwrap();
+        let deferred = || {
+            let registry = PROFILERS.read();
+            let item = registry
+                .profilers
+                .iter()
+                .find(|item| Arc::ptr_eq(&item.profiler, &guard.profiler))
+                .unwrap();
+            let len = item.deferred.lock().len();
+            len
+        };
+
+        // the samples taken while the profiler is locked are deferred
+        let locked = guard.profiler.write();
+        busy_for(Duration::from_millis(200));
+        let pending = deferred();
+        drop(locked);
+        assert!(pending > 0);
+
+        guard.flush();
+        let profiler = guard.profiler.read();
+        let count: isize = profiler
+            .data
+            .try_iter()
+            .unwrap()
+            .map(|entry| entry.count)
+            .sum();
+        // some of them may have been merged by a sample in between, but none is lost
+        assert!(count as usize + profiler.dropped_sample_counter >= pending);
+        assert_eq!(
+            count as usize + profiler.dropped_sample_counter,
+            profiler.sample_counter as usize
+        );
+    }
+
+    #[test]
+    fn interval_stats() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(100)
+            .record_intervals(true)
+            .build()
+            .unwrap();
+        busy_for(Duration::from_secs(1));
+
+        let stats = guard.interval_stats().unwrap();
+        // other tests may take the CPU from this one meanwhile
+        assert!(stats.count >= 30, "{:?}", stats);
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+        let mean_ms = stats.mean.as_secs_f64() * 1000.0;
+        assert!((5.0..20.0).contains(&mean_ms), "{:?}", stats);
+        assert!(stats.stddev < stats.max, "{:?}", stats);
+        assert_eq!(ProfilerGuard::new(100).unwrap().interval_stats(), None);
+    }
+
+    #[test]
+    fn intervals() {
+        let mut intervals = Intervals::default();
+        assert_eq!(intervals.stats(), IntervalStats::default());
+        for now in [0, 10, 30, 40, 60] {
+            intervals.record(now * 1_000_000);
+        }
+        let stats = intervals.stats();
+        let ms = Duration::from_millis;
+        assert_eq!((stats.count, stats.min, stats.max), (4, ms(10), ms(20)));
+        assert_eq!((stats.mean, stats.stddev), (ms(15), ms(5)));
+    }
+
+    #[test]
+    fn report_timing() {
+        let guard = ProfilerGuard::new(97).unwrap();
+        let report = guard.report().build().unwrap();
+
+        assert_eq!(report.frequency(), 97);
+        let period_ms = report.period().as_secs_f64() * 1000.0;
+        assert!((period_ms - 10.3).abs() < 0.1);
+    }
+
+    #[test]
+    fn drop_empty_stacks() {
+        let profiler = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        let mut bt = Vec::new();
+        backtrace::trace(|frame| {
+            bt.push(UnresolvedFrame::from(frame));
+            bt.len() < MAX_DEPTH
+        });
+
+        profiler.write().sample(&[], b"empty", 0);
+        profiler.write().sample(&bt, b"captured", 1);
+
+        let report = ReportBuilder::new(&profiler).build().unwrap();
+        assert_eq!(report.dropped_samples, 1);
+        assert_eq!(report.data.len(), 1);
+        assert!(report
+            .data
+            .keys()
+            .all(|frames| !frames.is_empty() && frames.thread_name == "captured"));
+    }
+
+    #[inline(never)]
+    fn busy_for(duration: std::time::Duration) -> usize {
+        let prime_numbers = prepare_prime_numbers();
+        let start = Instant::now();
+        let mut count = 0;
+        while start.elapsed() < duration {
+            for i in 2..50000 {
+                if is_prime_number(i, &prime_numbers) {
+                    count += 1;
+                }
+            }
+        }
+
+        count
+    }
+
+    #[test]
+    fn multiple_profilers() {
+        let guard1 = ProfilerGuard::new(100).unwrap();
+        let guard2 = ProfilerGuard::new(50).unwrap();
+        busy_for(std::time::Duration::from_millis(500));
+
+        let report1 = guard1.report().build().unwrap();
+        let report2 = guard2.report().build().unwrap();
+        assert_eq!(report1.frequency(), 100);
+        assert_eq!(report2.frequency(), 50);
+        assert!(report1.data.values().sum::<isize>() > 0);
+        let count2 = report2.data.values().sum::<isize>();
+        assert!(count2 > 0);
+
+        // the remaining profiler keeps sampling
+        drop(guard1);
+        busy_for(std::time::Duration::from_millis(500));
+        let report2 = guard2.report().build().unwrap();
+        assert!(report2.data.values().sum::<isize>() > count2);
+        drop(guard2);
+    }
+
+    #[test]
+    fn into_report() {
+        let guard = ProfilerGuard::new(100).unwrap();
+        let mut bt = Vec::new();
+        backtrace::trace(|frame| {
+            bt.push(UnresolvedFrame::from(frame));
+            bt.len() < MAX_DEPTH
+        });
+        for _ in 0..10 {
+            guard.profiler.write().sample(&bt, b"manual", 1);
+        }
+        busy_for(std::time::Duration::from_millis(100));
+
+        let borrowed = guard.report().build().unwrap();
+        let builder = guard.into_report();
+        let owned = builder.build().unwrap();
+
+        let manual_count = |report: &crate::Report| {
+            report
+                .data
+                .iter()
+                .filter(|(frames, _)| frames.thread_name == "manual")
+                .map(|(_, count)| *count)
+                .sum::<isize>()
+        };
+        assert_eq!(manual_count(&borrowed), 10);
+        assert_eq!(manual_count(&owned), 10);
+        for (frames, count) in borrowed.data.iter() {
+            assert!(owned.data.get(frames).unwrap() >= count);
+        }
+
+        // the samples have been moved into the first report
+        assert!(builder.build().unwrap().data.is_empty());
+    }
+
+    // Spins until the calling thread consumed `duration` of CPU time.
+    fn burn_cpu_time(duration: std::time::Duration) {
+        let start = thread_cpu_time();
+        while thread_cpu_time() - start < duration.as_nanos() as u64 {}
+    }
+
+    #[test]
+    fn weighted_by_cpu_time() {
+        let mut bt = Vec::new();
+        backtrace::trace(|frame| {
+            bt.push(UnresolvedFrame::from(frame));
+            bt.len() < MAX_DEPTH
+        });
+
+        let unweighted = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        let weighted = RwLock::new(Profiler::new(100, Collector::new().unwrap()));
+        weighted.write().cpu_times = Some(ThreadTable::new());
+
+        // the timer fires once, then skips two ticks
+        for profiler in [&unweighted, &weighted].iter() {
+            profiler.write().sample(&bt, b"first", 1);
+        }
+        burn_cpu_time(std::time::Duration::from_millis(30));
+        for profiler in [&unweighted, &weighted].iter() {
+            profiler.write().sample(&bt[1..], b"skewed", 1);
+        }
+
+        let count = |profiler: &RwLock<Profiler>, thread_name: &str| {
+            ReportBuilder::new(profiler)
+                .build()
+                .unwrap()
+                .data
+                .iter()
+                .filter(|(frames, _)| frames.thread_name == thread_name)
+                .map(|(_, count)| *count)
+                .sum::<isize>()
+        };
+        assert_eq!(count(&unweighted, "first"), 1);
+        assert_eq!(count(&unweighted, "skewed"), 1);
+        assert_eq!(count(&weighted, "first"), 1);
+        assert_eq!(count(&weighted, "skewed"), 3);
+    }
+
+    #[test]
+    fn busy_threads() {
+        let guard = ProfilerGuard::new(100).unwrap();
+        let threads: Vec<_> = ["busy-a", "busy-b"]
+            .iter()
+            .map(|name| {
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn(|| busy_for(std::time::Duration::from_millis(500)))
+                    .unwrap()
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| {
+            thread.join().unwrap();
+        });
+
+        let threads = guard.report().build().unwrap().threads();
+        let count = |name: &str| {
+            threads
+                .iter()
+                .filter(|(_, thread_name, _)| thread_name.as_deref() == Some(name))
+                .map(|(_, _, count)| *count)
+                .sum::<usize>()
+        };
+        let (count_a, count_b) = (count("busy-a"), count("busy-b"));
+        assert!(count_a > 0 && count_b > 0);
+        assert!(count_a < count_b * 3 && count_b < count_a * 3);
+    }
+
+    #[test]
+    fn thread_name_override() {
+        let guard = ProfilerGuard::new(100).unwrap();
+        busy_for(std::time::Duration::from_millis(300));
+
+        let current_thread = unsafe { libc::pthread_self() } as u64;
+        let report = guard
+            .report()
+            .thread_name_override(move |thread_id, thread_name| {
+                if thread_id == current_thread {
+                    "http-acceptor".to_owned()
+                } else {
+                    thread_name.unwrap_or_default().to_owned()
+                }
+            })
+            .build()
+            .unwrap();
+
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded
+            .lines()
+            .any(|line| line.starts_with("http-acceptor;")));
+    }
+
+    #[test]
+    fn full_thread_names() {
+        const NAME: &str = "tokio-runtime-worker-12";
+
+        let guard = ProfilerGuard::new(1000).unwrap();
+        let thread_id = std::thread::Builder::new()
+            .name(NAME.to_owned())
+            .spawn(|| {
+                register_thread_name();
+                busy_for(Duration::from_millis(200));
+                unsafe { libc::pthread_self() as u64 }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        let profiler = guard.profiler.clone();
+        drop(guard);
+
+        let thread_names = |full_thread_names| {
+            let report = ReportBuilder::new(&profiler)
+                .full_thread_names(full_thread_names)
+                .build()
+                .unwrap();
+            report
+                .threads()
+                .into_iter()
+                .filter(|(id, _, _)| *id == thread_id)
+                .filter_map(|(_, name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        let truncated = thread_names(false);
+        assert!(!truncated.is_empty());
+        assert!(truncated
+            .iter()
+            .all(|name| name.len() < NAME.len() && NAME.starts_with(name.as_str())));
+        assert_eq!(thread_names(true), [NAME]);
+    }
+
+    #[test]
+    fn dedup_samples() {
+        let mut profiler = Profiler::new(100, Collector::in_memory());
+        profiler.last_samples = Some(ThreadTable::new());
+        let mut captures = 0;
+        let mut sample = |profiler: &mut Profiler, thread_id, registers, ip| {
+            if !profiler.sample_repeated(thread_id, registers, None) {
+                captures += 1;
+                let frames = [UnresolvedFrame {
+                    ip,
+                    symbol_address: ip,
+                }];
+                profiler.sample_at(&frames, b"", thread_id, registers, None);
+            }
+        };
+        let counts = |profiler: &Profiler| {
+            let mut counts = HashMap::new();
+            for entry in profiler.data.try_iter().unwrap() {
+                *counts.entry(entry.item.frames[0].ip).or_insert(0) += entry.count;
+            }
+            counts
+        };
+
+        // thread 1 spins in a loop, then in another, thread 2 can't be deduplicated
+        for _ in 0..10 {
+            sample(&mut profiler, 1, Some([1, 2, 3]), 0x10);
+        }
+        for _ in 0..5 {
+            sample(&mut profiler, 1, Some([4, 5, 6]), 0x20);
+            sample(&mut profiler, 2, None, 0x30);
+        }
+        sample(&mut profiler, 1, Some([4, 5, 6]), 0x20);
+        assert_eq!(captures, 2 + 5);
+        assert_eq!(profiler.sample_counter, 21);
+        // the last repeats are pending
+        assert_eq!(counts(&profiler)[&0x20], 1);
+
+        profiler.flush_repeated();
+        let expected: HashMap<usize, isize> =
+            [(0x10, 10), (0x20, 6), (0x30, 5)].iter().copied().collect();
+        assert_eq!(counts(&profiler), expected);
+        profiler.flush_repeated();
+        assert_eq!(counts(&profiler), expected);
+    }
+
+    #[test]
+    fn dedup_samples_report() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .dedup_samples(true)
+            .build()
+            .unwrap();
+        busy_for(Duration::from_millis(300));
+        let profiler = guard.profiler.clone();
+        drop(guard);
+
+        let report = ReportBuilder::new(&profiler).build().unwrap();
+        let total: isize = report.data.values().sum();
+        let profiler = profiler.read();
+        assert!(total > 0);
+        assert_eq!(
+            total as usize + report.dropped_samples,
+            profiler.sample_counter as usize
+        );
+    }
+
+    #[cfg(all(target_os = "linux", feature = "protobuf"))]
+    #[test]
+    fn record_cpu() {
+        // pins a thread to the first CPU it may run on
+        fn pin() -> u32 {
+            unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                let size = std::mem::size_of::<libc::cpu_set_t>();
+                assert_eq!(libc::sched_getaffinity(0, size, &mut set), 0);
+                let cpu = (0..libc::CPU_SETSIZE as usize)
+                    .find(|cpu| libc::CPU_ISSET(*cpu, &set))
+                    .unwrap();
+                libc::CPU_ZERO(&mut set);
+                libc::CPU_SET(cpu, &mut set);
+                assert_eq!(libc::sched_setaffinity(0, size, &set), 0);
+                cpu as u32
+            }
+        }
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .record_cpu(true)
+            .build()
+            .unwrap();
+        let (thread_id, cpu) = std::thread::spawn(|| {
+            let cpu = pin();
+            busy_for(Duration::from_millis(200));
+            (unsafe { libc::pthread_self() as u64 }, cpu)
+        })
+        .join()
+        .unwrap();
+        let report = guard.report().build().unwrap();
+
+        let cpus: Vec<_> = report
+            .data
+            .keys()
+            .filter(|frames| frames.thread_id == thread_id)
+            .map(|frames| frames.cpu)
+            .collect();
+        assert!(!cpus.is_empty());
+        // it may still have been sampled before being pinned
+        assert!(cpus.contains(&Some(cpu)));
+
+        let profile = report.to_pprof_profile();
+        let cpu_key = profile
+            .string_table
+            .iter()
+            .position(|string| string == "cpu")
+            .unwrap() as i64;
+        assert!(profile.sample.iter().any(|sample| sample
+            .label
+            .iter()
+            .any(|label| label.key == cpu_key && label.num == i64::from(cpu))));
+    }
+
+    #[test]
+    fn in_memory() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .in_memory(true)
+            .build()
+            .unwrap();
+        busy_for(std::time::Duration::from_millis(200));
+
+        let report = guard.report().build().unwrap();
+        assert!(!report.data.is_empty());
+    }
+
+    #[test]
+    fn collector_config() {
+        let spill_dir = tempfile::tempdir().unwrap();
+        let config = CollectorConfig {
+            buckets: 64,
+            associativity: 2,
+            buffer_len: 16,
+            spill_dir: Some(spill_dir.path().to_owned()),
+        };
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .with_collector_config(config.clone())
+            .build()
+            .unwrap();
+        assert_eq!(guard.profiler.read().data.config(), &config);
+        assert_eq!(std::fs::read_dir(spill_dir.path()).unwrap().count(), 1);
+
+        guard.tick();
+        let report = guard.report().build().unwrap();
+        assert_eq!(report.data.values().sum::<isize>(), 1);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn sample_labeler() {
+        let guard = ProfilerGuard::new(1000).unwrap();
+        busy_for(std::time::Duration::from_millis(200));
+
+        let report = guard
+            .report()
+            .sample_labeler(|frames| vec![("thread".to_owned(), frames.thread_id.to_string())])
+            .build()
+            .unwrap();
+        let profile = report.pprof().unwrap();
+        assert!(!profile.sample.is_empty());
+
+        let current_thread = unsafe { libc::pthread_self() } as u64;
+        let string = |index: i64| profile.string_table[index as usize].as_str();
+        let mut labelled_current = false;
+        for sample in profile.sample.iter() {
+            assert_eq!(sample.label.len(), 1);
+            assert_eq!(string(sample.label[0].key), "thread");
+            labelled_current |= string(sample.label[0].str) == current_thread.to_string();
+        }
+        assert!(labelled_current);
+    }
+
+    #[test]
+    fn unwinders() {
+        let unwinders = [
+            Unwinder::Backtrace,
+            Unwinder::FramePointer,
+            Unwinder::LibUnwind,
+        ];
+        for unwinder in unwinders.iter().copied() {
+            let guard = ProfilerGuardBuilder::default()
+                .frequency(1000)
+                .unwinder(unwinder);
+            if !unwinder.is_supported() {
+                assert!(matches!(guard.build(), Err(Error::UnsupportedUnwinder(_))));
+                continue;
+            }
+
+            let guard = guard.build().unwrap();
+            busy_for(std::time::Duration::from_millis(200));
+            let report = guard.report().build().unwrap();
+            let hot = report.data.keys().any(|frames| {
+                frames.frames.iter().flatten().any(|symbol| {
+                    let name = symbol.name();
+                    name.contains("is_prime_number") || name.contains("busy_for")
+                })
+            });
+            assert!(hot, "{:?} didn't capture the hot frame", unwinder);
+        }
+    }
+
+    #[test]
+    fn injected_collector() {
+        let mut bt = Vec::new();
+        backtrace::trace(|frame| {
+            bt.push(UnresolvedFrame::from(frame));
+            bt.len() < MAX_DEPTH
+        });
+        let mut collector = Collector::in_memory();
+        collector
+            .add(UnresolvedFrames::new(&bt, b"injected", 1), 5)
+            .unwrap();
+
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .collector(collector)
+            .build()
+            .unwrap();
+        busy_for(std::time::Duration::from_millis(200));
+        let report = guard.report().build().unwrap();
+
+        let injected: isize = report
+            .data
+            .iter()
+            .filter(|(frames, _)| frames.thread_name == "injected")
+            .map(|(_, count)| *count)
+            .sum();
+        assert_eq!(injected, 5);
+        assert!(report
+            .data
+            .keys()
+            .any(|frames| frames.thread_name != "injected"));
+    }
+
+    #[test]
+    fn eviction_policy() {
+        use crate::{LeastRecentlyUsed, MinCount};
+
+        let config = CollectorConfig {
+            buckets: 1,
+            associativity: 2,
+            ..CollectorConfig::default()
+        };
+        let builder = CollectorBuilder::default().in_memory(true).config(config);
+        let frame = UnresolvedFrame {
+            ip: eviction_policy as *const () as usize + 1,
+            symbol_address: eviction_policy as *const () as usize,
+        };
+        // the threads still in the bucket, after the hot one was sampled first
+        let kept = |mut profiler: Profiler| {
+            for _ in 0..10 {
+                profiler.sample(&[frame], b"hot", 1);
+            }
+            profiler.sample(&[frame], b"cold", 2);
+            profiler.sample(&[frame], b"new", 3);
+            let entries = profiler.data.try_iter().unwrap();
+            let mut kept: Vec<u64> = entries.take(2).map(|entry| entry.item.thread_id).collect();
+            kept.sort_unstable();
+            kept
+        };
+
+        let min_count = builder.clone().policy(MinCount).build().unwrap();
+        assert_eq!(kept(Profiler::new(100, min_count)), [1, 3]);
+        let lru = builder.policy(LeastRecentlyUsed).build().unwrap();
+        assert_eq!(kept(Profiler::new(100, lru)), [2, 3]);
+    }
+
+    #[test]
+    fn merged_threads() {
+        let guard = ProfilerGuard::new(1000).unwrap();
+        let threads: Vec<_> = ["worker-a", "worker-b"]
+            .iter()
+            .map(|name| {
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn(|| busy_for(std::time::Duration::from_millis(300)))
+                    .unwrap()
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| {
+            thread.join().unwrap();
+        });
+        // stop sampling, so that both reports are built from the same samples
+        let profiler = guard.profiler.clone();
+        drop(guard);
+
+        let split = ReportBuilder::new(&profiler).build().unwrap();
+        let merged = ReportBuilder::new(&profiler)
+            .split_by_thread(false)
+            .build()
+            .unwrap();
+
+        let mut expected: std::collections::HashMap<_, (isize, usize)> = Default::default();
+        for (frames, count) in split.data.iter() {
+            let entry = expected.entry(&frames.frames).or_insert((0, 0));
+            entry.0 += count;
+            entry.1 += 1;
+        }
+        // the workers ran the same function, so some of their stacks must be identical
+        assert!(expected.values().any(|(_, threads)| *threads > 1));
+
+        assert_eq!(merged.data.len(), expected.len());
+        for (frames, count) in merged.data.iter() {
+            assert_eq!(frames.thread_id, 0);
+            assert_eq!(expected[&frames.frames].0, *count);
+        }
+    }
+
+    #[test]
+    fn overhead() {
+        let start = Instant::now();
+        let guard = ProfilerGuard::new(100).unwrap();
+        busy_for(Duration::from_millis(500));
+        let overhead = guard.overhead();
+        let elapsed = start.elapsed();
+
+        assert!(overhead > Duration::from_secs(0));
+        assert!(overhead < elapsed / 4, "{:?} of {:?}", overhead, elapsed);
+        let report = guard.report().build().unwrap();
+        assert!(report.timing.overhead >= overhead);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn dump_pprof() {
+        use crate::protos::{self, Message};
+
+        let guard = ProfilerGuard::new(1000).unwrap();
+        busy_for(Duration::from_millis(200));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profile.pb");
+        guard.dump_pprof(&path).unwrap();
+
+        let content = std::fs::read(&path).unwrap();
+        let profile = protos::Profile::decode(content.as_slice()).unwrap();
+        assert!(!profile.sample.is_empty());
+        // the temp file was renamed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn thread_lifetimes() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .thread_lifetimes(true)
+            .build()
+            .unwrap();
+        let thread_id = std::thread::spawn(|| {
+            busy_for(Duration::from_millis(200));
+            unsafe { libc::pthread_self() as u64 }
+        })
+        .join()
+        .unwrap();
+        let exited = Instant::now();
+        busy_for(Duration::from_millis(200));
+
+        let report = guard.report().build().unwrap();
+        let lifetimes = report.thread_lifetimes();
+        let (_, first, last) = lifetimes
+            .iter()
+            .find(|(id, _, _)| *id == thread_id)
+            .unwrap();
+        assert!(first < last);
+        assert!(*last <= exited);
+        assert!(lifetimes.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    }
+
+    #[test]
+    fn ignore_frames_matching() {
+        let guard = ProfilerGuard::new(1000).unwrap();
+        busy_for(Duration::from_millis(300));
+        let profiler = guard.profiler.clone();
+        drop(guard);
+
+        let regex = regex::Regex::new(r"::busy_for(::h[0-9a-f]{16})?$").unwrap();
+        let unfiltered = ReportBuilder::new(&profiler).build().unwrap();
+        let filtered = ReportBuilder::new(&profiler)
+            .ignore_frames_matching(regex.clone())
+            .build()
+            .unwrap();
+
+        let mut expected: std::collections::HashMap<crate::Frames, isize> = Default::default();
+        for (frames, count) in unfiltered.data.iter() {
+            let mut frames = frames.clone();
+            frames.retain_symbols(|symbol| !regex.is_match(&symbol.name()));
+            *expected.entry(frames).or_insert(0) += count;
+        }
+        assert!(unfiltered.data.keys().any(|frames| frames
+            .frames
+            .iter()
+            .flatten()
+            .any(|symbol| regex.is_match(&symbol.name()))));
+        // the callers and callees of the ignored frame are adjacent, the stacks which became
+        // identical are merged
+        assert_eq!(filtered.data, expected);
+
+        let mut folded = Vec::new();
+        filtered.write_folded(&mut folded).unwrap();
+        assert!(!String::from_utf8(folded).unwrap().contains("busy_for"));
+    }
+
+    #[test]
+    fn deferred_sample() {
+        let profiler = Arc::new(RwLock::new(Profiler::new(100, Collector::new().unwrap())));
+        let registered = Registered::new(profiler.clone());
+        let sample = || {
+            let mut captured = [None; Unwinder::COUNT];
+            let start = monotonic_time();
+            registered.sample(start, 100, std::ptr::null_mut(), b"main", 1, &mut captured);
+        };
+
+        // as if the signal interrupted the thread in the middle of an `add`
+        let locked = profiler.write();
+        sample();
+        drop(locked);
+        assert_eq!(profiler.read().data.try_iter().unwrap().count(), 0);
+        assert_eq!(registered.deferred.lock().len(), 1);
+
+        // the deferred sample is merged with the next one
+        sample();
+        assert!(registered.deferred.lock().is_empty());
+        let profiler = profiler.read();
+        let count: isize = profiler
+            .data
+            .try_iter()
+            .unwrap()
+            .map(|entry| entry.count)
+            .sum();
+        assert_eq!(count, 2);
+        assert_eq!(profiler.sample_counter, 2);
+    }
+
+    #[cfg(feature = "flamegraph")]
+    #[test]
+    fn profile_test() {
+        let count = crate::profile_test!("profiler_self_test", {
+            busy_for(Duration::from_millis(200))
+        });
+        assert!(count > 0);
+
+        let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+        let path = Path::new(&target).join("pprof/profiler_self_test.svg");
+        let svg = std::fs::read_to_string(path).unwrap();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("busy_for"));
+    }
+
+    #[test]
+    fn lock_deadline() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .lock_deadline(Duration::from_micros(50))
+            .build()
+            .unwrap();
+
+        // another thread holds the lock long enough for the deferred samples to fill up
+        let (locked, holding) = std::sync::mpsc::channel();
+        let profiler = guard.profiler.clone();
+        let holder = std::thread::spawn(move || {
+            let _locked = profiler.write();
+            locked.send(()).unwrap();
+            std::thread::sleep(Duration::from_secs(1));
         });
+        holding.recv().unwrap();
+        let start = Instant::now();
+        busy_for(Duration::from_millis(200));
+        // the sampled thread didn't wait for the lock to be released
+        assert!(start.elapsed() < Duration::from_secs(1));
+        holder.join().unwrap();
+
+        guard.flush();
+        let report = guard.report().build().unwrap();
+        assert!(report.dropped_samples > 0);
+        let profiler = guard.profiler.read();
+        let count: isize = profiler
+            .data
+            .try_iter()
+            .unwrap()
+            .map(|entry| entry.count)
+            .sum();
+        assert_eq!(
+            count as usize + profiler.dropped_sample_counter,
+            profiler.sample_counter as usize
+        );
+    }
+
+    // reads from /dev/urandom, where the time is spent generating the bytes in the kernel
+    #[inline(never)]
+    fn syscall_heavy(duration: Duration) {
+        let mut file = std::fs::File::open("/dev/urandom").unwrap();
+        let mut buffer = vec![0_u8; 1 << 20];
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            std::io::Read::read_exact(&mut file, &mut buffer).unwrap();
+        }
+    }
+
+    #[test]
+    fn user_time() {
+        // the share of the samples of this thread taken in `syscall_heavy`, for a workload
+        // spending half of its time in it
+        let syscall_share = |kind| {
+            let guard = ProfilerGuardBuilder::default()
+                .frequency(1000)
+                .timer_kind(kind)
+                .build()
+                .unwrap();
+            for _ in 0..3 {
+                syscall_heavy(Duration::from_millis(100));
+                busy_for(Duration::from_millis(100));
+            }
+            let report = guard.report().build().unwrap();
+
+            let current_thread = unsafe { libc::pthread_self() } as u64;
+            let (mut syscall, mut total) = (0, 0);
+            for (frames, count) in report.data.iter() {
+                if frames.thread_id != current_thread {
+                    continue;
+                }
+                total += count;
+                let mut names = frames.frames.iter().flatten().map(Symbol::name);
+                if names.any(|name| name.contains("syscall_heavy")) {
+                    syscall += count;
+                }
+            }
+            syscall as f64 / total as f64
+        };
+
+        let cpu_share = syscall_share(TimerKind::CpuTime);
+        let user_share = syscall_share(TimerKind::UserTime);
+        assert!(cpu_share > 0.3, "{}", cpu_share);
+        // the kernel splits the CPU time between user and kernel mode at its ticks, so some
+        // samples are still taken in `syscall_heavy`
+        assert!(user_share < cpu_share, "{} and {}", user_share, cpu_share);
+    }
+
+    #[inline(never)]
+    fn ticked_first(guard: &ProfilerGuard, ticks: usize) {
+        for _ in 0..ticks {
+            guard.tick();
+        }
+    }
+
+    #[inline(never)]
+    fn ticked_second(guard: &ProfilerGuard, ticks: usize) {
+        for _ in 0..ticks {
+            guard.tick();
+        }
+    }
+
+    #[inline(never)]
+    fn ticked_nested(guard: &ProfilerGuard, depth: usize) -> usize {
+        if depth == 0 {
+            guard.tick();
+            0
+        } else {
+            // not a tail call, so that the frame stays on the stack
+            let nested = ticked_nested(guard, depth - 1);
+            unsafe { std::ptr::read_volatile(&nested) + 1 }
+        }
+    }
+
+    #[test]
+    fn truncated_stacks() {
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .build()
+            .unwrap();
+        ticked_nested(&guard, 0);
+        ticked_nested(&guard, MAX_DEPTH);
+        ticked_nested(&guard, MAX_DEPTH);
+
+        let report = guard.report().build().unwrap();
+        assert_eq!(report.truncated_samples, 2, "{:?}", report);
+
+        let report = guard.report().mark_truncated_stacks(true).build().unwrap();
+        let root = |frames: &crate::Frames| frames.frames.last().unwrap()[0].name();
+        let marked: isize = report
+            .data
+            .iter()
+            .filter(|(frames, _)| root(frames) == "[truncated]")
+            .map(|(_, count)| *count)
+            .sum();
+        assert_eq!(marked, 2);
+        assert_eq!(report.data.values().sum::<isize>(), 3);
+    }
+
+    #[test]
+    fn manual_timer() {
+        let manual = |frequency| {
+            ProfilerGuardBuilder::default()
+                .frequency(frequency)
+                .timer_kind(TimerKind::Manual)
+                .build()
+                .unwrap()
+        };
+        let guard = manual(100);
+        // the ticks of a guard are not sampled by the other ones, whatever their frequency
+        let other = manual(50);
+        ticked_first(&guard, 6);
+        ticked_second(&guard, 4);
+        ticked_first(&other, 5);
+
+        let current_thread = unsafe { libc::pthread_self() } as u64;
+        let ticked = |report: &crate::Report, name: &str| {
+            report
+                .data
+                .iter()
+                .filter(|(frames, _)| frames.thread_id == current_thread)
+                .filter(|(frames, _)| {
+                    let mut names = frames.frames.iter().flatten().map(Symbol::name);
+                    names.any(|frame| frame.contains(name))
+                })
+                .map(|(_, count)| *count)
+                .sum::<isize>()
+        };
+        let report = guard.report().build().unwrap();
+        assert_eq!(ticked(&report, "ticked_first"), 6);
+        assert_eq!(ticked(&report, "ticked_second"), 4);
+        assert_eq!(report.data.values().sum::<isize>(), 10);
+        let report = other.report().build().unwrap();
+        assert_eq!(ticked(&report, "ticked_first"), 5);
+        assert_eq!(report.data.values().sum::<isize>(), 5);
+    }
+
+    #[test]
+    fn manual_signal_restored() {
+        // the disposition of `SIGUSR2` is process-wide, so this runs itself again in a child
+        // process, where no other manual profiler runs meanwhile
+        if std::env::var_os("PPROF_TEST_MANUAL_SIGNAL").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "profiler::tests::manual_signal_restored"])
+                .env("PPROF_TEST_MANUAL_SIGNAL", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn received(_: c_int) {
+            RECEIVED.fetch_add(1, Ordering::Relaxed);
+        }
+        let handler = signal::SigHandler::Handler(received);
+        unsafe { signal::signal(TimerKind::Manual.signal(), handler) }.unwrap();
+
+        let guard = ProfilerGuardBuilder::default()
+            .timer_kind(TimerKind::Manual)
+            .build()
+            .unwrap();
+        guard.tick();
+        // not raised by `tick`, it is for no profiler
+        signal::raise(TimerKind::Manual.signal()).unwrap();
+        assert_eq!(
+            guard.report().build().unwrap().data.values().sum::<isize>(),
+            1
+        );
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 0);
+        drop(guard);
+
+        signal::raise(TimerKind::Manual.signal()).unwrap();
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn sleep_phase(duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    // the CPU time rather than the wall-clock time, which other tests may take
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn busy_phase(duration: Duration) {
+        burn_cpu_time(duration);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wall_time() {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(100)
+            .per_thread_timers(true)
+            .wall_time(true)
+            .build()
+            .unwrap();
+        assert!(ProfilerGuard::new(100).unwrap().wall_report().is_none());
+        for _ in 0..4 {
+            busy_phase(Duration::from_millis(50));
+            sleep_phase(Duration::from_millis(50));
+        }
+
+        let current_thread = unsafe { libc::pthread_self() } as u64;
+        let phases = |report: crate::Report| {
+            let (mut busy, mut sleeping) = (0, 0);
+            for (frames, count) in report.data.iter() {
+                if frames.thread_id != current_thread {
+                    continue;
+                }
+                let mut names = frames.frames.iter().flatten().map(Symbol::name);
+                match names.find(|name| name.contains("busy_phase") || name.contains("sleep_phase"))
+                {
+                    Some(name) if name.contains("busy_phase") => busy += count,
+                    Some(_) => sleeping += count,
+                    None => {}
+                }
+            }
+            (busy, sleeping)
+        };
+        let (cpu_busy, cpu_sleeping) = phases(guard.report().build().unwrap());
+        let (wall_busy, wall_sleeping) = phases(guard.wall_report().unwrap().build().unwrap());
+
+        // about 20 samples of each phase on the wall clock, the sleep takes no CPU time
+        assert!(
+            cpu_busy > 0 && cpu_sleeping * 10 <= cpu_busy,
+            "{} {}",
+            cpu_busy,
+            cpu_sleeping
+        );
+        assert!(
+            wall_busy > 0 && wall_sleeping >= 10,
+            "{} {}",
+            wall_busy,
+            wall_sleeping
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn per_thread_timers() {
+        use std::sync::Barrier;
+
+        // the handler drops the samples taken while another test registers a profiler, so this
+        // runs itself again in a child process
+        if std::env::var_os("PPROF_TEST_PER_THREAD_TIMERS").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "profiler::tests::per_thread_timers"])
+                .env("PPROF_TEST_PER_THREAD_TIMERS", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let barrier = Arc::new(Barrier::new(3));
+        let busy_thread = || {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                // the same CPU time each, however they are scheduled
+                let start = thread_cpu_time();
+                while thread_cpu_time() - start < 250_000_000 {
+                    busy_for(Duration::from_millis(1));
+                }
+                (unsafe { libc::pthread_self() }) as u64
+            })
+        };
+        let first = busy_thread();
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(200)
+            .per_thread_timers(true)
+            .build()
+            .unwrap();
+        // started after the timers were created
+        let second = busy_thread();
+        guard.refresh_threads();
+        barrier.wait();
+        let (first, second) = (first.join().unwrap(), second.join().unwrap());
+
+        let report = guard.report().build().unwrap();
+        let samples = |thread_id: u64| {
+            report
+                .data
+                .iter()
+                .filter(|(frames, _)| frames.thread_id == thread_id)
+                .map(|(_, count)| *count)
+                .sum::<isize>()
+        };
+        let (first, second) = (samples(first), samples(second));
+        // 50 samples each for 250ms at 200 Hz, as each timer counts the CPU time of its thread
+        assert!(first > 0 && second > 0, "{} {}", first, second);
+        assert!(
+            (first - second).abs() * 4 <= first.max(second),
+            "{} {}",
+            first,
+            second
+        );
     }
 }
@@ -1,41 +1,652 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use parking_lot::RwLock;
+use regex::Regex;
 
-use crate::frames::{Frames, UnresolvedFrames};
-use crate::profiler::Profiler;
+use crate::frames::{
+    Frames, KeepFrames, StackOrder, Symbol, SymbolDisplay, SymbolInterner, UnresolvedFrames,
+};
+use crate::profiler::{full_thread_name, Profiler, ThreadState};
+use crate::timer::ReportTiming;
 
 use crate::{Error, Result};
 
+// bounds on untrusted folded input, so a malformed line can't make us allocate without limit
+const MAX_FOLDED_LINE: usize = 64 * 1024;
+const MAX_FOLDED_DEPTH: usize = 1024;
+
 /// The final presentation of a report which is actually an `HashMap` from `Frames` to isize (count).
 pub struct Report {
     /// key is a backtrace captured by profiler and value is count of it.
     pub data: HashMap<Frames, isize>,
+
+    /// timing information (frequency, start time and duration) of the collection.
+    pub timing: ReportTiming,
+
+    /// count of samples dropped because their stack was empty, either at collection or after
+    /// resolving. A large number usually means the unwinder is failing. The samples dropped by
+    /// a collector with bounded spills are counted too, see `Collector::with_max_spill_bytes`.
+    pub dropped_samples: usize,
+
+    /// count of samples whose stack was deeper than `MAX_DEPTH`, so that its outermost frames
+    /// were left out, see `ReportBuilder::mark_truncated_stacks`.
+    pub truncated_samples: usize,
+
+    /// key/value labels given by `ReportBuilder::sample_labeler` to the backtraces in `data`.
+    /// They are written into the pprof samples.
+    pub labels: HashMap<Frames, Vec<(String, String)>>,
+
+    /// regexes written into the pprof `drop_frames` and `keep_frames` fields, see
+    /// `ReportBuilder::pprof_drop_frames` and `ReportBuilder::pprof_keep_frames`.
+    pub pprof_drop_frames: Option<String>,
+    pub pprof_keep_frames: Option<String>,
+
+    /// whether the pprof locations carry the sampled addresses, see
+    /// `ReportBuilder::pprof_addresses`.
+    pub pprof_addresses: bool,
+
+    /// key/value metadata given by `ReportBuilder::metadata`, e.g. the git commit or the host.
+    pub metadata: BTreeMap<String, String>,
+
+    /// the scheduler state of every sampled thread id when the report was built, if recorded,
+    /// see `ProfilerGuardBuilder::thread_states`. The state of every sample is in its key,
+    /// `Frames::state`.
+    pub thread_states: Vec<(u64, ThreadState)>,
+}
+
+impl Report {
+    /// The sample frequency this report was collected at.
+    pub fn frequency(&self) -> i32 {
+        self.timing.frequency
+    }
+
+    /// The time represented by a single sample, derived from the frequency.
+    pub fn period(&self) -> Duration {
+        self.timing.period()
+    }
+
+    /// Whether this report has no samples, i.e. no stack was sampled at least once. An empty
+    /// report is still exported: an svg notice in place of the flamegraph, an empty pprof
+    /// profile, and no folded stacks.
+    pub fn is_empty(&self) -> bool {
+        self.data.values().all(|count| *count <= 0)
+    }
+
+    /// Iterates over the distinct stacks of this report and their sample counts, in no particular
+    /// order.
+    pub fn iter(&self) -> ReportIter<'_> {
+        ReportIter {
+            inner: self.data.iter(),
+        }
+    }
+
+    /// The metadata attached with `ReportBuilder::metadata`.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    // The metadata as `key=value` strings, ordered by key.
+    #[cfg(any(feature = "flamegraph", feature = "protobuf"))]
+    fn metadata_strings(&self) -> impl Iterator<Item = String> + '_ {
+        self.metadata
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+    }
+
+    /// The thread ids with the times of their first and last samples, sorted by first sample,
+    /// e.g. to show thread lifetimes on a timeline. It is empty unless they were recorded, see
+    /// `ProfilerGuardBuilder::thread_lifetimes`.
+    pub fn thread_lifetimes(&self) -> Vec<(u64, Instant, Instant)> {
+        let mut lifetimes = self.timing.thread_lifetimes.clone();
+        lifetimes.sort_by_key(|(_, first, _)| *first);
+
+        lifetimes
+    }
+
+    /// Counts the samples of every adjacent `(caller, callee)` pair of symbols over all stacks,
+    /// inlined functions included, as needed to build call graphs.
+    pub fn call_edges(&self) -> HashMap<(Symbol, Symbol), usize> {
+        let mut edges = HashMap::new();
+        for (key, count) in self.data.iter() {
+            let symbols = key.symbols(StackOrder::LeafFirst);
+            for pair in symbols.windows(2) {
+                let (callee, caller) = (pair[0], pair[1]);
+                *edges.entry((caller.clone(), callee.clone())).or_insert(0) += *count as usize;
+            }
+        }
+
+        edges
+    }
+
+    /// Lists every thread seen in this report as `(thread_id, thread_name, sample count)`, busiest
+    /// thread first. A thread renamed while being profiled is listed once per name it had.
+    pub fn threads(&self) -> Vec<(u64, Option<String>, usize)> {
+        let mut threads: HashMap<(u64, &str), usize> = HashMap::new();
+        for (key, count) in self.data.iter() {
+            *threads
+                .entry((key.thread_id, key.thread_name.as_str()))
+                .or_insert(0) += *count as usize;
+        }
+
+        let mut threads: Vec<_> = threads
+            .into_iter()
+            .map(|((thread_id, thread_name), count)| {
+                let thread_name = if thread_name.is_empty() {
+                    None
+                } else {
+                    Some(thread_name.to_owned())
+                };
+                (thread_id, thread_name, count)
+            })
+            .collect();
+        threads.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+
+        threads
+    }
+
+    /// Aggregates the samples by the source line of their leaf symbol as
+    /// `(filename, line, sample count)`, hottest line first, e.g. to annotate source with how hot
+    /// each line is. Leaf symbols without a filename or a line number, e.g. when built without
+    /// debug info, are counted together as `(None, None, count)`.
+    pub fn hot_lines(&self) -> Vec<(Option<PathBuf>, Option<u32>, usize)> {
+        let mut lines: HashMap<(Option<&Path>, Option<u32>), usize> = HashMap::new();
+        for (key, count) in self.data.iter() {
+            let leaf = match key.frames.first().and_then(|frame| frame.first()) {
+                Some(leaf) => leaf,
+                None => continue,
+            };
+            let line = match (&leaf.filename, leaf.lineno) {
+                (Some(filename), Some(lineno)) => (Some(&**filename), Some(lineno)),
+                _ => (None, None),
+            };
+            *lines.entry(line).or_insert(0) += *count as usize;
+        }
+
+        let mut lines: Vec<_> = lines
+            .into_iter()
+            .map(|((filename, line), count)| (filename.map(Path::to_path_buf), line, count))
+            .collect();
+        lines.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, a.1).cmp(&(&b.0, b.1))));
+
+        lines
+    }
+
+    /// The Wilson score interval of the fraction of all samples the true share of `frames` lies
+    /// in, at the given `confidence` level, e.g. `0.95`, to judge whether a difference between
+    /// two reports is significant or sampling noise. A report without samples, or a confidence of
+    /// 1 or more, gives `(0.0, 1.0)`, and a confidence of 0 or less the observed fraction.
+    pub fn confidence_interval(&self, frames: &Frames, confidence: f64) -> (f64, f64) {
+        let total: isize = self.data.values().sum();
+        if total <= 0 || confidence >= 1.0 {
+            return (0.0, 1.0);
+        }
+        let n = total as f64;
+        let p = self.data.get(frames).copied().unwrap_or(0) as f64 / n;
+        if confidence <= 0.0 {
+            return (p, p);
+        }
+
+        let z = normal_quantile(1.0 - (1.0 - confidence) / 2.0);
+        let z2 = z * z;
+        let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+        let margin = z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+
+        ((center - margin).max(0.0), (center + margin).min(1.0))
+    }
+
+    /// The samples per second of every stack over the duration of this report, so that reports
+    /// of different durations compare. Multiplied by `period`, a rate is the CPU time the stack
+    /// took per second, e.g. 0.5 for half a core. It is empty if the duration is unknown, e.g. for
+    /// a report parsed from folded stacks.
+    pub fn to_rates(&self) -> HashMap<Frames, f64> {
+        let seconds = self.timing.duration.as_secs_f64();
+        if seconds <= 0.0 {
+            return HashMap::new();
+        }
+
+        self.data
+            .iter()
+            .map(|(frames, count)| (frames.clone(), *count as f64 / seconds))
+            .collect()
+    }
+
+    /// Formats the `top_n` hottest functions of this report as an aligned text table, like
+    /// `go tool pprof -top`, e.g. to print to a terminal. A row per function gives its self
+    /// (`flat`) and inclusive (`cum`) sample counts with their share of all the samples, and the
+    /// running total of the flat shares (`sum%`). The rows are sorted by flat count.
+    pub fn text_table(&self, top_n: usize) -> String {
+        let total: isize = self.data.values().sum();
+        let (self_counts, inclusive_counts) = self.function_counts();
+        let mut rows: Vec<(&String, isize, isize)> = inclusive_counts
+            .iter()
+            .map(|(name, cum)| (name, self_counts.get(name).copied().unwrap_or(0), *cum))
+            .collect();
+        rows.sort_by(|a, b| (b.1, b.2).cmp(&(a.1, a.2)).then(a.0.cmp(b.0)));
+
+        let percent = |count: isize| {
+            if total > 0 {
+                count as f64 * 100.0 / total as f64
+            } else {
+                0.0
+            }
+        };
+        let width = total.to_string().len().max("flat".len());
+
+        let mut table = format!(
+            "Showing top {} functions out of {}, {} samples in total\n",
+            top_n.min(rows.len()),
+            rows.len(),
+            total
+        );
+        table.push_str(&format!(
+            "{:>width$} {:>7} {:>7} {:>width$} {:>7}\n",
+            "flat",
+            "flat%",
+            "sum%",
+            "cum",
+            "cum%",
+            width = width
+        ));
+        let mut sum = 0;
+        for (name, flat, cum) in rows.into_iter().take(top_n) {
+            sum += flat;
+            table.push_str(&format!(
+                "{:>width$} {:>6.2}% {:>6.2}% {:>width$} {:>6.2}%  {}\n",
+                flat,
+                percent(flat),
+                percent(sum),
+                cum,
+                percent(cum),
+                name,
+                width = width
+            ));
+        }
+
+        table
+    }
+
+    // The self and inclusive sample counts of every function, by demangled name. A recursive
+    // function is only counted once per stack.
+    fn function_counts(&self) -> (HashMap<String, isize>, HashMap<String, isize>) {
+        let mut self_counts: HashMap<String, isize> = HashMap::new();
+        let mut inclusive_counts: HashMap<String, isize> = HashMap::new();
+        for (key, count) in self.data.iter() {
+            let names: Vec<String> = key
+                .symbols(StackOrder::LeafFirst)
+                .into_iter()
+                .map(Symbol::name)
+                .collect();
+            if let Some(leaf) = names.first() {
+                *self_counts.entry(leaf.clone()).or_insert(0) += count;
+            }
+            let unique: HashSet<&String> = names.iter().collect();
+            for name in unique {
+                *inclusive_counts.entry(name.clone()).or_insert(0) += count;
+            }
+        }
+
+        (self_counts, inclusive_counts)
+    }
+
+    /// Writes this report as folded stacks, one `stack count` line per distinct stack. The
+    /// thread (its name, or id when unnamed) comes first, then the frames from the root to the
+    /// leaf, separated by `;`. This is the input `flamegraph` renders and `from_folded` parses.
+    pub fn write_folded<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        for line in self.folded_lines() {
+            writeln!(writer, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the folded stacks written by `write_folded`, with the lines sorted so that the
+    /// result is stable, e.g. for snapshot tests.
+    pub fn to_collapsed_string(&self) -> String {
+        let mut folded = Vec::new();
+        // writing to a `Vec` can't fail
+        self.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8_lossy(&folded);
+
+        let mut lines: Vec<&str> = folded.lines().collect();
+        lines.sort_unstable();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    fn folded_lines(&self) -> Vec<String> {
+        self.data
+            .iter()
+            .map(|(key, value)| {
+                let mut line = String::new();
+                if !key.thread_name.is_empty() {
+                    line.push_str(&key.thread_name);
+                } else {
+                    line.push_str(&format!("{:?}", key.thread_id));
+                }
+                line.push(';');
+
+                for symbol in key.symbols(StackOrder::RootFirst) {
+                    line.push_str(&format!("{}", symbol));
+                    line.push(';');
+                }
+
+                line.pop().unwrap_or_default();
+                line.push_str(&format!(" {}", value));
+
+                line
+            })
+            .collect()
+    }
+
+    /// A report of the subtrees of the frames whose name contains `frame_name`, like the pprof
+    /// focus: every stack through such a frame is trimmed to start at it, at its outermost
+    /// occurrence if it recurses, and the other stacks are left out. The stacks which become
+    /// identical once trimmed are merged, so the counts of the subtrees are preserved.
+    pub fn focus(&self, frame_name: &str) -> Report {
+        self.map_stacks(|frames| frames.focus(frame_name))
+    }
+
+    /// A report without the symbols whose name matches `regex`, like the pprof `-hide` option:
+    /// their callers and callees become adjacent, and the stacks which become identical are
+    /// merged. A stack left empty is counted in `dropped_samples`.
+    pub fn hide(&self, regex: &Regex) -> Report {
+        self.map_stacks(|frames| {
+            frames.retain_symbols(|symbol| !regex.is_match(&symbol.name()));
+            true
+        })
+    }
+
+    /// A report without the callees of the symbols whose name matches `regex`, like the pprof
+    /// `-prune_from` option: every stack through such a symbol ends at its outermost occurrence,
+    /// its callers are kept. The stacks which become identical are merged.
+    pub fn prune_from(&self, regex: &Regex) -> Report {
+        self.map_stacks(|frames| {
+            frames.prune_from(|symbol| regex.is_match(&symbol.name()));
+            true
+        })
+    }
+
+    // A report of the stacks `map` is true for, once changed by it. The stacks which become
+    // identical are merged, with the labels of the first of them, and those left empty are
+    // counted in `dropped_samples`.
+    fn map_stacks<F: FnMut(&mut Frames) -> bool>(&self, mut map: F) -> Report {
+        let mut data = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut dropped_samples = self.dropped_samples;
+        for (frames, count) in self.data.iter() {
+            let mut mapped = frames.clone();
+            if !map(&mut mapped) {
+                continue;
+            }
+            if mapped.is_empty() {
+                dropped_samples += (*count).max(0) as usize;
+                continue;
+            }
+            if let Some(frame_labels) = self.labels.get(frames) {
+                labels
+                    .entry(mapped.clone())
+                    .or_insert_with(|| frame_labels.clone());
+            }
+            *data.entry(mapped).or_insert(0) += count;
+        }
+
+        Report {
+            data,
+            timing: self.timing.clone(),
+            dropped_samples,
+            truncated_samples: self.truncated_samples,
+            labels,
+            pprof_drop_frames: self.pprof_drop_frames.clone(),
+            pprof_keep_frames: self.pprof_keep_frames.clone(),
+            pprof_addresses: self.pprof_addresses,
+            metadata: self.metadata.clone(),
+            thread_states: self.thread_states.clone(),
+        }
+    }
+
+    /// Parses a report from folded stacks, as written by `write_folded`.
+    ///
+    /// The input is treated as untrusted: an empty line, a missing or non-positive count, an
+    /// overflowing total, or an overlong line or stack returns `Error::MalformedInput` instead of
+    /// panicking. The parsed report has default `timing`.
+    pub fn from_folded(input: &str) -> Result<Report> {
+        let mut data: HashMap<Frames, isize> = HashMap::new();
+        let mut interner = SymbolInterner::default();
+        let mut total: isize = 0;
+
+        for (index, line) in input.lines().enumerate() {
+            let malformed = |reason| Error::MalformedInput(index + 1, reason);
+
+            if line.len() > MAX_FOLDED_LINE {
+                return Err(malformed("line too long"));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                return Err(malformed("empty line"));
+            }
+
+            let (stack, count) = line
+                .rsplit_once(' ')
+                .ok_or_else(|| malformed("missing count"))?;
+            let count: isize = count.parse().map_err(|_| malformed("invalid count"))?;
+            if count <= 0 {
+                return Err(malformed("count must be positive"));
+            }
+            total = total
+                .checked_add(count)
+                .ok_or_else(|| malformed("count overflow"))?;
+
+            let mut segments = stack.split(';');
+            let thread = segments.next().unwrap_or_default();
+            if thread.is_empty() {
+                return Err(malformed("missing thread"));
+            }
+            let (thread_name, thread_id) = match thread.parse::<u64>() {
+                Ok(thread_id) => (String::new(), thread_id),
+                Err(_) => (thread.to_owned(), 0),
+            };
+
+            let mut frames = Vec::new();
+            for name in segments {
+                if name.is_empty() {
+                    return Err(malformed("empty frame"));
+                }
+                if frames.len() == MAX_FOLDED_DEPTH {
+                    return Err(malformed("stack too deep"));
+                }
+                frames.push(vec![Symbol::synthetic(name)]);
+            }
+            if frames.is_empty() {
+                return Err(malformed("empty stack"));
+            }
+            frames.reverse();
+
+            let mut key = Frames {
+                frames,
+                thread_name,
+                thread_id,
+                cpu: None,
+                state: None,
+            };
+            interner.intern_frames(&mut key);
+            // can't overflow, as it never exceeds `total`
+            *data.entry(key).or_insert(0) += count;
+        }
+
+        Ok(Report {
+            data,
+            timing: ReportTiming::default(),
+            dropped_samples: 0,
+            truncated_samples: 0,
+            labels: HashMap::new(),
+            pprof_drop_frames: None,
+            pprof_keep_frames: None,
+            pprof_addresses: false,
+            metadata: BTreeMap::new(),
+            thread_states: Vec::new(),
+        })
+    }
+}
+
+/// Iterator over the stacks of a `Report` and their sample counts, see `Report::iter`.
+pub struct ReportIter<'a> {
+    inner: std::collections::hash_map::Iter<'a, Frames, isize>,
+}
+
+impl<'a> Iterator for ReportIter<'a> {
+    type Item = (&'a Frames, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next()
+            .map(|(frames, count)| (frames, *count as usize))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Iterates over the stacks of a report and their sample counts:
+///
+/// ```rust
+/// let report = pprof::Report::from_folded("main;parse;lex 3\nmain;parse 1\n").unwrap();
+/// for (frames, count) in &report {
+///     println!("{:?}: {}", frames, count);
+/// }
+/// ```
+impl<'a> IntoIterator for &'a Report {
+    type Item = (&'a Frames, usize);
+    type IntoIter = ReportIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 /// The presentation of an unsymbolicated report which is actually an `HashMap` from `UnresolvedFrames` to isize (count).
 pub struct UnresolvedReport {
     /// key is a backtrace captured by profiler and value is count of it.
     pub data: HashMap<UnresolvedFrames, isize>,
+
+    /// timing information (frequency, start time and duration) of the collection.
+    pub timing: ReportTiming,
+}
+
+type FramesPostProcessor = Box<dyn Fn(&mut Frames)>;
+type ThreadNameOverride = Box<dyn Fn(u64, Option<&str>) -> String>;
+type SampleLabeler = Box<dyn Fn(&Frames) -> Vec<(String, String)>>;
+type AddressRebase = Box<dyn Fn(usize) -> usize>;
+
+enum ProfilerRef<'a> {
+    Borrowed(&'a RwLock<Profiler>),
+    // the profiler has been stopped, and its samples are moved into the report
+    Owned(Arc<RwLock<Profiler>>),
 }
 
 /// A builder of `Report` and `UnresolvedReport`. It builds report from a running `Profiler`.
 pub struct ReportBuilder<'a> {
-    frames_post_processor: Option<Box<dyn Fn(&mut Frames)>>,
-    profiler: &'a RwLock<Result<Profiler>>,
+    rebase: Option<AddressRebase>,
+    frames_post_processor: Option<FramesPostProcessor>,
+    thread_name_override: Option<ThreadNameOverride>,
+    full_thread_names: bool,
+    sample_labeler: Option<SampleLabeler>,
+    max_render_depth: Option<(usize, KeepFrames)>,
+    strip_hash_suffixes: bool,
+    mark_truncated_stacks: bool,
+    symbol_display: SymbolDisplay,
+    ignored_frames: Vec<Regex>,
+    kept_stacks: Vec<String>,
+    collapse_recursion: bool,
+    prepend_crate_frames: bool,
+    split_by_thread: bool,
+    pprof_drop_frames: Option<String>,
+    pprof_keep_frames: Option<String>,
+    pprof_addresses: bool,
+    metadata: BTreeMap<String, String>,
+    ensured_frames: Vec<String>,
+    profiler: ProfilerRef<'a>,
 }
 
 impl<'a> ReportBuilder<'a> {
-    pub(crate) fn new(profiler: &'a RwLock<Result<Profiler>>) -> Self {
+    pub(crate) fn new(profiler: &'a RwLock<Profiler>) -> Self {
         Self {
+            rebase: None,
             frames_post_processor: None,
-            profiler,
+            thread_name_override: None,
+            full_thread_names: false,
+            sample_labeler: None,
+            max_render_depth: None,
+            strip_hash_suffixes: false,
+            mark_truncated_stacks: false,
+            symbol_display: SymbolDisplay::Full,
+            ignored_frames: Vec::new(),
+            kept_stacks: Vec::new(),
+            collapse_recursion: false,
+            prepend_crate_frames: false,
+            split_by_thread: true,
+            pprof_drop_frames: None,
+            pprof_keep_frames: None,
+            pprof_addresses: false,
+            metadata: BTreeMap::new(),
+            ensured_frames: Vec::new(),
+            profiler: ProfilerRef::Borrowed(profiler),
         }
     }
 
+    pub(crate) fn owned(profiler: Arc<RwLock<Profiler>>) -> ReportBuilder<'static> {
+        ReportBuilder {
+            rebase: None,
+            frames_post_processor: None,
+            thread_name_override: None,
+            full_thread_names: false,
+            sample_labeler: None,
+            max_render_depth: None,
+            strip_hash_suffixes: false,
+            mark_truncated_stacks: false,
+            symbol_display: SymbolDisplay::Full,
+            ignored_frames: Vec::new(),
+            kept_stacks: Vec::new(),
+            collapse_recursion: false,
+            prepend_crate_frames: false,
+            split_by_thread: true,
+            pprof_drop_frames: None,
+            pprof_keep_frames: None,
+            pprof_addresses: false,
+            metadata: BTreeMap::new(),
+            ensured_frames: Vec::new(),
+            profiler: ProfilerRef::Owned(profiler),
+        }
+    }
+
+    fn profiler(&self) -> &RwLock<Profiler> {
+        match &self.profiler {
+            ProfilerRef::Borrowed(profiler) => profiler,
+            ProfilerRef::Owned(profiler) => profiler,
+        }
+    }
+
+    /// Set `rebase` of a `ReportBuilder`. It maps every captured address before it is resolved,
+    /// e.g. to shift the addresses of a profile taken in a container by the slide of its load
+    /// addresses, so that it can be symbolized against the relocated binaries on the host.
+    pub fn rebase<T>(&mut self, rebase: T) -> &mut Self
+    where
+        T: Fn(usize) -> usize + 'static,
+    {
+        self.rebase.replace(Box::new(rebase));
+
+        self
+    }
+
     /// Set `frames_post_processor` of a `ReportBuilder`. Before finally building a report, `frames_post_processor`
     /// will be applied to every Frames.
     pub fn frames_post_processor<T>(&mut self, frames_post_processor: T) -> &mut Self
@@ -48,78 +659,377 @@ impl<'a> ReportBuilder<'a> {
         self
     }
 
+    /// Show the full names of the threads instead of their truncated captured names, for the
+    /// thread building the report and those registered with `register_thread_name`. It is
+    /// applied before `thread_name_override`.
+    pub fn full_thread_names(&mut self, full_thread_names: bool) -> &mut Self {
+        self.full_thread_names = full_thread_names;
+
+        self
+    }
+
+    /// Set `thread_name_override` of a `ReportBuilder`. It maps the id and captured name (if any)
+    /// of every sampled thread to the name shown in the report, e.g. to give runtime workers a
+    /// logical role. It is applied before `frames_post_processor`.
+    pub fn thread_name_override<T>(&mut self, thread_name_override: T) -> &mut Self
+    where
+        T: Fn(u64, Option<&str>) -> String + 'static,
+    {
+        self.thread_name_override
+            .replace(Box::new(thread_name_override));
+
+        self
+    }
+
+    /// Set `sample_labeler` of a `ReportBuilder`. It gives key/value labels to every backtrace of
+    /// the report, after `frames_post_processor`, which pprof can then filter samples by.
+    pub fn sample_labeler<T>(&mut self, sample_labeler: T) -> &mut Self
+    where
+        T: Fn(&Frames) -> Vec<(String, String)> + 'static,
+    {
+        self.sample_labeler.replace(Box::new(sample_labeler));
+
+        self
+    }
+
+    /// Truncate every stack to its `depth` frames at the `keep` end, marking the truncated ones
+    /// with a `[truncated]` frame, so that pathologically deep stacks stay readable and quick to
+    /// render. It is applied after `frames_post_processor`.
+    pub fn max_render_depth(&mut self, depth: usize, keep: KeepFrames) -> &mut Self {
+        self.max_render_depth.replace((depth, keep));
+
+        self
+    }
+
+    /// Strip the hash suffixes of the symbol names, so that the monomorphizations of a generic
+    /// function are aggregated as a single frame. It is applied before `frames_post_processor`.
+    pub fn strip_hash_suffixes(&mut self, strip_hash_suffixes: bool) -> &mut Self {
+        self.strip_hash_suffixes = strip_hash_suffixes;
+
+        self
+    }
+
+    /// Add a root frame named `[truncated]` to the stacks which were deeper than `MAX_DEPTH`,
+    /// see `Report::truncated_samples`, so that they don't pass for shallow stacks, nor are
+    /// aggregated with the complete ones starting at their outermost captured frame.
+    pub fn mark_truncated_stacks(&mut self, mark_truncated_stacks: bool) -> &mut Self {
+        self.mark_truncated_stacks = mark_truncated_stacks;
+
+        self
+    }
+
+    /// How the names of the symbols are rendered, `SymbolDisplay::Full` by default. With
+    /// `SymbolDisplay::Short`, the functions of the same short name, e.g. `Vec::push`, are
+    /// aggregated as a single frame. It is applied after all the other processing of the frames,
+    /// so that the filters and `frames_post_processor` still see the full names.
+    pub fn symbol_display(&mut self, symbol_display: SymbolDisplay) -> &mut Self {
+        self.symbol_display = symbol_display;
+
+        self
+    }
+
+    /// Remove the symbols whose demangled name matches `regex` from every stack, e.g.
+    /// `.*::drop_in_place`, so that their callers and callees become adjacent. The stacks which
+    /// become identical are aggregated. It can be called several times, and is applied after
+    /// `strip_hash_suffixes` and before `frames_post_processor`.
+    pub fn ignore_frames_matching(&mut self, regex: Regex) -> &mut Self {
+        self.ignored_frames.push(regex);
+
+        self
+    }
+
+    /// Keep only the stacks with a frame whose demangled name contains `name`, e.g. to focus a
+    /// flamegraph on what `handle_request` does. The kept stacks are whole, their frames above
+    /// the matching one included, and a stack matching several times is kept once. It can be
+    /// called several times to keep the stacks matching any of the names, and is applied after
+    /// `ignore_frames_matching`. The other stacks are left out of the report, not counted as
+    /// dropped samples.
+    pub fn only_stacks_containing(&mut self, name: &str) -> &mut Self {
+        self.kept_stacks.push(name.to_owned());
+
+        self
+    }
+
+    /// Collapse direct recursion into a single frame, see `Frames::collapse_recursion`, so that
+    /// a recursive function renders as one block. It is applied after `frames_post_processor`.
+    pub fn collapse_recursion(&mut self, collapse_recursion: bool) -> &mut Self {
+        self.collapse_recursion = collapse_recursion;
+
+        self
+    }
+
+    /// Add a root frame named after the crate every stack was sampled in, see
+    /// `Frames::prepend_crate_frame`, so that a flamegraph shows the share of every crate before
+    /// the functions. It is applied after `max_render_depth`.
+    pub fn prepend_crate_frames(&mut self, prepend_crate_frames: bool) -> &mut Self {
+        self.prepend_crate_frames = prepend_crate_frames;
+
+        self
+    }
+
+    /// Whether identical stacks of different threads are kept apart, which is the default. If
+    /// not, the thread name and id of every stack are cleared after all the other processing, so
+    /// that they are aggregated.
+    pub fn split_by_thread(&mut self, split_by_thread: bool) -> &mut Self {
+        self.split_by_thread = split_by_thread;
+
+        self
+    }
+
+    /// Set the pprof `drop_frames` regex, with which the pprof tool hides the matching frames and
+    /// their callees when viewing the profile. The report itself isn't filtered.
+    pub fn pprof_drop_frames(&mut self, regex: &str) -> &mut Self {
+        self.pprof_drop_frames.replace(regex.to_owned());
+
+        self
+    }
+
+    /// Set the pprof `keep_frames` regex, with which the pprof tool keeps the frames matching it
+    /// even if they match `drop_frames`. The report itself isn't filtered.
+    pub fn pprof_keep_frames(&mut self, regex: &str) -> &mut Self {
+        self.pprof_keep_frames.replace(regex.to_owned());
+
+        self
+    }
+
+    /// Write the sampled address of every frame into the `address` of its pprof location, see
+    /// `Symbol::ip`, e.g. for the pprof tool to show the disassembly. A function then gets a
+    /// location per sampled address instead of a single one.
+    pub fn pprof_addresses(&mut self, pprof_addresses: bool) -> &mut Self {
+        self.pprof_addresses = pprof_addresses;
+
+        self
+    }
+
+    /// Attach the `key`/`value` metadata to the report, e.g. the git commit or the host, so that
+    /// archived profiles are self-describing. It is written into the pprof comments and the
+    /// flamegraph subtitle. Setting a key again replaces its value.
+    pub fn metadata(&mut self, key: &str, value: &str) -> &mut Self {
+        self.metadata.insert(key.to_owned(), value.to_owned());
+
+        self
+    }
+
+    /// Make sure the functions named `names` appear in the report, e.g. for the frame set of the
+    /// flamegraphs of several runs to stay the same when diffing them. A function which wasn't
+    /// sampled at all gets a placeholder stack of its own with a zero count, neither bound to a
+    /// thread nor to its callers.
+    pub fn ensure_frames(&mut self, names: &[&str]) -> &mut Self {
+        self.ensured_frames
+            .extend(names.iter().map(|name| (*name).to_owned()));
+
+        self
+    }
+
     /// Build an `UnresolvedReport`
     pub fn build_unresolved(&self) -> Result<UnresolvedReport> {
         let mut hash_map = HashMap::new();
 
-        match self.profiler.read().as_ref() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
-            }
-            Ok(profiler) => {
-                profiler.data.try_iter()?.for_each(|entry| {
-                    let count = entry.count;
-                    if count > 0 {
-                        let key = &entry.item;
-                        match hash_map.get_mut(key) {
-                            Some(value) => {
-                                *value += count;
-                            }
-                            None => {
-                                match hash_map.insert(key.clone(), count) {
-                                    None => {}
-                                    Some(_) => {
-                                        unreachable!();
-                                    }
-                                };
+        let mut profiler = self.profiler().write();
+        profiler.flush_repeated();
+        profiler.try_iter()?.for_each(|(key, count)| {
+            if count > 0 {
+                match hash_map.get_mut(key) {
+                    Some(value) => {
+                        *value += count;
+                    }
+                    None => {
+                        match hash_map.insert(key.clone(), count) {
+                            None => {}
+                            Some(_) => {
+                                unreachable!();
                             }
-                        }
+                        };
                     }
-                });
-
-                Ok(UnresolvedReport { data: hash_map })
+                }
             }
-        }
+        });
+
+        Ok(UnresolvedReport {
+            data: hash_map,
+            timing: profiler.timing(),
+        })
     }
 
     /// Build a `Report`.
+    ///
+    /// If this builder comes from `ProfilerGuard::into_report`, the samples are moved rather than
+    /// cloned into the report, so only the first report built contains them.
     pub fn build(&self) -> Result<Report> {
+        match &self.profiler {
+            ProfilerRef::Borrowed(profiler) => {
+                let mut profiler = profiler.write();
+                profiler.flush_repeated();
+                let entries = profiler
+                    .try_iter()?
+                    .filter(|(_, count)| *count > 0)
+                    .map(|(item, count)| (item.clone(), count));
+
+                let dropped_samples =
+                    profiler.dropped_sample_counter + profiler.dropped_count().max(0) as usize;
+                Ok(self.resolve(
+                    entries,
+                    profiler.timing(),
+                    dropped_samples,
+                    profiler.thread_states(),
+                ))
+            }
+            ProfilerRef::Owned(profiler) => {
+                let mut profiler = profiler.write();
+                profiler.flush_repeated();
+                let timing = profiler.timing();
+                let thread_states = profiler.thread_states();
+                let dropped_samples = std::mem::take(&mut profiler.dropped_sample_counter)
+                    + profiler.dropped_count().max(0) as usize;
+                let entries = profiler.drain()?;
+
+                Ok(self.resolve(entries, timing, dropped_samples, thread_states))
+            }
+        }
+    }
+
+    fn resolve<I>(
+        &self,
+        entries: I,
+        timing: ReportTiming,
+        mut dropped_samples: usize,
+        thread_states: Vec<(u64, ThreadState)>,
+    ) -> Report
+    where
+        I: Iterator<Item = (UnresolvedFrames, isize)>,
+    {
         let mut hash_map = HashMap::new();
+        let mut interner = SymbolInterner::default();
+        let mut truncated_samples = 0;
 
-        match self.profiler.write().as_mut() {
-            Err(err) => {
-                log::error!("Error in creating profiler: {}", err);
-                Err(Error::CreatingError)
-            }
-            Ok(profiler) => {
-                profiler.data.try_iter()?.for_each(|entry| {
-                    let count = entry.count;
-                    if count > 0 {
-                        let mut key = Frames::from(entry.item.clone());
-                        if let Some(processor) = &self.frames_post_processor {
-                            processor(&mut key);
-                        }
+        let entries = entries
+            .filter(|(_, count)| *count > 0)
+            .map(|(mut item, count)| {
+                if let Some(rebase) = &self.rebase {
+                    item.rebase(rebase);
+                }
+                (item, count)
+            });
+        entries.for_each(|(item, count)| {
+            let truncated = item.truncated;
+            let mut key = interner.resolve(item);
+            if truncated && self.mark_truncated_stacks {
+                key.frames.push(vec![Symbol::synthetic("[truncated]")]);
+            }
+            if self.full_thread_names {
+                if let Some(name) = full_thread_name(key.thread_id, &key.thread_name) {
+                    key.thread_name = name;
+                }
+            }
+            if let Some(thread_name_override) = &self.thread_name_override {
+                let thread_name = Some(key.thread_name.as_str()).filter(|name| !name.is_empty());
+                key.thread_name = thread_name_override(key.thread_id, thread_name);
+            }
+            if self.strip_hash_suffixes {
+                key.strip_hash_suffixes();
+            }
+            if !self.ignored_frames.is_empty() {
+                key.retain_symbols(|symbol| {
+                    let name = symbol.name();
+                    !self
+                        .ignored_frames
+                        .iter()
+                        .any(|regex| regex.is_match(&name))
+                });
+            }
+            if !self.kept_stacks.is_empty() {
+                let mut names = key.frames.iter().flatten().map(Symbol::name);
+                if !names.any(|name| self.kept_stacks.iter().any(|kept| name.contains(kept))) {
+                    return;
+                }
+            }
+            if let Some(processor) = &self.frames_post_processor {
+                processor(&mut key);
+            }
+            if self.collapse_recursion {
+                key.collapse_recursion();
+            }
+            if let Some((depth, keep)) = self.max_render_depth {
+                key.truncate(depth, keep);
+            }
+            if self.prepend_crate_frames && !key.is_empty() {
+                key.prepend_crate_frame();
+            }
+            if self.symbol_display == SymbolDisplay::Short {
+                key.shorten_names();
+            }
+            if !self.split_by_thread {
+                key.thread_name.clear();
+                key.thread_id = 0;
+            }
 
-                        match hash_map.get_mut(&key) {
-                            Some(value) => {
-                                *value += count;
-                            }
-                            None => {
-                                match hash_map.insert(key, count) {
-                                    None => {}
-                                    Some(_) => {
-                                        unreachable!();
-                                    }
-                                };
-                            }
+            if key.is_empty() {
+                dropped_samples += count as usize;
+                return;
+            }
+            if truncated {
+                truncated_samples += count as usize;
+            }
+            // the processing above may rename symbols
+            interner.intern_frames(&mut key);
+
+            match hash_map.get_mut(&key) {
+                Some(value) => {
+                    *value += count;
+                }
+                None => {
+                    match hash_map.insert(key, count) {
+                        None => {}
+                        Some(_) => {
+                            unreachable!();
                         }
-                    }
-                });
+                    };
+                }
+            }
+        });
+
+        if !self.ensured_frames.is_empty() {
+            let sampled: HashSet<String> = hash_map
+                .keys()
+                .flat_map(|key| key.frames.iter().flatten())
+                .map(Symbol::name)
+                .collect();
+            for name in &self.ensured_frames {
+                if !sampled.contains(name) {
+                    let key = Frames {
+                        frames: vec![vec![Symbol::synthetic(name)]],
+                        thread_name: String::new(),
+                        thread_id: 0,
+                        cpu: None,
+                        state: None,
+                    };
+                    hash_map.insert(key, 0);
+                }
+            }
+        }
 
-                Ok(Report { data: hash_map })
+        let mut labels = HashMap::new();
+        if let Some(labeler) = &self.sample_labeler {
+            for key in hash_map.keys() {
+                let key_labels = labeler(key);
+                if !key_labels.is_empty() {
+                    labels.insert(key.clone(), key_labels);
+                }
             }
         }
+
+        Report {
+            data: hash_map,
+            timing,
+            dropped_samples,
+            truncated_samples,
+            labels,
+            pprof_drop_frames: self.pprof_drop_frames.clone(),
+            pprof_keep_frames: self.pprof_keep_frames.clone(),
+            pprof_addresses: self.pprof_addresses,
+            metadata: self.metadata.clone(),
+            thread_states,
+        }
     }
 }
 
@@ -140,11 +1050,90 @@ impl Debug for Report {
     }
 }
 
+mod dot {
+    use super::*;
+
+    fn escape(name: &str) -> String {
+        name.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    impl Report {
+        /// Writes the call graph of this report in the Graphviz DOT format, like
+        /// `go tool pprof -dot`. Every function is a node labelled with its self and inclusive
+        /// sample counts, and sized by its self count. Every call is an edge labelled with its
+        /// sample count.
+        pub fn dot<W>(&self, mut writer: W) -> Result<()>
+        where
+            W: Write,
+        {
+            let total: isize = self.data.values().sum();
+            let (self_counts, inclusive_counts) = self.function_counts();
+
+            let mut nodes: Vec<(&String, isize)> = inclusive_counts
+                .iter()
+                .map(|(name, count)| (name, *count))
+                .collect();
+            nodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            let ids: HashMap<&String, usize> = nodes
+                .iter()
+                .enumerate()
+                .map(|(index, (name, _))| (*name, index))
+                .collect();
+
+            let percent = |count: isize| {
+                if total > 0 {
+                    count as f64 * 100.0 / total as f64
+                } else {
+                    0.0
+                }
+            };
+
+            writeln!(writer, "digraph \"pprof\" {{")?;
+            writeln!(writer, "node [shape=box fontname=\"Helvetica\"];")?;
+            for (name, inclusive) in nodes.iter() {
+                let self_count = self_counts.get(*name).copied().unwrap_or(0);
+                writeln!(
+                    writer,
+                    "N{} [label=\"{}\\n{} ({:.2}%)\\nof {} ({:.2}%)\" fontsize={:.0}];",
+                    ids[name],
+                    escape(name),
+                    self_count,
+                    percent(self_count),
+                    inclusive,
+                    percent(*inclusive),
+                    8.0 + 16.0 * percent(self_count) / 100.0,
+                )?;
+            }
+
+            let mut edges: Vec<((String, String), usize)> = self
+                .call_edges()
+                .into_iter()
+                .map(|((caller, callee), count)| ((caller.name(), callee.name()), count))
+                .fold(HashMap::new(), |mut edges, (key, count)| {
+                    *edges.entry(key).or_insert(0) += count;
+                    edges
+                })
+                .into_iter()
+                .collect();
+            edges.sort();
+            for ((caller, callee), count) in edges.iter() {
+                writeln!(
+                    writer,
+                    "N{} -> N{} [label=\" {}\"];",
+                    ids[caller], ids[callee], count
+                )?;
+            }
+            writeln!(writer, "}}")?;
+
+            Ok(())
+        }
+    }
+}
+
 #[cfg(feature = "flamegraph")]
 mod flamegraph {
     use super::*;
     use inferno::flamegraph;
-    use std::io::Write;
 
     impl Report {
         /// `flamegraph` will write an svg flamegraph into `writer` **only available with `flamegraph` feature**
@@ -155,6 +1144,23 @@ mod flamegraph {
             self.flamegraph_with_options(writer, &mut flamegraph::Options::default())
         }
 
+        /// same as `flamegraph`, but the counts in the tooltips are the estimated time in
+        /// milliseconds, i.e. the sample counts scaled by `period`, instead of sample counts. They
+        /// stay sample counts if the period is unknown.
+        pub fn flamegraph_with_time<W>(&self, writer: W) -> Result<()>
+        where
+            W: Write,
+        {
+            let mut options = flamegraph::Options::default();
+            let period = self.period();
+            if period > Duration::from_secs(0) {
+                options.count_name = "ms".to_owned();
+                options.factor = period.as_secs_f64() * 1000.0;
+            }
+
+            self.flamegraph_with_options(writer, &mut options)
+        }
+
         /// same as `flamegraph`, but accepts custom `options` for the flamegraph
         pub fn flamegraph_with_options<W>(
             &self,
@@ -164,52 +1170,405 @@ mod flamegraph {
         where
             W: Write,
         {
-            let lines: Vec<String> = self
-                .data
-                .iter()
-                .map(|(key, value)| {
-                    let mut line = String::new();
-                    if !key.thread_name.is_empty() {
-                        line.push_str(&key.thread_name);
-                    } else {
-                        line.push_str(&format!("{:?}", key.thread_id));
-                    }
-                    line.push(';');
+            // inferno rejects stacks which were all sampled zero times, e.g. placeholders only
+            if self.is_empty() {
+                return write_placeholder(writer, options);
+            }
 
-                    for frame in key.frames.iter().rev() {
-                        for symbol in frame.iter().rev() {
-                            line.push_str(&format!("{}", symbol));
-                            line.push(';');
-                        }
-                    }
+            // the metadata is shown unless a subtitle was given
+            let subtitle = options.subtitle.clone();
+            if subtitle.is_none() && !self.metadata.is_empty() {
+                options.subtitle = Some(self.metadata_strings().collect::<Vec<_>>().join(", "));
+            }
+            let lines = self.folded_lines();
+            let result = flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer);
+            options.subtitle = subtitle;
+            result.unwrap();
+            // TODO: handle this error
 
-                    line.pop().unwrap_or_default();
-                    line.push_str(&format!(" {}", value));
+            Ok(())
+        }
 
-                    line
-                })
-                .collect();
-            if !lines.is_empty() {
-                flamegraph::from_lines(options, lines.iter().map(|s| &**s), writer).unwrap();
-                // TODO: handle this error
+        /// same as `flamegraph`, but the frames are colored by `color` from their names, e.g. to
+        /// tell the frames of a crate from those of its dependencies, instead of by the palette.
+        pub fn flamegraph_with_colors<W, F>(&self, writer: W, color: F) -> Result<()>
+        where
+            W: Write,
+            F: Fn(&str) -> flamegraph::color::Color,
+        {
+            let mut palette_map = self.palette_map(color);
+            let mut options = flamegraph::Options::default();
+            options.palette_map = Some(&mut palette_map);
+
+            self.flamegraph_with_options(writer, &mut options)
+        }
+
+        /// Maps the name of every frame of the flamegraph, threads included, to its color given by
+        /// `color`, to be set as the `palette_map` of custom `options`, see
+        /// `flamegraph_with_colors`.
+        pub fn palette_map<F>(&self, color: F) -> flamegraph::color::PaletteMap
+        where
+            F: Fn(&str) -> flamegraph::color::Color,
+        {
+            let mut palette_map = flamegraph::color::PaletteMap::default();
+            for line in self.folded_lines() {
+                let stack = line.rsplit_once(' ').map_or("", |(stack, _)| stack);
+                for name in stack.split(';') {
+                    if palette_map.get(name).is_none() {
+                        palette_map.insert(name, color(name));
+                    }
+                }
             }
 
-            Ok(())
+            palette_map
         }
     }
+
+    // the width inferno renders flamegraphs at when `image_width` isn't set
+    const DEFAULT_IMAGE_WIDTH: usize = 1200;
+
+    // Writes an svg with a "no samples" notice, sized like a flamegraph, in place of the
+    // flamegraph of an empty report.
+    fn write_placeholder<W>(mut writer: W, options: &flamegraph::Options) -> Result<()>
+    where
+        W: Write,
+    {
+        let width = options.image_width.unwrap_or(DEFAULT_IMAGE_WIDTH);
+        let height = options.font_size * 5;
+        write!(
+            writer,
+            concat!(
+                r#"<?xml version="1.0" standalone="no"?>"#,
+                r#"<svg version="1.1" width="{width}" height="{height}" "#,
+                r#"viewBox="0 0 {width} {height}" xmlns="http://www.w3.org/2000/svg">"#,
+                r#"<text x="50%" y="{y}" text-anchor="middle" font-family="{font}" "#,
+                r#"font-size="{size}">no samples</text></svg>"#,
+            ),
+            width = width,
+            height = height,
+            y = options.font_size * 2,
+            font = options
+                .font_type
+                .replace('&', "&amp;")
+                .replace('"', "&quot;"),
+            size = options.font_size,
+        )?;
+
+        Ok(())
+    }
 }
 
+#[cfg(feature = "protobuf")]
+pub use protobuf::WriteSummary;
+
 #[cfg(feature = "protobuf")]
 mod protobuf {
     use super::*;
-    use crate::protos;
+    use crate::protos::{self, Message};
+    use flate2::read::GzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
     use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::{BufWriter, Read};
+    use std::os::raw::c_void;
+
+    /// The size and CRC32 of an encoded profile, as returned by `Report::write_pprof`, so that a
+    /// receiver can check it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WriteSummary {
+        pub bytes: usize,
+        pub crc32: u32,
+    }
+
+    // Counts and checksums the bytes written through it.
+    struct SummaryWriter<W> {
+        inner: W,
+        bytes: usize,
+        hasher: crc32fast::Hasher,
+    }
+
+    impl<W: Write> Write for SummaryWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let written = self.inner.write(buf)?;
+            self.bytes += written;
+            self.hasher.update(&buf[..written]);
+
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
 
     impl Report {
-        // `pprof` will generate google's pprof format report
+        /// `pprof` will generate google's pprof format report **only available with `protobuf` feature**
         pub fn pprof(&self) -> crate::Result<protos::Profile> {
+            Ok(self.to_pprof_profile())
+        }
+
+        /// Encodes the pprof format report into `writer`, returning the number of bytes written
+        /// and their CRC32. **only available with `protobuf` feature**
+        pub fn write_pprof<W: Write>(&self, writer: W) -> crate::Result<WriteSummary> {
+            let profile = self.to_pprof_profile();
+            let mut content = Vec::with_capacity(profile.encoded_len());
+            // can't fail, as the buffer has the required capacity
+            profile.encode(&mut content).unwrap();
+
+            let mut writer = SummaryWriter {
+                inner: writer,
+                bytes: 0,
+                hasher: crc32fast::Hasher::new(),
+            };
+            writer.write_all(&content)?;
+            writer.flush()?;
+
+            Ok(WriteSummary {
+                bytes: writer.bytes,
+                crc32: writer.hasher.finalize(),
+            })
+        }
+
+        /// Writes a gzipped pprof profile per thread id into `dir`, named `thread-<thread_id>.pb.gz`,
+        /// and returns their paths sorted by thread id. Each profile only has the samples of its
+        /// thread, named or not, so that together they add up to this report. **only available
+        /// with `protobuf` feature**
+        pub fn write_pprof_per_thread<P: AsRef<Path>>(
+            &self,
+            dir: P,
+        ) -> crate::Result<Vec<PathBuf>> {
+            let mut threads: BTreeMap<u64, HashMap<Frames, isize>> = BTreeMap::new();
+            for (key, count) in self.data.iter() {
+                threads
+                    .entry(key.thread_id)
+                    .or_default()
+                    .insert(key.clone(), *count);
+            }
+
+            let mut paths = Vec::new();
+            for (thread_id, data) in threads {
+                let labels = self
+                    .labels
+                    .iter()
+                    .filter(|(key, _)| data.contains_key(key))
+                    .map(|(key, labels)| (key.clone(), labels.clone()))
+                    .collect();
+                let mut timing = self.timing.clone();
+                timing
+                    .thread_lifetimes
+                    .retain(|(lifetime_thread_id, _, _)| *lifetime_thread_id == thread_id);
+                let report = Report {
+                    data,
+                    timing,
+                    // they can't be told apart by thread
+                    dropped_samples: 0,
+                    truncated_samples: 0,
+                    labels,
+                    pprof_drop_frames: self.pprof_drop_frames.clone(),
+                    pprof_keep_frames: self.pprof_keep_frames.clone(),
+                    pprof_addresses: self.pprof_addresses,
+                    metadata: self.metadata.clone(),
+                    thread_states: self
+                        .thread_states
+                        .iter()
+                        .filter(|(state_thread_id, _)| *state_thread_id == thread_id)
+                        .copied()
+                        .collect(),
+                };
+
+                let path = dir.as_ref().join(format!("thread-{}.pb.gz", thread_id));
+                let file = BufWriter::new(File::create(&path)?);
+                let mut encoder = GzEncoder::new(file, Compression::default());
+                report.write_pprof(&mut encoder)?;
+                encoder.finish()?.flush()?;
+                paths.push(path);
+            }
+
+            Ok(paths)
+        }
+
+        /// Reads a pprof profile, gzipped or not, e.g. to merge profiles from other sources. The
+        /// counts are read from the `samples` sample type, or the first one counted in `count`,
+        /// or else the first one. The locations become the frames and their lines the inlined
+        /// symbols, named after the system name of their function, or its name, or `Unknow`. The
+        /// `cpu` numeric label sets the cpu of the frames and the other string labels become
+        /// `labels`, while the threads aren't known. A malformed profile returns
+        /// `Error::MalformedProfile`. **only available with `protobuf` feature**
+        pub fn from_pprof<R: Read>(mut reader: R) -> crate::Result<Report> {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content)?;
+            if content.starts_with(&[0x1f, 0x8b]) {
+                let mut decoded = Vec::new();
+                GzDecoder::new(content.as_slice()).read_to_end(&mut decoded)?;
+                content = decoded;
+            }
+            let profile = protos::Profile::decode(content.as_slice())
+                .map_err(|err| Error::MalformedProfile(err.to_string()))?;
+            let malformed = |reason: &str| Error::MalformedProfile(reason.to_owned());
+
+            let string = |index: i64| {
+                usize::try_from(index)
+                    .ok()
+                    .and_then(|index| profile.string_table.get(index))
+                    .map(String::as_str)
+                    .ok_or_else(|| malformed("string index out of range"))
+            };
+            let value_index = {
+                let mut value_index = None;
+                for (index, value_type) in profile.sample_type.iter().enumerate() {
+                    if string(value_type.r#type)? == "samples" {
+                        value_index = Some(index);
+                        break;
+                    }
+                    if value_index.is_none() && string(value_type.unit)? == "count" {
+                        value_index = Some(index);
+                    }
+                }
+                value_index.unwrap_or(0)
+            };
+
+            let functions: HashMap<u64, &protos::Function> = profile
+                .function
+                .iter()
+                .map(|function| (function.id, function))
+                .collect();
+            let mut locations: HashMap<u64, Vec<Symbol>> = HashMap::new();
+            for location in profile.location.iter() {
+                let addr = match location.address {
+                    0 => None,
+                    address => Some(address as usize as *mut c_void),
+                };
+                let mut symbols = Vec::new();
+                for line in location.line.iter() {
+                    let function = functions
+                        .get(&line.function_id)
+                        .ok_or_else(|| malformed("unknown function id"))?;
+                    let name = match string(function.system_name)? {
+                        "" => string(function.name)?,
+                        system_name => system_name,
+                    };
+                    let filename = string(function.filename)?;
+                    symbols.push(Symbol {
+                        name: Some(name.as_bytes().into()).filter(|_| !name.is_empty()),
+                        addr,
+                        ip: addr,
+                        lineno: u32::try_from(line.line).ok().filter(|line| *line > 0),
+                        filename: Some(Path::new(filename).into()).filter(|_| !filename.is_empty()),
+                    });
+                }
+                if symbols.is_empty() {
+                    symbols.push(match addr {
+                        Some(addr) => Symbol::unresolved(addr),
+                        None => Symbol {
+                            name: None,
+                            addr: None,
+                            ip: None,
+                            lineno: None,
+                            filename: None,
+                        },
+                    });
+                }
+                locations.insert(location.id, symbols);
+            }
+
+            let mut data: HashMap<Frames, isize> = HashMap::new();
+            let mut labels: HashMap<Frames, Vec<(String, String)>> = HashMap::new();
+            let mut dropped_samples = 0;
+            for sample in profile.sample.iter() {
+                let count = *sample
+                    .value
+                    .get(value_index)
+                    .ok_or_else(|| malformed("missing sample value"))?;
+                let count = isize::try_from(count).map_err(|_| malformed("count overflow"))?;
+                if count <= 0 {
+                    continue;
+                }
+                if sample.location_id.is_empty() {
+                    dropped_samples += count as usize;
+                    continue;
+                }
+
+                let mut frames = Vec::with_capacity(sample.location_id.len());
+                for location_id in sample.location_id.iter() {
+                    let symbols = locations
+                        .get(location_id)
+                        .ok_or_else(|| malformed("unknown location id"))?;
+                    frames.push(symbols.clone());
+                }
+                let (mut cpu, mut state) = (None, None);
+                let mut sample_labels = Vec::new();
+                for label in sample.label.iter() {
+                    let key = string(label.key)?;
+                    if label.str != 0 {
+                        let value = string(label.str)?;
+                        match ThreadState::from_name(value).filter(|_| key == "state") {
+                            Some(thread_state) => state = Some(thread_state),
+                            None => sample_labels.push((key.to_owned(), value.to_owned())),
+                        }
+                    } else if key == "cpu" {
+                        cpu = u32::try_from(label.num).ok();
+                    }
+                }
+
+                let key = Frames {
+                    frames,
+                    thread_name: String::new(),
+                    thread_id: 0,
+                    cpu,
+                    state,
+                };
+                if !sample_labels.is_empty() {
+                    labels.insert(key.clone(), sample_labels);
+                }
+                let total = data.entry(key).or_insert(0);
+                *total = total
+                    .checked_add(count)
+                    .ok_or_else(|| malformed("count overflow"))?;
+            }
+
+            let optional_string = |index: i64| -> crate::Result<Option<String>> {
+                let value = string(index)?;
+                Ok(Some(value.to_owned()).filter(|_| !value.is_empty()))
+            };
+            let mut metadata = BTreeMap::new();
+            for comment in profile.comment.iter() {
+                if let Some((key, value)) = string(*comment)?.split_once('=') {
+                    metadata.insert(key.to_owned(), value.to_owned());
+                }
+            }
+            let frequency = match profile.period {
+                period if period > 0 => (1_000_000_000 / period).min(i32::MAX as i64) as i32,
+                _ => 0,
+            };
+            let timing = ReportTiming {
+                frequency,
+                start_time: std::time::UNIX_EPOCH
+                    + Duration::from_nanos(profile.time_nanos.max(0) as u64),
+                duration: Duration::from_nanos(profile.duration_nanos.max(0) as u64),
+                ..ReportTiming::default()
+            };
+
+            Ok(Report {
+                data,
+                timing,
+                dropped_samples,
+                truncated_samples: 0,
+                labels,
+                pprof_drop_frames: optional_string(profile.drop_frames)?,
+                pprof_keep_frames: optional_string(profile.keep_frames)?,
+                pprof_addresses: false,
+                metadata,
+                thread_states: Vec::new(),
+            })
+        }
+
+        /// Builds the pprof `Profile` message of this report, to be customized (e.g. with more
+        /// labels), merged with other profiles, or encoded with `protos::Message`.
+        pub fn to_pprof_profile(&self) -> protos::Profile {
             let mut dudup_str = HashSet::new();
-            for key in self.data.iter().map(|(key, _)| key) {
+            for key in self.data.keys() {
                 for frame in key.frames.iter() {
                     for symbol in frame {
                         dudup_str.insert(symbol.name());
@@ -218,9 +1577,20 @@ mod protobuf {
                     }
                 }
             }
+            for (key, value) in self.labels.values().flatten() {
+                dudup_str.insert(key.clone());
+                dudup_str.insert(value.clone());
+            }
+            if self.data.keys().any(|key| key.cpu.is_some()) {
+                dudup_str.insert("cpu".to_owned());
+            }
+            for state in self.data.keys().filter_map(|key| key.state) {
+                dudup_str.insert("state".to_owned());
+                dudup_str.insert(state.name().to_owned());
+            }
             // string table's first element must be an empty string
             let mut str_tbl = vec!["".to_owned()];
-            str_tbl.extend(dudup_str.into_iter());
+            str_tbl.extend(dudup_str);
 
             let mut strings = HashMap::new();
             for (index, name) in str_tbl.iter().enumerate() {
@@ -231,47 +1601,84 @@ mod protobuf {
             let mut loc_tbl = vec![];
             let mut fn_tbl = vec![];
             let mut functions = HashMap::new();
+            let mut locations = HashMap::new();
             for (key, count) in self.data.iter() {
                 let mut locs = vec![];
-                for frame in key.frames.iter() {
-                    for symbol in frame {
-                        let name = symbol.name();
-                        if let Some(loc_idx) = functions.get(&name) {
-                            locs.push(*loc_idx);
-                            continue;
-                        }
-                        let sys_name = symbol.sys_name();
-                        let filename = symbol.filename();
-                        let lineno = symbol.lineno();
-                        let function_id = fn_tbl.len() as u64 + 1;
-                        let function = protos::Function {
-                            id: function_id,
-                            name: *strings.get(name.as_str()).unwrap() as i64,
-                            system_name: *strings.get(sys_name.as_ref()).unwrap() as i64,
-                            filename: *strings.get(filename.as_ref()).unwrap() as i64,
-                            ..protos::Function::default()
-                        };
-                        functions.insert(name, function_id);
-                        let line = protos::Line {
-                            function_id,
-                            line: lineno as i64,
-                        };
-                        let loc = protos::Location {
-                            id: function_id,
-                            line: vec![line],
-                            ..protos::Location::default()
-                        };
-                        // the fn_tbl has the same length with loc_tbl
-                        fn_tbl.push(function);
-                        loc_tbl.push(loc);
-                        // current frame locations
-                        locs.push(function_id);
+                // pprof lists the locations of a sample from the leaf
+                for symbol in key.symbols(StackOrder::LeafFirst) {
+                    let name = symbol.name();
+                    let address = match symbol.ip {
+                        Some(ip) if self.pprof_addresses => ip as usize as u64,
+                        _ => 0,
+                    };
+                    if let Some(loc_idx) = locations.get(&(name.as_str(), address)) {
+                        locs.push(*loc_idx);
+                        continue;
                     }
+                    let function_id = match functions.get(&name) {
+                        Some(function_id) => *function_id,
+                        None => {
+                            let function_id = fn_tbl.len() as u64 + 1;
+                            let sys_name = symbol.sys_name();
+                            let filename = symbol.filename();
+                            fn_tbl.push(protos::Function {
+                                id: function_id,
+                                name: *strings.get(name.as_str()).unwrap() as i64,
+                                system_name: *strings.get(sys_name.as_ref()).unwrap() as i64,
+                                filename: *strings.get(filename.as_ref()).unwrap() as i64,
+                                ..protos::Function::default()
+                            });
+                            functions.insert(name.clone(), function_id);
+                            function_id
+                        }
+                    };
+                    let line = protos::Line {
+                        function_id,
+                        line: symbol.lineno() as i64,
+                    };
+                    // without addresses, the fn_tbl has the same length with loc_tbl
+                    let location_id = loc_tbl.len() as u64 + 1;
+                    let loc = protos::Location {
+                        id: location_id,
+                        address,
+                        line: vec![line],
+                        ..protos::Location::default()
+                    };
+                    loc_tbl.push(loc);
+                    let name = strings.get_key_value(name.as_str()).unwrap().0;
+                    locations.insert((*name, address), location_id);
+                    // current frame locations
+                    locs.push(location_id);
+                }
+                let mut label: Vec<_> = self
+                    .labels
+                    .get(key)
+                    .into_iter()
+                    .flatten()
+                    .map(|(key, value)| protos::Label {
+                        key: *strings.get(key.as_str()).unwrap() as i64,
+                        str: *strings.get(value.as_str()).unwrap() as i64,
+                        ..protos::Label::default()
+                    })
+                    .collect();
+                if let Some(cpu) = key.cpu {
+                    label.push(protos::Label {
+                        key: *strings.get("cpu").unwrap() as i64,
+                        num: i64::from(cpu),
+                        ..protos::Label::default()
+                    });
+                }
+                if let Some(state) = key.state {
+                    label.push(protos::Label {
+                        key: *strings.get("state").unwrap() as i64,
+                        str: *strings.get(state.name()).unwrap() as i64,
+                        ..protos::Label::default()
+                    });
                 }
                 let sample = protos::Sample {
                     location_id: locs,
                     value: vec![*count as i64],
-                    ..protos::Sample::default()
+                    label,
                 };
                 samples.push(sample);
             }
@@ -282,15 +1689,911 @@ mod protobuf {
                 r#type: type_idx as i64,
                 unit: unit_idx as i64,
             };
-            let profile = protos::Profile {
+            let period_unit_idx = str_tbl.len();
+            str_tbl.push("nanoseconds".to_owned());
+            let period_type = protos::ValueType {
+                r#type: type_idx as i64,
+                unit: period_unit_idx as i64,
+            };
+            let mut push_str = |value: &Option<String>| match value {
+                Some(value) => {
+                    str_tbl.push(value.clone());
+                    str_tbl.len() as i64 - 1
+                }
+                None => 0,
+            };
+            let drop_frames = push_str(&self.pprof_drop_frames);
+            let keep_frames = push_str(&self.pprof_keep_frames);
+            let comment = self
+                .metadata_strings()
+                .map(|comment| push_str(&Some(comment)))
+                .collect();
+            let time_nanos = self
+                .timing
+                .start_time
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as i64)
+                .unwrap_or(0);
+            protos::Profile {
                 sample_type: vec![sample_type],
                 sample: samples,
                 string_table: str_tbl,
                 function: fn_tbl,
                 location: loc_tbl,
+                time_nanos,
+                duration_nanos: self.timing.duration.as_nanos() as i64,
+                period_type: Some(period_type),
+                period: self.period().as_nanos() as i64,
+                drop_frames,
+                keep_frames,
+                comment,
                 ..protos::Profile::default()
+            }
+        }
+    }
+}
+
+// The quantile function of the standard normal distribution at `p`, in (0, 1), with Acklam's
+// rational approximation, whose relative error is below 1.2e-9.
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const LOW: f64 = 0.02425;
+
+    let polynomial = |coefficients: &[f64], x: f64| {
+        coefficients
+            .iter()
+            .fold(0.0, |value, coefficient| value * x + coefficient)
+    };
+    // the tails
+    let tail = |q: f64| {
+        let q = (-2.0 * q.ln()).sqrt();
+        polynomial(&C, q) / (polynomial(&D, q) * q + 1.0)
+    };
+    if p < LOW {
+        tail(p)
+    } else if p > 1.0 - LOW {
+        -tail(1.0 - p)
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        polynomial(&A, r) * q / (polynomial(&B, r) * r + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+
+    fn malformed_line(input: &str) -> Option<usize> {
+        match Report::from_folded(input) {
+            Err(Error::MalformedInput(line, _)) => Some(line),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn from_folded() {
+        let report = Report::from_folded("main;a;b 3\nmain;a;b 2\n42;a 1\n").unwrap();
+        assert_eq!(report.data.len(), 2);
+
+        let named = report
+            .data
+            .iter()
+            .find(|(key, _)| key.thread_name == "main")
+            .unwrap();
+        assert_eq!(*named.1, 5);
+        let names: Vec<_> = named.0.frames.iter().map(|frame| frame[0].name()).collect();
+        assert_eq!(names, vec!["b", "a"]);
+
+        let unnamed = report
+            .data
+            .iter()
+            .find(|(key, _)| key.thread_id == 42)
+            .unwrap();
+        assert_eq!(*unnamed.1, 1);
+        assert!(unnamed.0.thread_name.is_empty());
+
+        assert!(Report::from_folded("").unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn into_iter() {
+        let report = Report::from_folded("main;a;b 3\nmain;a;b 2\nmain;a 1\n42;a 1\n").unwrap();
+
+        let mut iterated = 0;
+        let mut total = 0;
+        for (frames, count) in &report {
+            assert_eq!(report.data[frames] as usize, count);
+            iterated += 1;
+            total += count;
+        }
+        assert_eq!(iterated, 3);
+        assert_eq!(total, 7);
+    }
+
+    #[test]
+    fn focus() {
+        let report = Report::from_folded(
+            "t;main;serve;handle;parse 3\nt;main;handle;parse 2\nt;main;pool;run;handle;write 4\n\
+             t;main;idle 5\nt;main;handle;x;handle 1\n",
+        )
+        .unwrap();
+        let focused = report.focus("handle");
+
+        // the focus frame is at different depths in the stacks, which start at it once trimmed
+        assert_eq!(
+            focused.to_collapsed_string(),
+            "t;handle;parse 5\nt;handle;write 4\nt;handle;x;handle 1\n"
+        );
+        assert!(focused
+            .data
+            .keys()
+            .all(|frames| frames.frames.last().unwrap()[0].name() == "handle"));
+        assert_eq!(focused.data.values().sum::<isize>(), 10);
+        assert!(report.focus("missing").data.is_empty());
+    }
+
+    #[test]
+    fn hide() {
+        let report = Report::from_folded(
+            "t;main;poll;serve;parse 3\nt;main;serve;parse 2\nt;main;poll;idle 1\nt;poll 4\n",
+        )
+        .unwrap();
+        let hidden = report.hide(&Regex::new("^poll$").unwrap());
+
+        // the stacks through `poll` merge with those which didn't go through it
+        assert_eq!(
+            hidden.to_collapsed_string(),
+            "t;main;idle 1\nt;main;serve;parse 5\n"
+        );
+        assert_eq!(hidden.dropped_samples, 4);
+        assert_eq!(
+            report.hide(&Regex::new("missing").unwrap()).data,
+            report.data
+        );
+    }
+
+    #[test]
+    fn prune_from() {
+        let report = Report::from_folded(
+            "t;main;serve;parse;lex 3\nt;main;serve;parse;eval 2\nt;main;io;parse;lex 1\n\
+             t;main;parse;parse;lex 4\nt;main;idle 5\n",
+        )
+        .unwrap();
+        let pruned = report.prune_from(&Regex::new("^parse$").unwrap());
+
+        // the callees of the outermost `parse` are cut, its callers are kept
+        assert_eq!(
+            pruned.to_collapsed_string(),
+            "t;main;idle 5\nt;main;io;parse 1\nt;main;parse 4\nt;main;serve;parse 5\n"
+        );
+        assert_eq!(pruned.data.values().sum::<isize>(), 15);
+    }
+
+    #[test]
+    fn call_edges() {
+        let report =
+            Report::from_folded("t;main;a;b 3\nt;main;a;c 2\nt;main;b 1\nt;main;a;a;c 4\n")
+                .unwrap();
+        let edges = report.call_edges();
+
+        let edge = |caller: &str, callee: &str| {
+            edges
+                .get(&(Symbol::synthetic(caller), Symbol::synthetic(callee)))
+                .copied()
+        };
+        assert_eq!(edges.len(), 5);
+        assert_eq!(edge("main", "a"), Some(9));
+        assert_eq!(edge("a", "b"), Some(3));
+        assert_eq!(edge("a", "c"), Some(6));
+        assert_eq!(edge("main", "b"), Some(1));
+        assert_eq!(edge("a", "a"), Some(4));
+        assert_eq!(edge("b", "a"), None);
+    }
+
+    #[test]
+    fn to_collapsed_string() {
+        let report = Report::from_folded("t;main;b 2\nt;main;a 3\n7;main;a 1\n").unwrap();
+        assert_eq!(
+            report.to_collapsed_string(),
+            "7;main;a 1\nt;main;a 3\nt;main;b 2\n"
+        );
+        assert_eq!(Report::from_folded("").unwrap().to_collapsed_string(), "");
+    }
+
+    #[test]
+    fn to_rates() {
+        let mut report = Report::from_folded("t;main;a 30\nt;main;b 70\n").unwrap();
+        assert!(report.to_rates().is_empty());
+        report.timing.frequency = 100;
+        report.timing.duration = Duration::from_secs(2);
+
+        let rates = report.to_rates();
+        assert_eq!(rates.len(), 2);
+        for (frames, rate) in rates.iter() {
+            let expected = report.data[frames] as f64 / 2.0;
+            assert!((rate - expected).abs() < 1e-9, "{} {}", rate, expected);
+        }
+        // 50 samples of 10ms per second: half a core
+        let total: f64 = rates.values().sum();
+        assert!((total * report.period().as_secs_f64() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn confidence_interval() {
+        let report = Report::from_folded("t;main;a 30\nt;main;b 70\n").unwrap();
+        let frames = report
+            .data
+            .keys()
+            .find(|key| key.frames[0][0].name() == "a")
+            .unwrap();
+
+        let (low, high) = report.confidence_interval(frames, 0.95);
+        assert!((low - 0.218_948_85).abs() < 1e-6, "{}", low);
+        assert!((high - 0.395_848_55).abs() < 1e-6, "{}", high);
+
+        assert!((normal_quantile(0.975) - 1.959_964).abs() < 1e-6);
+        assert!((normal_quantile(0.01) + 2.326_348).abs() < 1e-6);
+        assert_eq!(report.confidence_interval(frames, 0.0), (0.3, 0.3));
+        assert_eq!(report.confidence_interval(frames, 1.0), (0.0, 1.0));
+        let empty = Report::from_folded("").unwrap();
+        assert_eq!(empty.confidence_interval(frames, 0.95), (0.0, 1.0));
+    }
+
+    #[test]
+    fn hot_lines() {
+        let report =
+            Report::from_folded("t;main;a 3\nt;main;b 2\nt;main;a;c 4\nt;main;d 1\n").unwrap();
+        let data = report
+            .data
+            .into_iter()
+            .map(|(mut frames, count)| {
+                let leaf = &mut frames.frames[0][0];
+                let (filename, lineno) = match leaf.name().as_str() {
+                    "a" => (Some("src/a.rs"), Some(10)),
+                    "b" => (Some("src/a.rs"), Some(10)),
+                    "c" => (Some("src/c.rs"), Some(7)),
+                    // no line info
+                    _ => (Some("src/d.rs"), None),
+                };
+                leaf.filename = filename.map(|filename| Path::new(filename).into());
+                leaf.lineno = lineno;
+                (frames, count)
+            })
+            .collect();
+        let report = Report { data, ..report };
+
+        assert_eq!(
+            report.hot_lines(),
+            [
+                (Some(PathBuf::from("src/a.rs")), Some(10), 5),
+                (Some(PathBuf::from("src/c.rs")), Some(7), 4),
+                (None, None, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn dot() {
+        let report = Report::from_folded("t;main;a;b 3\nt;main;a;c 2\nt;main;b 1\n").unwrap();
+        let mut dot = Vec::new();
+        report.dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+
+        assert!(dot.starts_with("digraph \"pprof\" {\n"));
+        assert!(dot.ends_with("}\n"));
+        // nodes are numbered by decreasing inclusive count
+        assert!(dot.contains("N0 [label=\"main\\n0 (0.00%)\\nof 6 (100.00%)\""));
+        assert!(dot.contains("N1 [label=\"a\\n0 (0.00%)\\nof 5 (83.33%)\""));
+        assert!(dot.contains("N2 [label=\"b\\n4 (66.67%)\\nof 4 (66.67%)\""));
+        assert!(dot.contains("N3 [label=\"c\\n2 (33.33%)\\nof 2 (33.33%)\""));
+        assert!(dot.contains("N0 -> N1 [label=\" 5\"];"));
+        assert!(dot.contains("N1 -> N2 [label=\" 3\"];"));
+        assert!(dot.contains("N1 -> N3 [label=\" 2\"];"));
+        assert!(dot.contains("N0 -> N2 [label=\" 1\"];"));
+        assert_eq!(dot.matches("->").count(), 4);
+    }
+
+    #[test]
+    fn text_table() {
+        let report =
+            Report::from_folded("t;main;a;b 3\nt;main;a;c 2\nt;main;b 1\nt;main;d 4\n").unwrap();
+        let table = report.text_table(10);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Showing top 5 functions out of 5, 10 samples in total"
+        );
+        let header: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(header, ["flat", "flat%", "sum%", "cum", "cum%"]);
+
+        let rows: Vec<Vec<&str>> = lines[2..]
+            .iter()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(rows[0], ["4", "40.00%", "40.00%", "4", "40.00%", "b"]);
+        assert_eq!(rows[1], ["4", "40.00%", "80.00%", "4", "40.00%", "d"]);
+        // ties on flat are sorted by cum
+        assert_eq!(rows[3], ["0", "0.00%", "100.00%", "10", "100.00%", "main"]);
+        assert_eq!(rows[4], ["0", "0.00%", "100.00%", "5", "50.00%", "a"]);
+        let flat_share: f64 = rows
+            .iter()
+            .map(|row| row[1].trim_end_matches('%').parse::<f64>().unwrap())
+            .sum();
+        assert!((flat_share - 100.0).abs() < 0.1);
+        // the columns are aligned
+        assert!(lines[1..]
+            .iter()
+            .all(|line| line.find('%') == lines[2].find('%')));
+
+        assert_eq!(report.text_table(1).lines().count(), 3);
+        assert_eq!(
+            Report::from_folded("")
+                .unwrap()
+                .text_table(5)
+                .lines()
+                .count(),
+            2
+        );
+    }
+
+    #[cfg(feature = "flamegraph")]
+    #[test]
+    fn flamegraph_with_time() {
+        let mut report = Report::from_folded("t;main;a 4\nt;main;b 2\n").unwrap();
+        report.timing.frequency = 100;
+
+        let mut svg = Vec::new();
+        report.flamegraph_with_time(&mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains("all (60 ms, 100%)"));
+        assert!(svg.contains("a (40 ms, 66.67%)"));
+    }
+
+    #[test]
+    fn from_folded_malformed() {
+        assert_eq!(malformed_line("\n"), Some(1));
+        assert_eq!(malformed_line("main;a 1\n\nmain;b 1"), Some(2));
+        assert_eq!(malformed_line("main;a"), Some(1));
+        assert_eq!(malformed_line("main;a "), Some(1));
+        assert_eq!(malformed_line("main;a x"), Some(1));
+        assert_eq!(malformed_line("main;a 0"), Some(1));
+        assert_eq!(malformed_line("main;a -3"), Some(1));
+        assert_eq!(malformed_line("main;a 99999999999999999999999"), Some(1));
+        assert_eq!(
+            malformed_line(&format!("main;a {}\nmain;b {}", isize::MAX, 1)),
+            Some(2)
+        );
+        assert_eq!(malformed_line("main 1"), Some(1));
+        assert_eq!(malformed_line(";a 1"), Some(1));
+        assert_eq!(malformed_line("main;;a 1"), Some(1));
+        assert_eq!(
+            malformed_line(&format!("main{} 1", ";a".repeat(MAX_FOLDED_DEPTH + 1))),
+            Some(1)
+        );
+        assert_eq!(
+            malformed_line(&format!("main;{} 1", "a".repeat(MAX_FOLDED_LINE))),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn from_folded_random_input() {
+        const ALPHABET: &[u8] = b"ab;; 019-\n\\\t";
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10000 {
+            let len = rng.gen_range(0..64);
+            let input: String = (0..len)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect();
+            // must never panic, whatever the input
+            let _ = Report::from_folded(&input);
+        }
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn to_pprof_profile() {
+        use crate::protos::{self, Message};
+
+        let report = Report::from_folded("main;a;b 3\nmain;a;c 2\n42;a;b;d 1\n").unwrap();
+        let profile = report.to_pprof_profile();
+        assert_eq!(profile.sample.len(), report.data.len());
+        assert_eq!(profile.location.len(), 4);
+        assert_eq!(profile.function.len(), 4);
+        let total: i64 = profile.sample.iter().map(|sample| sample.value[0]).sum();
+        assert_eq!(total, 6);
+
+        let mut content = Vec::new();
+        profile.encode(&mut content).unwrap();
+        let decoded = protos::Profile::decode(content.as_slice()).unwrap();
+        assert_eq!(decoded, profile);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn pprof_addresses() {
+        let report = Report::from_folded("main;a;b 3\nmain;a;c 2\n").unwrap();
+        // `a` sampled at a different address in each stack
+        let data = report.data.into_iter().map(|(mut frames, count)| {
+            let ip = if frames.frames[0][0].name() == "b" {
+                0x1000
+            } else {
+                0x1008
             };
-            Ok(profile)
+            frames.frames[1][0].ip = Some(ip as *mut std::os::raw::c_void);
+            (frames, count)
+        });
+        let mut report = Report {
+            data: data.collect(),
+            ..report
+        };
+
+        assert_eq!(report.to_pprof_profile().location.len(), 3);
+        report.pprof_addresses = true;
+        let profile = report.to_pprof_profile();
+        assert_eq!(profile.function.len(), 3);
+        let mut addresses: Vec<u64> = profile.location.iter().map(|loc| loc.address).collect();
+        addresses.sort_unstable();
+        assert_eq!(addresses, [0, 0, 0x1000, 0x1008]);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn write_pprof() {
+        use crate::protos::Message;
+
+        // the bitwise CRC32 (IEEE), as a reference
+        fn crc32(bytes: &[u8]) -> u32 {
+            let mut crc = !0_u32;
+            for byte in bytes {
+                crc ^= *byte as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 {
+                        (crc >> 1) ^ 0xedb8_8320
+                    } else {
+                        crc >> 1
+                    };
+                }
+            }
+            !crc
+        }
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let report = Report::from_folded("main;a;b 3\nmain;a;c 2\n").unwrap();
+        let mut content = Vec::new();
+        let summary = report.write_pprof(&mut content).unwrap();
+        assert_eq!(summary.bytes, content.len());
+        assert_eq!(summary.crc32, crc32(&content));
+
+        let profile = crate::protos::Profile::decode(content.as_slice()).unwrap();
+        assert_eq!(profile.sample.len(), 2);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn write_pprof_per_thread() {
+        use crate::protos::{self, Message};
+        use std::io::Read;
+
+        let report = Report::from_folded("1;main;a 3\n2;main;b 2\n1;main;c 1\n").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let paths = report.write_pprof_per_thread(dir.path()).unwrap();
+        assert_eq!(
+            paths,
+            [
+                dir.path().join("thread-1.pb.gz"),
+                dir.path().join("thread-2.pb.gz")
+            ]
+        );
+
+        let totals: Vec<i64> = paths
+            .iter()
+            .map(|path| {
+                let mut content = Vec::new();
+                flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap())
+                    .read_to_end(&mut content)
+                    .unwrap();
+                let profile = protos::Profile::decode(content.as_slice()).unwrap();
+                profile.sample.iter().map(|sample| sample.value[0]).sum()
+            })
+            .collect();
+        assert_eq!(totals, [4, 2]);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn from_pprof() {
+        use flate2::write::GzEncoder;
+
+        fn stacks(report: &Report) -> Vec<(String, isize)> {
+            let mut stacks: Vec<_> = report
+                .data
+                .iter()
+                .map(|(key, count)| {
+                    let names: Vec<_> = key
+                        .symbols(StackOrder::RootFirst)
+                        .iter()
+                        .map(|symbol| symbol.name())
+                        .collect();
+                    (names.join(";"), *count)
+                })
+                .collect();
+            stacks.sort();
+            stacks
+        }
+
+        let mut report = Report::from_folded("t;main;a;b 3\nt;main;a 2\nt;main;c 1\n").unwrap();
+        report
+            .metadata
+            .insert("commit".to_owned(), "abc".to_owned());
+        report.timing.frequency = 100;
+        let mut content = Vec::new();
+        report.write_pprof(&mut content).unwrap();
+
+        let read = Report::from_pprof(content.as_slice()).unwrap();
+        assert_eq!(
+            stacks(&read),
+            [
+                ("main;a".to_owned(), 2),
+                ("main;a;b".to_owned(), 3),
+                ("main;c".to_owned(), 1)
+            ]
+        );
+        assert_eq!(read.frequency(), 100);
+        assert_eq!(read.metadata(), &report.metadata);
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        report.write_pprof(&mut encoder).unwrap();
+        let gzipped = Report::from_pprof(encoder.finish().unwrap().as_slice()).unwrap();
+        assert_eq!(stacks(&gzipped), stacks(&read));
+
+        assert!(matches!(
+            Report::from_pprof(&b"\xff\xff\xff"[..]),
+            Err(Error::MalformedProfile(_))
+        ));
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn state_labels() {
+        let report = Report::from_folded("main;a 3\nmain;b 2\nmain;c 1\n").unwrap();
+        let states = [
+            Some(ThreadState::Running),
+            Some(ThreadState::Sleeping),
+            None,
+        ];
+        let data = report
+            .data
+            .iter()
+            .map(|(frames, count)| {
+                let leaf = frames.frames[0][0].name();
+                let state = states[(leaf.as_bytes()[0] - b'a') as usize];
+                (
+                    Frames {
+                        state,
+                        ..frames.clone()
+                    },
+                    *count,
+                )
+            })
+            .collect();
+        let report = Report { data, ..report };
+        let mut content = Vec::new();
+        report.write_pprof(&mut content).unwrap();
+
+        let read = Report::from_pprof(content.as_slice()).unwrap();
+        let mut states: Vec<_> = read
+            .data
+            .iter()
+            .map(|(frames, count)| (frames.frames[0][0].name(), frames.state, *count))
+            .collect();
+        states.sort();
+        assert_eq!(
+            states,
+            [
+                ("a".to_owned(), Some(ThreadState::Running), 3),
+                ("b".to_owned(), Some(ThreadState::Sleeping), 2),
+                ("c".to_owned(), None, 1),
+            ]
+        );
+        assert!(read.labels.is_empty());
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn from_pprof_sample_types() {
+        use crate::protos::{self, Message};
+
+        let strings = [
+            "",
+            "cpu",
+            "nanoseconds",
+            "samples",
+            "count",
+            "main",
+            "file.rs",
+        ];
+        let value_type = |r#type, unit| protos::ValueType { r#type, unit };
+        let profile = protos::Profile {
+            sample_type: vec![value_type(1, 2), value_type(3, 4)],
+            sample: vec![protos::Sample {
+                location_id: vec![2, 1],
+                value: vec![30_000_000, 3],
+                label: vec![],
+            }],
+            location: vec![
+                protos::Location {
+                    id: 1,
+                    line: vec![protos::Line {
+                        function_id: 1,
+                        line: 7,
+                    }],
+                    ..protos::Location::default()
+                },
+                // a function without a name
+                protos::Location {
+                    id: 2,
+                    address: 0x1234,
+                    line: vec![protos::Line {
+                        function_id: 2,
+                        line: 0,
+                    }],
+                    ..protos::Location::default()
+                },
+            ],
+            function: vec![
+                protos::Function {
+                    id: 1,
+                    name: 5,
+                    filename: 6,
+                    ..protos::Function::default()
+                },
+                protos::Function {
+                    id: 2,
+                    ..protos::Function::default()
+                },
+            ],
+            string_table: strings.iter().map(|string| string.to_string()).collect(),
+            ..protos::Profile::default()
+        };
+
+        let mut content = Vec::new();
+        profile.encode(&mut content).unwrap();
+        let report = Report::from_pprof(content.as_slice()).unwrap();
+        assert_eq!(report.data.len(), 1);
+        let (key, count) = report.data.iter().next().unwrap();
+        assert_eq!(*count, 3);
+        let leaf = &key.frames[0][0];
+        assert_eq!(leaf.name(), "Unknow");
+        assert_eq!(leaf.addr, Some(0x1234 as *mut std::os::raw::c_void));
+        let root = &key.frames[1][0];
+        assert_eq!(root.name(), "main");
+        assert_eq!((root.filename(), root.lineno), ("file.rs".into(), Some(7)));
+    }
+
+    #[cfg(feature = "flamegraph")]
+    #[test]
+    fn flamegraph_with_colors() {
+        use crate::flamegraph::color::Color;
+
+        let report = Report::from_folded("t;main;app::hot 3\nt;main;serde::cold 1\n").unwrap();
+        let mut svg = Vec::new();
+        report
+            .flamegraph_with_colors(&mut svg, |name| {
+                if name.starts_with("app::") {
+                    Color { r: 255, g: 0, b: 0 }
+                } else {
+                    Color {
+                        r: 128,
+                        g: 128,
+                        b: 128,
+                    }
+                }
+            })
+            .unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+
+        // the rect of a frame precedes its text
+        let hot = svg.find(">app::hot<").unwrap();
+        let fill = svg[..hot].rfind("fill=\"").unwrap();
+        assert!(svg[fill..].starts_with("fill=\"rgb(255,0,0)\""));
+        assert_eq!(svg.matches("rgb(255,0,0)").count(), 1);
+        assert_eq!(svg.matches("rgb(128,128,128)").count(), 3);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn metadata() {
+        let mut report = Report::from_folded("main;a;b 3\n").unwrap();
+        report.metadata.insert("host".to_owned(), "db-1".to_owned());
+        report
+            .metadata
+            .insert("commit".to_owned(), "abc123".to_owned());
+        assert_eq!(report.metadata().len(), 2);
+        assert_eq!(report.metadata()["host"], "db-1");
+
+        let profile = report.to_pprof_profile();
+        let comments: Vec<&str> = profile
+            .comment
+            .iter()
+            .map(|index| profile.string_table[*index as usize].as_str())
+            .collect();
+        assert_eq!(comments, ["commit=abc123", "host=db-1"]);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn stack_order() {
+        let report = Report::from_folded("main;a;b;c 1\n").unwrap();
+
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main;a;b;c 1\n");
+
+        let profile = report.to_pprof_profile();
+        let names: Vec<&str> = profile.sample[0]
+            .location_id
+            .iter()
+            .map(|id| {
+                let location = &profile.location[*id as usize - 1];
+                let function = &profile.function[location.line[0].function_id as usize - 1];
+                profile.string_table[function.name as usize].as_str()
+            })
+            .collect();
+        assert_eq!(names, ["c", "b", "a"]);
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn pprof_drop_keep_frames() {
+        let mut report = Report::from_folded("main;a;b 3\n").unwrap();
+        let profile = report.to_pprof_profile();
+        assert_eq!((profile.drop_frames, profile.keep_frames), (0, 0));
+
+        report.pprof_drop_frames = Some("^std::".to_owned());
+        report.pprof_keep_frames = Some("^std::io::".to_owned());
+        let profile = report.to_pprof_profile();
+        let string = |index: i64| profile.string_table[index as usize].as_str();
+        assert_eq!(string(profile.drop_frames), "^std::");
+        assert_eq!(string(profile.keep_frames), "^std::io::");
+    }
+
+    #[test]
+    fn crate_frames() {
+        let report = Report::from_folded(
+            "main;app::main;serde::de::parse 3\nmain;app::main;tokio::spawn;serde::ser::write 2\nmain;app::main;tokio::park 1\n",
+        )
+        .unwrap();
+        let data = report
+            .data
+            .into_iter()
+            .map(|(mut frames, count)| {
+                frames.prepend_crate_frame();
+                (frames, count)
+            })
+            .collect();
+        let report = Report { data, ..report };
+
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&folded).unwrap().lines().collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            [
+                "main;serde;app::main;serde::de::parse 3",
+                "main;serde;app::main;tokio::spawn;serde::ser::write 2",
+                "main;tokio;app::main;tokio::park 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_rendering() {
+        let mut frames = Frames {
+            frames: (0..5000)
+                .map(|i| vec![Symbol::synthetic(&format!("f{}", i))])
+                .collect(),
+            thread_name: "main".to_owned(),
+            thread_id: 0,
+            cpu: None,
+            state: None,
+        };
+        frames.truncate(64, KeepFrames::Leaf);
+        let report = Report {
+            data: vec![(frames, 1)].into_iter().collect(),
+            timing: ReportTiming::default(),
+            dropped_samples: 0,
+            truncated_samples: 0,
+            labels: HashMap::new(),
+            pprof_drop_frames: None,
+            pprof_keep_frames: None,
+            pprof_addresses: false,
+            metadata: BTreeMap::new(),
+            thread_states: Vec::new(),
+        };
+
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let (stack, _) = folded.trim_end().rsplit_once(' ').unwrap();
+        let stack: Vec<_> = stack.split(';').collect();
+        // the thread, the truncation marker, then the kept frames down to the leaf
+        assert_eq!(stack.len(), 64 + 2);
+        assert_eq!(stack[1], "[truncated]");
+        assert_eq!(stack[65], "f0");
+    }
+
+    #[test]
+    fn empty_report_exporters() {
+        let report = Report::from_folded("").unwrap();
+        assert!(report.is_empty());
+        assert!(!Report::from_folded("main;a 1\n").unwrap().is_empty());
+
+        let mut folded = Vec::new();
+        report.write_folded(&mut folded).unwrap();
+        assert!(folded.is_empty());
+        assert!(report.to_collapsed_string().is_empty());
+
+        let mut dot = Vec::new();
+        report.dot(&mut dot).unwrap();
+        assert!(String::from_utf8(dot).unwrap().starts_with("digraph"));
+
+        #[cfg(feature = "flamegraph")]
+        {
+            let mut svg = Vec::new();
+            report.flamegraph(&mut svg).unwrap();
+            let svg = String::from_utf8(svg).unwrap();
+            assert!(svg.contains("<svg") && svg.ends_with("</svg>"));
+            assert!(svg.contains("no samples"));
+        }
+
+        #[cfg(feature = "protobuf")]
+        {
+            use crate::protos::{self, Message};
+
+            let profile = report.pprof().unwrap();
+            assert!(profile.sample.is_empty());
+            let mut encoded = Vec::new();
+            profile.encode(&mut encoded).unwrap();
+            let decoded = protos::Profile::decode(encoded.as_slice()).unwrap();
+            assert_eq!(decoded.string_table.first().map(String::as_str), Some(""));
+
+            let mut written = Vec::new();
+            report.write_pprof(&mut written).unwrap();
+            assert!(Report::from_pprof(written.as_slice()).unwrap().is_empty());
         }
     }
 }
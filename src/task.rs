@@ -0,0 +1,213 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use parking_lot::Mutex;
+
+thread_local! {
+    // the id of the task polled by this thread, if instrumented, read by the signal handler
+    static CURRENT_TASK: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+lazy_static::lazy_static! {
+    // the names of the instrumented tasks, by id, and their ids by name
+    static ref TASK_NAMES: Mutex<(Vec<String>, HashMap<String, u32>)> =
+        Mutex::new(Default::default());
+}
+
+/// Instruments `future` as a task named `name`, so that the samples taken while it is polled get
+/// a root frame named `name`: in async code, all the stacks start from the poll loop of the
+/// executor, and a flamegraph then groups them by task instead. The tasks of the same name share
+/// the frame, so names should be few, e.g. what a task does rather than its id.
+pub fn instrument_task<F: Future>(name: &str, future: F) -> InstrumentedTask<F> {
+    let mut names = TASK_NAMES.lock();
+    let (by_id, by_name) = &mut *names;
+    let task = *by_name.entry(name.to_owned()).or_insert_with(|| {
+        by_id.push(name.to_owned());
+        by_id.len() as u32 - 1
+    });
+
+    InstrumentedTask { task, future }
+}
+
+/// Spawns `future` on the current tokio runtime as a task named `name`, see `instrument_task`.
+/// **only available with `tokio` feature**
+#[cfg(feature = "tokio")]
+pub fn spawn_task<F>(name: &str, future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(instrument_task(name, future))
+}
+
+/// A future instrumented as a named task, see `instrument_task`.
+pub struct InstrumentedTask<F> {
+    task: u32,
+    future: F,
+}
+
+impl<F: Future> Future for InstrumentedTask<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // `future` is pinned along with `self`, it is never moved out of it
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let _polled = Polled::enter(this.task);
+        future.poll(cx)
+    }
+}
+
+// Marks the thread as polling a task, until dropped, even by a panic.
+struct Polled {
+    previous: Option<u32>,
+}
+
+impl Polled {
+    fn enter(task: u32) -> Self {
+        let previous = CURRENT_TASK.with(|current| current.replace(Some(task)));
+        Self { previous }
+    }
+}
+
+impl Drop for Polled {
+    fn drop(&mut self) {
+        CURRENT_TASK.with(|current| current.set(self.previous));
+    }
+}
+
+/// The task polled by the calling thread, if any. The thread local is a constant `Cell`, so it is
+/// never lazily initialized nor registered for destruction. This function is AS-safe.
+pub(crate) fn current_task() -> Option<u32> {
+    CURRENT_TASK.try_with(Cell::get).ok().flatten()
+}
+
+/// The name of the task `task`.
+pub(crate) fn task_name(task: u32) -> Option<String> {
+    TASK_NAMES.lock().0.get(task as usize).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        unsafe { Waker::from_raw(clone(std::ptr::null())) }
+    }
+
+    #[test]
+    fn instrumented_poll() {
+        let inner = instrument_task("inner", async { current_task() });
+        let mut outer = Box::pin(instrument_task("outer", async {
+            let outer = current_task();
+            (outer, inner.await, current_task())
+        }));
+        let waker = noop_waker();
+
+        assert_eq!(current_task(), None);
+        let (outer, inner, after) = match outer.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(tasks) => tasks,
+            Poll::Pending => unreachable!(),
+        };
+        assert_eq!(current_task(), None);
+        assert_eq!(after, outer);
+        assert_eq!(outer.and_then(task_name).as_deref(), Some("outer"));
+        assert_eq!(inner.and_then(task_name).as_deref(), Some("inner"));
+        assert_eq!(instrument_task("outer", async {}).task, outer.unwrap());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[inline(never)]
+    fn busy_alpha(duration: std::time::Duration) -> u64 {
+        let start = std::time::Instant::now();
+        let mut sum = 0_u64;
+        while start.elapsed() < duration {
+            for i in 0..10_000_u64 {
+                sum = unsafe { std::ptr::read_volatile(&sum) }.wrapping_add(i);
+            }
+        }
+
+        sum
+    }
+
+    #[cfg(feature = "tokio")]
+    #[inline(never)]
+    fn busy_beta(duration: std::time::Duration) -> u64 {
+        let start = std::time::Instant::now();
+        let mut sum = 0_u64;
+        while start.elapsed() < duration {
+            for i in 0..10_000_u64 {
+                sum = unsafe { std::ptr::read_volatile(&sum) }.wrapping_mul(i | 1);
+            }
+        }
+
+        sum
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn spawned_tasks() {
+        use std::time::Duration;
+
+        let guard = crate::ProfilerGuardBuilder::default()
+            .frequency(1000)
+            .build()
+            .unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // both tasks run interleaved on the same thread, from the same poll loop
+        runtime.block_on(async {
+            let alpha = spawn_task("task-alpha", async {
+                for _ in 0..20 {
+                    busy_alpha(Duration::from_millis(10));
+                    tokio::task::yield_now().await;
+                }
+            });
+            let beta = spawn_task("task-beta", async {
+                for _ in 0..20 {
+                    busy_beta(Duration::from_millis(10));
+                    tokio::task::yield_now().await;
+                }
+            });
+            alpha.await.unwrap();
+            beta.await.unwrap();
+        });
+        let report = guard.report().build().unwrap();
+
+        let current_thread = unsafe { libc::pthread_self() } as u64;
+        let (mut alpha, mut beta) = (0, 0);
+        for (frames, count) in report.data.iter() {
+            if frames.thread_id != current_thread {
+                continue;
+            }
+            let root = frames.frames.last().unwrap()[0].name();
+            let has = |name: &str| {
+                let mut names = frames.frames.iter().flatten().map(|symbol| symbol.name());
+                names.any(|frame| frame.contains(name))
+            };
+            if has("busy_alpha") {
+                assert_eq!(root, "task-alpha");
+                alpha += count;
+            } else if has("busy_beta") {
+                assert_eq!(root, "task-beta");
+                beta += count;
+            }
+        }
+        assert!(alpha > 0 && beta > 0, "{:?}", report);
+    }
+}
@@ -2,6 +2,9 @@
 
 use std::os::raw::c_int;
 use std::ptr::null_mut;
+use std::time::{Duration, Instant, SystemTime};
+
+use nix::sys::signal::Signal;
 
 #[repr(C)]
 #[derive(Clone)]
@@ -21,54 +24,349 @@ extern "C" {
     fn setitimer(which: c_int, new_value: *mut Itimerval, old_value: *mut Itimerval) -> c_int;
 }
 
+const ITIMER_REAL: c_int = 0;
+const ITIMER_VIRTUAL: c_int = 1;
 const ITIMER_PROF: c_int = 2;
 
+// The kernel checks the CPU timers of a process on every scheduler tick, so they can't fire more
+// often than the tick rate (`CONFIG_HZ`), which is at most 1000 on common configurations.
+const MAX_TICK_FREQUENCY: c_int = 1000;
+
+/// The time which drives the sampling timer, i.e. what the samples are a share of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimerKind {
+    /// The CPU time of the process, both in user and kernel mode, with `ITIMER_PROF` and
+    /// `SIGPROF`. The default.
+    #[default]
+    CpuTime,
+
+    /// The CPU time of the process in user mode only, with `ITIMER_VIRTUAL` and `SIGVTALRM`, to
+    /// leave the time spent in syscalls out.
+    UserTime,
+
+    /// The wall-clock time, with `ITIMER_REAL` and `SIGALRM`, so that the time a thread spends
+    /// sleeping or blocked is sampled too, e.g. for latency analysis. The kernel sends the
+    /// signals of the process-wide timer to a single thread, usually the main one, so every
+    /// thread is only sampled with `ProfilerGuardBuilder::per_thread_timers`. It takes over
+    /// `SIGALRM`, which `alarm` also uses.
+    WallTime,
+
+    /// No timer: a sample of the calling thread is taken on every `ProfilerGuard::tick`, with
+    /// `SIGUSR2`, so that tests get an exact number of samples of a known stack. The frequency is
+    /// then only used to scale the report. The manual profilers take over `SIGUSR2`, ignoring it
+    /// unless raised by `tick`, and restore its previous disposition once the last one stops.
+    Manual,
+}
+
+impl TimerKind {
+    pub(crate) const COUNT: usize = 4;
+
+    // The `setitimer` timer of this kind, if any.
+    fn which(self) -> Option<c_int> {
+        match self {
+            TimerKind::CpuTime => Some(ITIMER_PROF),
+            TimerKind::UserTime => Some(ITIMER_VIRTUAL),
+            TimerKind::WallTime => Some(ITIMER_REAL),
+            TimerKind::Manual => None,
+        }
+    }
+
+    /// The signal sent by the timer.
+    pub(crate) fn signal(self) -> Signal {
+        match self {
+            TimerKind::CpuTime => Signal::SIGPROF,
+            TimerKind::UserTime => Signal::SIGVTALRM,
+            TimerKind::WallTime => Signal::SIGALRM,
+            TimerKind::Manual => Signal::SIGUSR2,
+        }
+    }
+
+    /// The kind of the timer sending `signal`, if any. This function is AS-safe.
+    pub(crate) fn from_signal(signal: c_int) -> Option<TimerKind> {
+        if signal == Signal::SIGPROF as c_int {
+            Some(TimerKind::CpuTime)
+        } else if signal == Signal::SIGVTALRM as c_int {
+            Some(TimerKind::UserTime)
+        } else if signal == Signal::SIGALRM as c_int {
+            Some(TimerKind::WallTime)
+        } else if signal == Signal::SIGUSR2 as c_int {
+            Some(TimerKind::Manual)
+        } else {
+            None
+        }
+    }
+}
+
 pub struct Timer {
-    _frequency: c_int,
+    pub frequency: c_int,
+    kind: TimerKind,
+}
+
+// The timer interval for `frequency` in microseconds, the resolution of `setitimer`. It is at
+// least 1, as a zero interval would disarm the timer.
+fn interval(frequency: c_int) -> i64 {
+    (1e6 as i64 / i64::from(frequency)).max(1)
+}
+
+/// The frequency the timer can actually fire at when `frequency` is requested, at most the kernel
+/// tick rate (see `MAX_TICK_FREQUENCY`) and rounded to the microsecond resolution of the interval.
+pub(crate) fn effective_frequency(frequency: c_int) -> c_int {
+    if frequency <= 0 {
+        return 0;
+    }
+
+    ((1e6 as i64 / interval(frequency)) as c_int).min(MAX_TICK_FREQUENCY)
+}
+
+// Arms the timer of `kind` to fire every `interval` microseconds, or disarms it if `interval` is
+// 0. A manual timer is never armed, it is ticked by `ProfilerGuard::tick` instead.
+fn set_timer(kind: TimerKind, interval: i64) {
+    let which = match kind.which() {
+        Some(which) => which,
+        None => return,
+    };
+    let it_interval = Timeval {
+        tv_sec: interval / 1e6 as i64,
+        tv_usec: interval % 1e6 as i64,
+    };
+    let it_value = it_interval.clone();
+
+    unsafe {
+        setitimer(
+            which,
+            &mut Itimerval {
+                it_interval,
+                it_value,
+            },
+            null_mut(),
+        )
+    };
 }
 
 impl Timer {
-    pub fn new(frequency: c_int) -> Timer {
-        let interval = 1e6 as i64 / i64::from(frequency);
-        let it_interval = Timeval {
-            tv_sec: interval / 1e6 as i64,
-            tv_usec: interval % 1e6 as i64,
-        };
-        let it_value = it_interval.clone();
-
-        unsafe {
-            setitimer(
-                ITIMER_PROF,
-                &mut Itimerval {
-                    it_interval,
-                    it_value,
-                },
-                null_mut(),
-            )
-        };
+    pub fn new(kind: TimerKind, frequency: c_int) -> Timer {
+        set_timer(kind, interval(frequency));
 
-        Timer {
-            _frequency: frequency,
-        }
+        Timer { frequency, kind }
     }
 }
 
 impl Drop for Timer {
     fn drop(&mut self) {
-        let it_interval = Timeval {
-            tv_sec: 0,
-            tv_usec: 0,
+        set_timer(self.kind, 0);
+    }
+}
+
+/// A timer of `kind` per thread of the process, each firing at `frequency` on the CPU time of its
+/// own thread, or on the wall clock, and sending the signal to that thread, see `ProfilerGuardBuilder::per_thread_timers`.
+/// The signals of a process-wide timer go to whichever thread the kernel picks, which isn't
+/// always the one consuming the CPU time, so busy threads can be sampled unevenly. Its signals
+/// have `SI_TIMER` as code, to be told apart from those of the process-wide timer.
+pub struct ThreadTimers {
+    pub frequency: c_int,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    kind: TimerKind,
+    // the timers by thread id
+    #[cfg(target_os = "linux")]
+    timers: Vec<(libc::pid_t, libc::timer_t)>,
+}
+
+// SAFETY: a `timer_t` is only an id of a timer of the process, which any thread can set or delete.
+#[cfg(target_os = "linux")]
+unsafe impl Send for ThreadTimers {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for ThreadTimers {}
+
+impl ThreadTimers {
+    /// Creates a timer for every thread of the process. A timer is only created for the threads
+    /// running now, see `refresh`. It creates none on other platforms than Linux.
+    pub fn new(kind: TimerKind, frequency: c_int) -> ThreadTimers {
+        let mut timers = ThreadTimers {
+            frequency,
+            kind,
+            #[cfg(target_os = "linux")]
+            timers: Vec::new(),
         };
-        let it_value = it_interval.clone();
-        unsafe {
-            setitimer(
-                ITIMER_PROF,
-                &mut Itimerval {
-                    it_interval,
-                    it_value,
-                },
-                null_mut(),
-            )
+        timers.refresh();
+
+        timers
+    }
+
+    /// Creates a timer for the threads started since the last refresh, and deletes the timers of
+    /// those which exited.
+    #[cfg(target_os = "linux")]
+    pub fn refresh(&mut self) {
+        let threads: Vec<libc::pid_t> = match std::fs::read_dir("/proc/self/task") {
+            Ok(tasks) => tasks
+                .filter_map(|task| task.ok()?.file_name().to_str()?.parse().ok())
+                .collect(),
+            Err(err) => {
+                log::error!("fail to list the threads to time: {}", err);
+                return;
+            }
         };
+
+        self.timers.retain(|(tid, timer)| {
+            let running = threads.contains(tid);
+            if !running {
+                unsafe { libc::timer_delete(*timer) };
+            }
+            running
+        });
+        for tid in threads {
+            if self.timers.iter().all(|(timed, _)| *timed != tid) {
+                if let Some(timer) = create_thread_timer(self.kind, tid, interval(self.frequency)) {
+                    self.timers.push((tid, timer));
+                }
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn refresh(&mut self) {}
+}
+
+impl Drop for ThreadTimers {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        for (_, timer) in self.timers.drain(..) {
+            unsafe { libc::timer_delete(timer) };
+        }
+    }
+}
+
+// Creates a timer of `kind` firing every `interval` microseconds of CPU time of the thread `tid`,
+// or of wall-clock time, sending the signal to that thread. It fails if the thread exited meanwhile.
+#[cfg(target_os = "linux")]
+fn create_thread_timer(kind: TimerKind, tid: libc::pid_t, interval: i64) -> Option<libc::timer_t> {
+    // the CPU clock of a thread, see `MAKE_THREAD_CPUCLOCK` in the kernel: `CPUCLOCK_PROF` for
+    // the user and kernel time, `CPUCLOCK_VIRT` for the user time only
+    let clock = match kind {
+        TimerKind::CpuTime => (!tid << 3) | 4,
+        TimerKind::UserTime => (!tid << 3) | 4 | 1,
+        TimerKind::WallTime => libc::CLOCK_MONOTONIC,
+        TimerKind::Manual => return None,
+    };
+
+    let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+    event.sigev_notify = libc::SIGEV_THREAD_ID;
+    event.sigev_signo = kind.signal() as c_int;
+    event.sigev_notify_thread_id = tid;
+    let mut timer: libc::timer_t = null_mut();
+    if unsafe { libc::timer_create(clock, &mut event, &mut timer) } != 0 {
+        return None;
+    }
+
+    let period = libc::timespec {
+        tv_sec: (interval / 1e6 as i64) as libc::time_t,
+        tv_nsec: (interval % 1e6 as i64 * 1000) as libc::c_long,
+    };
+    let spec = libc::itimerspec {
+        it_interval: period,
+        it_value: period,
+    };
+    unsafe { libc::timer_settime(timer, 0, &spec, null_mut()) };
+
+    Some(timer)
+}
+
+/// Timing metadata for a collected report.
+#[derive(Debug, Clone)]
+pub struct ReportTiming {
+    /// Frequency at which samples were collected.
+    pub frequency: i32,
+    /// Collection start time.
+    pub start_time: SystemTime,
+    /// Collection duration.
+    pub duration: Duration,
+    /// CPU time spent by the signal handler to take the samples.
+    pub overhead: Duration,
+    /// Times of the first and last samples of every thread id, if recorded, see
+    /// `ProfilerGuardBuilder::thread_lifetimes`.
+    pub thread_lifetimes: Vec<(u64, Instant, Instant)>,
+}
+
+impl ReportTiming {
+    /// The sampling period, i.e. the time represented by a single sample. It is zero if the
+    /// frequency is unknown.
+    pub fn period(&self) -> Duration {
+        if self.frequency > 0 {
+            Duration::from_secs(1) / self.frequency as u32
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/// The distribution of the intervals between the consecutive samples of a profiler, see
+/// `ProfilerGuardBuilder::record_intervals`. With a CPU-bound load, the mean is about the period;
+/// a large deviation means the kernel delays or coalesces the signals of the timer, so the profile
+/// may be biased.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IntervalStats {
+    /// The number of intervals, one less than the samples.
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    /// The standard deviation.
+    pub stddev: Duration,
+}
+
+/// Accumulates the intervals between samples. `record` is AS-safe.
+#[derive(Clone, Default)]
+pub(crate) struct Intervals {
+    // the monotonic time of the last sample, in nanoseconds
+    last: Option<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+    sum: u128,
+    sum_squares: u128,
+}
+
+impl Intervals {
+    /// Records a sample taken at the monotonic time `now`, in nanoseconds.
+    pub(crate) fn record(&mut self, now: u64) {
+        if let Some(last) = self.last.replace(now) {
+            let interval = now.saturating_sub(last);
+            self.min = if self.count == 0 {
+                interval
+            } else {
+                self.min.min(interval)
+            };
+            self.max = self.max.max(interval);
+            self.count += 1;
+            self.sum += interval as u128;
+            self.sum_squares += interval as u128 * interval as u128;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> IntervalStats {
+        if self.count == 0 {
+            return IntervalStats::default();
+        }
+        let mean = self.sum / self.count as u128;
+        let variance = (self.sum_squares / self.count as u128).saturating_sub(mean * mean);
+
+        IntervalStats {
+            count: self.count,
+            min: Duration::from_nanos(self.min),
+            max: Duration::from_nanos(self.max),
+            mean: Duration::from_nanos(mean as u64),
+            stddev: Duration::from_nanos((variance as f64).sqrt() as u64),
+        }
+    }
+}
+
+impl Default for ReportTiming {
+    fn default() -> Self {
+        Self {
+            frequency: 0,
+            start_time: SystemTime::UNIX_EPOCH,
+            duration: Duration::from_secs(0),
+            overhead: Duration::from_secs(0),
+            thread_lifetimes: Vec::new(),
+        }
     }
 }
@@ -0,0 +1,423 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::os::raw::c_void;
+use std::sync::Once;
+
+use crate::frames::UnresolvedFrame;
+use crate::{Error, Result};
+
+/// The strategy used by the signal handler to capture the stack of the interrupted thread. They
+/// trade reliability for speed differently, so one can be picked when another is flaky.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unwinder {
+    /// The unwinder `backtrace` picked at build time, usually libgcc's one, driven by the DWARF
+    /// unwind tables. The default.
+    #[default]
+    Backtrace,
+
+    /// Walks the chain of frame pointers from the interrupted context. It is the fastest, but the
+    /// stacks are only complete if everything was built with frame pointers
+    /// (`-C force-frame-pointers=yes`), which `self_test` checks, with a warning when a profiler
+    /// starts otherwise. Only supported on Linux x86_64 and aarch64.
+    FramePointer,
+
+    /// libunwind's `unw_backtrace`. Only supported with the `libunwind` feature, which links
+    /// against libunwind.
+    LibUnwind,
+}
+
+impl Unwinder {
+    pub(crate) const COUNT: usize = 3;
+
+    /// Returns `true` if this unwinder can be used on this platform and with these features.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Unwinder::Backtrace => true,
+            Unwinder::FramePointer => cfg!(target_os = "linux") && crate::context::SUPPORTED,
+            Unwinder::LibUnwind => cfg!(feature = "libunwind"),
+        }
+    }
+
+    /// Checks that this unwinder captures complete stacks here by capturing a known stack, which
+    /// fails with `Error::IncompleteStacks` and a diagnostic if not. Only the frame pointer
+    /// unwinder is checked, whose stacks are wrong when the binary was built without frame
+    /// pointers.
+    pub fn self_test(self) -> Result<()> {
+        match self {
+            Unwinder::FramePointer => frame_pointer::self_test().map_err(Error::IncompleteStacks),
+            _ => Ok(()),
+        }
+    }
+
+    // Warns once per process about the failure of `self_test`, when a profiler starts.
+    pub(crate) fn warn_if_incomplete(self) {
+        static FRAME_POINTER: Once = Once::new();
+        if self == Unwinder::FramePointer {
+            FRAME_POINTER.call_once(|| {
+                if let Err(err) = self.self_test() {
+                    log::warn!("{}", err);
+                }
+            });
+        }
+    }
+
+    /// Captures the stack interrupted at `ucontext` into `frames`, from the leaf, and returns the
+    /// number of captured frames and whether the unwinder stopped at a corrupt frame, which only
+    /// the frame pointer unwinder detects. An unsupported unwinder captures nothing. This function
+    /// has to be AS-safe.
+    pub(crate) fn trace(
+        self,
+        ucontext: *mut c_void,
+        frames: &mut [UnresolvedFrame],
+    ) -> (usize, bool) {
+        match self {
+            Unwinder::Backtrace => (trace_backtrace(frames), false),
+            Unwinder::FramePointer => frame_pointer::trace(ucontext, frames),
+            Unwinder::LibUnwind => (libunwind::trace(frames), false),
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) use frame_pointer::trace_from as trace_process;
+
+fn trace_backtrace(frames: &mut [UnresolvedFrame]) -> usize {
+    let mut index = 0;
+
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            if index < frames.len() {
+                frames[index] = UnresolvedFrame::from(frame);
+                index += 1;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    index
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod frame_pointer {
+    use super::*;
+    use crate::context;
+
+    // Reads the frame record at `fp` in the process `pid`, i.e. the caller's frame pointer and the
+    // return address. Without frame pointers, `fp` may hold any value, so it is read through
+    // `process_vm_readv`, which fails instead of faulting on an unmapped address.
+    fn read_frame_record(pid: libc::pid_t, fp: usize) -> Option<[usize; 2]> {
+        let mut record = [0_usize; 2];
+        let size = std::mem::size_of_val(&record);
+        let local = libc::iovec {
+            iov_base: record.as_mut_ptr() as *mut c_void,
+            iov_len: size,
+        };
+        let remote = libc::iovec {
+            iov_base: fp as *mut c_void,
+            iov_len: size,
+        };
+
+        let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+        if read == size as isize {
+            Some(record)
+        } else {
+            None
+        }
+    }
+
+    // The chain of frame records ends with a null frame pointer, any other one which can't be
+    // followed is corrupt.
+    pub(super) fn trace(ucontext: *mut c_void, frames: &mut [UnresolvedFrame]) -> (usize, bool) {
+        if ucontext.is_null() {
+            return (0, false);
+        }
+        let uc = unsafe { &*(ucontext as *const libc::ucontext_t) };
+        let pid = unsafe { libc::getpid() };
+        trace_from(
+            pid,
+            context::pc(uc),
+            context::fp(uc),
+            context::sp(uc),
+            frames,
+        )
+    }
+
+    // Captures the stack of the leaf frame at `ip` in the process `pid`, with its frame record at
+    // `fp` and its stack pointer at `sp`.
+    pub(crate) fn trace_from(
+        pid: libc::pid_t,
+        mut ip: usize,
+        mut fp: usize,
+        sp: usize,
+        frames: &mut [UnresolvedFrame],
+    ) -> (usize, bool) {
+        let mut index = 0;
+        let mut corrupt = false;
+        while index < frames.len() && ip != 0 {
+            frames[index] = UnresolvedFrame {
+                ip,
+                symbol_address: ip,
+            };
+            index += 1;
+
+            if fp == 0 {
+                break;
+            }
+            // frames live above the stack pointer, and a caller's frame above its callee's one
+            if fp < sp || fp % std::mem::align_of::<usize>() != 0 {
+                corrupt = true;
+                break;
+            }
+            match read_frame_record(pid, fp) {
+                Some([next_fp, return_address]) => {
+                    ip = return_address;
+                    if next_fp == 0 || next_fp > fp {
+                        fp = next_fp;
+                    } else {
+                        // the return address was read from a valid record, it is still captured
+                        corrupt = true;
+                        fp = 0;
+                    }
+                }
+                None => {
+                    corrupt = true;
+                    break;
+                }
+            }
+        }
+
+        (index, corrupt)
+    }
+
+    // the number of frames whose frame records are checked by the self test
+    const SELF_TEST_DEPTH: usize = 3;
+
+    // Captures a known chain of calls both by walking its frame records and with the DWARF
+    // unwinder, to compare them.
+    pub(super) fn self_test() -> std::result::Result<(), String> {
+        let mut captured = [UnresolvedFrame::default(); crate::MAX_DEPTH];
+        let mut reference = [UnresolvedFrame::default(); crate::MAX_DEPTH];
+        let (depth, reference_depth) = self_test_outer(&mut captured, &mut reference);
+
+        let ips = |frames: &[UnresolvedFrame]| -> Vec<usize> {
+            frames.iter().map(|frame| frame.ip).collect()
+        };
+        check_frame_records(
+            &ips(&captured[..depth]),
+            &ips(&reference[..reference_depth]),
+        )
+    }
+
+    // The calls captured by the self test, a volatile read after each of them keeps it from
+    // being a tail call, which would leave no frame record.
+    #[inline(never)]
+    fn self_test_outer(
+        captured: &mut [UnresolvedFrame],
+        reference: &mut [UnresolvedFrame],
+    ) -> (usize, usize) {
+        let depths = self_test_middle(captured, reference);
+        unsafe { std::ptr::read_volatile(&depths) }
+    }
+
+    #[inline(never)]
+    fn self_test_middle(
+        captured: &mut [UnresolvedFrame],
+        reference: &mut [UnresolvedFrame],
+    ) -> (usize, usize) {
+        let depths = self_test_leaf(captured, reference);
+        unsafe { std::ptr::read_volatile(&depths) }
+    }
+
+    #[inline(never)]
+    fn self_test_leaf(
+        captured: &mut [UnresolvedFrame],
+        reference: &mut [UnresolvedFrame],
+    ) -> (usize, usize) {
+        let fp: usize;
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+        }
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        }
+        // a local lies between the stack pointer and the frame record
+        let local = 0_u8;
+        let sp = &local as *const u8 as usize;
+        let ip = self_test_leaf as *const () as usize;
+
+        let pid = unsafe { libc::getpid() };
+        let (depth, _) = trace_from(pid, ip, fp, sp, captured);
+        (depth, trace_backtrace(reference))
+    }
+
+    // Checks that the return addresses read from the frame records of the leaf and its callers,
+    // following the leaf's `ip` in `captured`, are those found by the DWARF unwinder.
+    pub(super) fn check_frame_records(
+        captured: &[usize],
+        reference: &[usize],
+    ) -> std::result::Result<(), String> {
+        let callers = captured.get(1..).unwrap_or(&[]);
+        let found = reference
+            .iter()
+            .position(|ip| Some(ip) == callers.first())
+            .map(|start| {
+                let matching = reference[start..].iter().zip(callers);
+                matching.take_while(|(ip, caller)| ip == caller).count()
+            })
+            .unwrap_or(0)
+            .min(SELF_TEST_DEPTH);
+
+        if found == SELF_TEST_DEPTH {
+            Ok(())
+        } else {
+            Err(format!(
+                "the frame pointer unwinder found {} of {} frames of a known stack, the stacks \
+                 it captures will be incomplete or wrong: build with \
+                 `RUSTFLAGS=\"-C force-frame-pointers=yes\"`",
+                found, SELF_TEST_DEPTH
+            ))
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod frame_pointer {
+    use super::*;
+
+    pub(super) fn trace(_: *mut c_void, _: &mut [UnresolvedFrame]) -> (usize, bool) {
+        (0, false)
+    }
+
+    pub(super) fn self_test() -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "libunwind")]
+mod libunwind {
+    use super::*;
+    use std::os::raw::c_int;
+
+    #[link(name = "unwind")]
+    extern "C" {
+        fn unw_backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int;
+    }
+
+    pub(super) fn trace(frames: &mut [UnresolvedFrame]) -> usize {
+        let mut ips = [std::ptr::null_mut(); crate::MAX_DEPTH + 1];
+        let size = frames.len().min(ips.len());
+        let depth = unsafe { unw_backtrace(ips.as_mut_ptr(), size as c_int) }.max(0) as usize;
+
+        for (frame, ip) in frames.iter_mut().zip(ips[..depth].iter()) {
+            *frame = UnresolvedFrame {
+                ip: *ip as usize,
+                symbol_address: *ip as usize,
+            };
+        }
+
+        depth
+    }
+}
+
+#[cfg(not(feature = "libunwind"))]
+mod libunwind {
+    use super::*;
+
+    pub(super) fn trace(_: &mut [UnresolvedFrame]) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn frame_pointer_corrupt() {
+        use super::*;
+
+        // unwinds the context interrupted at `pc`, with the frame records in `stack`, the first
+        // one being at the stack pointer
+        fn trace(pc: usize, stack: &[usize]) -> (Vec<usize>, bool) {
+            let sp = stack.as_ptr() as usize;
+            let mut uc: libc::ucontext_t = unsafe { std::mem::zeroed() };
+            #[cfg(target_arch = "x86_64")]
+            {
+                uc.uc_mcontext.gregs[libc::REG_RIP as usize] = pc as i64;
+                uc.uc_mcontext.gregs[libc::REG_RSP as usize] = sp as i64;
+                uc.uc_mcontext.gregs[libc::REG_RBP as usize] = sp as i64;
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                uc.uc_mcontext.pc = pc as u64;
+                uc.uc_mcontext.sp = sp as u64;
+                uc.uc_mcontext.regs[29] = sp as u64;
+            }
+
+            let mut frames = [UnresolvedFrame::default(); 8];
+            let ucontext = &mut uc as *mut libc::ucontext_t as *mut c_void;
+            let (depth, corrupt) = Unwinder::FramePointer.trace(ucontext, &mut frames);
+            let ips = frames[..depth].iter().map(|frame| frame.ip).collect();
+            (ips, corrupt)
+        }
+
+        let mut stack = [0_usize; 4];
+        let second = &stack[2] as *const usize as usize;
+
+        // a complete chain, ended by a null frame pointer
+        stack[..2].copy_from_slice(&[second, 0x1111]);
+        stack[3] = 0x2222;
+        assert_eq!(trace(0x1000, &stack), (vec![0x1000, 0x1111, 0x2222], false));
+
+        // a frame pointer to unmapped memory isn't followed
+        stack[2] = usize::MAX & !0xff;
+        assert_eq!(trace(0x1000, &stack), (vec![0x1000, 0x1111, 0x2222], true));
+
+        // neither is one going down the stack
+        stack[2] = stack.as_ptr() as usize;
+        assert_eq!(trace(0x1000, &stack), (vec![0x1000, 0x1111, 0x2222], true));
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn frame_pointer_self_test() {
+        use super::frame_pointer::check_frame_records;
+
+        let reference = [0x10, 0x20, 0x1111, 0x2222, 0x3333, 0x4444];
+        assert!(check_frame_records(&[0x99, 0x1111, 0x2222, 0x3333, 0x4444], &reference).is_ok());
+        assert!(check_frame_records(&[0x99, 0x1111, 0x2222, 0x3333], &reference).is_ok());
+
+        let err = check_frame_records(&[0x99, 0x1111, 0x5555], &reference).unwrap_err();
+        assert!(err.contains("found 1 of 3 frames"), "{}", err);
+        assert!(err.contains("-C force-frame-pointers=yes"), "{}", err);
+        let err = check_frame_records(&[0x99], &reference).unwrap_err();
+        assert!(err.contains("found 0 of 3 frames"), "{}", err);
+
+        // whether this build has frame pointers depends on the flags and the optimization level
+        match super::Unwinder::FramePointer.self_test() {
+            Ok(()) => {}
+            Err(super::Error::IncompleteStacks(err)) => {
+                assert!(err.contains("force-frame-pointers"), "{}", err)
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+}